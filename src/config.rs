@@ -0,0 +1,68 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::peer::{
+    peer_stream::{
+        ConnectRetryPolicy, CLIENT_VERSION, DEFAULT_CONNECT_TIMEOUT, DEFAULT_PIPELINE_DEPTH,
+        DEFAULT_READ_TIMEOUT, MAX_MESSAGE_LEN,
+    },
+    tracker_stream::RetransmitPolicy,
+};
+
+/// Tunables for a [`crate::TRipClient`]. `ClientConfig::default()` matches the
+/// behaviour of [`crate::TRipClient::new`].
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Port advertised to trackers for incoming peer connections.
+    pub port: u16,
+    /// Peers to ask each tracker for, or -1 to let the tracker decide.
+    pub num_want: i32,
+    /// Version digits in our `-WMxxxx-` peer id.
+    pub client_version: [u8; 4],
+    /// Peer id prefix to use instead of `-WM<client_version>-`, e.g. for a
+    /// fork identifying as its own client. Must be exactly 8 bytes.
+    pub client_signature: Option<Vec<u8>>,
+    pub tracker_retransmit: RetransmitPolicy,
+    /// Local address for tracker sockets, see
+    /// [`crate::peer::tracker_stream::TrackerConnection::with_bind_addr`].
+    /// Trackers are contacted concurrently, each from its own socket, so a
+    /// non-zero port is rejected unless the magnet lists a single tracker.
+    pub tracker_bind_addr: Option<SocketAddr>,
+    /// Overall deadline for finding peers through trackers and the DHT.
+    pub discover_timeout: Duration,
+    pub peer_connect_timeout: Duration,
+    /// How often and how patiently to retry peers that fail to connect.
+    pub peer_connect_retry: ConnectRetryPolicy,
+    /// Upper bound on peer handshakes in flight at once.
+    pub max_concurrent_connects: usize,
+    /// Largest message accepted from a peer.
+    pub max_message_len: usize,
+    /// How long a peer may take to finish sending a message it has started.
+    pub peer_read_timeout: Duration,
+    /// Block requests kept in flight to each peer.
+    pub pipeline_depth: usize,
+    /// Stop a DHT lookup once this many peers are known.
+    pub dht_wanted_peers: usize,
+}
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            port: 6881,
+            num_want: -1,
+            client_version: *CLIENT_VERSION,
+            client_signature: None,
+            tracker_retransmit: RetransmitPolicy::default(),
+            tracker_bind_addr: None,
+            discover_timeout: Duration::from_secs(30),
+            peer_connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            peer_connect_retry: ConnectRetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_secs(2),
+            },
+            max_concurrent_connects: 30,
+            max_message_len: MAX_MESSAGE_LEN,
+            peer_read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            dht_wanted_peers: 50,
+        }
+    }
+}
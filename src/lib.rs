@@ -1,91 +1,920 @@
-use std::{net::SocketAddr, collections::HashSet};
+//! A BitTorrent client library for downloading from magnet links.
+//!
+//! [`TRipClient`] drives a whole download. For lower-level access the key
+//! building blocks are re-exported here: magnet parsing, UDP tracker
+//! announces, peer connections and the peer wire messages. These
+//! re-exports are the stable API and only change with a major version.
+//! Everything else under [`peer`] is reachable too but may change between
+//! minor versions.
 
-use async_std::{future::ready, task};
-use futures::{join, stream::FuturesUnordered, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_std::{future, sync::Mutex, task};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::Either,
+    stream::{self, FuturesUnordered},
+    Stream, StreamExt,
+};
+use log::{debug, info, warn};
 use peer::{
-    magnet::Magnet,
-    peer_stream::PeerConnection,
-    tracker_stream::{AnnounceEvent, AnnounceRequestDescriptor, TrackerConnection},
+    dht::{self, DHT_BOOTSTRAP_NODES},
+    download::{resume_pieces_until, verify_existing, PEER_IDLE_TIMEOUT},
+    stats::StatsTracker,
+    peer_stream::{client_signature, connect_peers, generate_peer_id},
+    seed::seed_pieces,
+    storage::Storage,
+    tracker_stream::{RetransmitPolicy, TrackerError},
 };
-use rand::Rng;
 use url::Url;
 
-mod peer;
+pub mod config;
+pub mod peer;
+
+pub use config::ClientConfig;
+pub use peer::{
+    download::DownloadOutcome,
+    magnet::{Magnet, MagnetError},
+    messages::{Bitfield, Message},
+    peer_stream::{PeerConnection, PeerError, PeerListener, PeerStreamOpts},
+    stats::Stats,
+    torrent::Info,
+    tracker_stream::{AnnounceEvent, AnnounceRequestDescriptor, AnnounceResult, TrackerConnection},
+};
+
+/// Re-announce period used until a tracker tells us otherwise.
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(300);
+/// Floor on the re-announce period, however eager the tracker.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// `left` reported before the metadata tells us the torrent's size. Any
+/// non-zero value keeps trackers from counting us as a seed.
+const UNKNOWN_LEFT: u64 = i64::MAX as u64;
 
-struct Peers {
-    connections: Vec<PeerConnection>,
+#[derive(thiserror::Error, Debug)]
+pub enum ClientError {
+    #[error("Magnet link lists no trackers")]
+    NoTrackers,
+    #[error("None of the {attempted} trackers could be reached")]
+    NoTrackersReachable { attempted: usize },
+    #[error("Client signature must be 8 bytes, got {0}")]
+    InvalidClientSignature(usize),
+    #[error("Tracker bind address {addr} has a fixed port, but {trackers} trackers would share it")]
+    SharedTrackerPort { addr: SocketAddr, trackers: usize },
 }
 
-struct Trackers {
+/// The UDP trackers listed in a magnet and our connections to them.
+pub struct Trackers {
+    pub addrs: Vec<Url>,
     pub connections: Vec<TrackerConnection>,
+    pub interval: Option<Duration>,
+    /// Trackers that failed during the last connect or announce, and why.
+    pub failures: Vec<(Url, TrackerError)>,
+    /// How long each tracker took to answer its last announce. Trackers
+    /// whose last announce failed are missing.
+    pub latencies: HashMap<Url, Duration>,
+    retransmit: RetransmitPolicy,
+    bind_addr: Option<SocketAddr>,
 }
 impl Trackers {
-    fn new(tracker_addrs: &Vec<Url>) -> Self {
-        let futures = tracker_addrs
+    /// Keeps the UDP trackers from `tracker_addrs`. Others are skipped, since
+    /// sending UDP connect packets to an HTTP tracker can only time out.
+    pub fn new(tracker_addrs: &[Url], retransmit: RetransmitPolicy, bind_addr: Option<SocketAddr>) -> Self {
+        let (addrs, skipped): (Vec<_>, Vec<_>) = tracker_addrs
+            .iter()
+            .cloned()
+            .partition(|tracker| tracker.scheme() == "udp");
+        for tracker in skipped {
+            debug!("Skipping non-UDP tracker {}", tracker);
+        }
+        Self {
+            addrs,
+            connections: Vec::new(),
+            interval: None,
+            failures: Vec::new(),
+            latencies: HashMap::new(),
+            retransmit,
+            bind_addr,
+        }
+    }
+    /// Connects to every tracker, failing only if none of them answer.
+    pub async fn connect(&mut self) -> Result<(), ClientError> {
+        if self.addrs.is_empty() {
+            return Err(ClientError::NoTrackers);
+        }
+        let (retransmit, bind_addr) = (self.retransmit, self.bind_addr);
+        let mut futures = self
+            .addrs
             .iter()
-            .map(|tracker| TrackerConnection::new(tracker.clone()))
+            .map(|tracker| async move {
+                let conn = match bind_addr {
+                    Some(bind_addr) => TrackerConnection::with_bind_addr(tracker.clone(), retransmit, bind_addr).await,
+                    None => TrackerConnection::with_retransmit(tracker.clone(), retransmit).await,
+                };
+                (tracker.clone(), conn)
+            })
             .collect::<FuturesUnordered<_>>();
-        let resolved = task::block_on(async { futures.collect::<Vec<_>>().await });
-        let conns = resolved
-            .into_iter()
-            .filter_map(|conn| match conn {
+        self.connections.clear();
+        self.failures.clear();
+        // Keep connections as they complete, so a caller that gives up
+        // waiting still has the trackers that answered in time
+        while let Some((addr, result)) = futures.next().await {
+            match result {
                 Ok(conn) => {
-                    println!("Connected to {}", conn.addr);
-                    Some(conn)
+                    info!("Connected to {}", conn.addr);
+                    self.connections.push(conn);
                 }
                 Err(e) => {
-                    println!("Tracker connection timed out");
-                    None
+                    warn!("Tracker connection to {} failed: {}", addr, e);
+                    self.failures.push((addr, e));
                 }
-            })
-            .collect();
-        Self { connections: conns }
-    }
-    async fn announce(&self, peer_id: [u8; 20], info_hash: [u8; 20]) -> Vec<SocketAddr> {
-        let futures = FuturesUnordered::new();
-        for conn in self.connections.iter() {
-            futures.push(conn.announce(AnnounceRequestDescriptor {
-                connection_id: conn.connection_id,
-                peer_id,
-                info_hash,
-                downloaded: 0,
-                left: 0,
-                uploaded: 0,
-                event: AnnounceEvent::None,
-            }))
-        }
-        let resolved = futures.filter_map(|result| {
-            async {
-                match result {
-                    Ok(resp) => Some(resp),
-                    Err(_) => {
-                        println!("Failed to announce to tracker");
-                        None
-                    },
+            }
+        }
+        if self.connections.is_empty() {
+            return Err(ClientError::NoTrackersReachable {
+                attempted: self.addrs.len(),
+            });
+        }
+        Ok(())
+    }
+    /// Announces to every connected tracker and, once all of them have
+    /// answered or failed, merges their answers: the deduplicated peers, the
+    /// longest interval and the largest swarm counts. An empty swarm still
+    /// counts as success; only an announce no tracker answered is an error.
+    pub async fn announce(
+        &mut self,
+        descriptor: AnnounceRequestDescriptor,
+    ) -> Result<AnnounceResult, ClientError> {
+        let mut resolved = Vec::new();
+        let mut failures = Vec::new();
+        for (addr, result) in self.announce_each(descriptor).await {
+            match result {
+                Ok(resp) => resolved.push(resp),
+                Err(e) => {
+                    warn!("Failed to announce to {}: {}", addr, e);
+                    failures.push((addr, e));
                 }
             }
-        }).collect::<Vec<_>>().await;
+        }
+        self.failures = failures;
+        if resolved.is_empty() {
+            return Err(ClientError::NoTrackersReachable {
+                attempted: self.connections.len(),
+            });
+        }
+        // Re-announce no sooner than the most patient tracker asked us to
+        let interval = resolved.iter().map(|result| result.interval).max().unwrap_or(0);
+        self.interval = Some(Duration::from_secs(interval.into()));
+        let mut uniques = HashSet::new();
+        let mut merged = AnnounceResult {
+            peers: Vec::new(),
+            interval,
+            leechers: 0,
+            seeders: 0,
+        };
+        for result in resolved {
+            merged.leechers = merged.leechers.max(result.leechers);
+            merged.seeders = merged.seeders.max(result.seeders);
+            merged.peers.extend(result.peers.into_iter().filter(|peer| uniques.insert(*peer)));
+        }
+        Ok(merged)
+    }
+    /// Announces to every connected tracker and returns each one's answer,
+    /// in the order they arrived. Latencies are recorded in `latencies`
+    /// and the connections reordered fastest first, so later announces and
+    /// [`Trackers::peer_stream`] try the quickest trackers first.
+    pub async fn announce_each(
+        &mut self,
+        descriptor: AnnounceRequestDescriptor,
+    ) -> Vec<(Url, Result<AnnounceResult, TrackerError>)> {
+        let results = self
+            .connections
+            .iter_mut()
+            .map(|conn| async move {
+                let started = Instant::now();
+                let result = conn.announce(descriptor).await;
+                (conn.addr.clone(), started.elapsed(), result)
+            })
+            .collect::<FuturesUnordered<_>>()
+            .collect::<Vec<_>>()
+            .await;
+        for (addr, latency, result) in &results {
+            if result.is_ok() {
+                self.latencies.insert(addr.clone(), *latency);
+            } else {
+                self.latencies.remove(addr);
+            }
+        }
+        // Trackers without a latency failed, so they go last
+        self.connections.sort_by_key(|conn| {
+            self.latencies.get(&conn.addr).copied().unwrap_or(Duration::MAX)
+        });
+        results
+            .into_iter()
+            .map(|(addr, _, result)| (addr, result))
+            .collect()
+    }
+    /// Announces to every connected tracker, yielding each peer as soon as
+    /// the first tracker to report it answers, so connecting can start
+    /// before slow trackers reply. Failures are only logged.
+    pub fn peer_stream(
+        &mut self,
+        descriptor: AnnounceRequestDescriptor,
+    ) -> impl Stream<Item = SocketAddr> + '_ {
         let mut uniques = HashSet::new();
-        let mut flattened = resolved.into_iter().flatten().collect::<Vec<_>>();
-        flattened.retain(|i| uniques.insert(*i));
-        flattened
+        self.connections
+            .iter_mut()
+            .map(|conn| async move { (conn.addr.clone(), conn.announce(descriptor).await) })
+            .collect::<FuturesUnordered<_>>()
+            .flat_map(|(addr, result)| {
+                let peers = match result {
+                    Ok(resp) => resp.peers,
+                    Err(e) => {
+                        warn!("Failed to announce to {}: {}", addr, e);
+                        Vec::new()
+                    }
+                };
+                stream::iter(peers)
+            })
+            .filter(move |peer| futures::future::ready(uniques.insert(*peer)))
     }
 }
 
 pub struct TRipClient {
     magnet: Magnet,
+    peer_id: [u8; 20],
+    config: ClientConfig,
+    trackers: Mutex<Trackers>,
+    stats: std::sync::Mutex<StatsTracker>,
 }
 impl TRipClient {
+    /// Parses `link` and prepares a client without touching the network.
+    ///
+    /// Before 0.2 this also connected to the trackers and announced, blocking
+    /// the calling thread. That work now happens lazily in the async
+    /// [`TRipClient::discover_peers`] and [`TRipClient::download`].
     pub fn new(link: &str) -> anyhow::Result<Self> {
+        TRipClient::with_config(link, ClientConfig::default())
+    }
+    pub fn with_config(link: &str, config: ClientConfig) -> anyhow::Result<Self> {
         let magnet = Magnet::from_link(link)?;
-        let trackers = Trackers::new(&magnet.trackers);
-        let mut peer_id = [0u8; 20];
-        rand::thread_rng().fill(&mut peer_id[..]);
-        let signature = "-WM0001-";
-        peer_id[0..signature.len()].copy_from_slice(signature.as_bytes());
-
-        let result = task::block_on(trackers.announce(peer_id, magnet.info_hash.bytes));
-        dbg!(result);
-        Ok(Self { magnet })
+        let trackers = Trackers::new(&magnet.trackers, config.tracker_retransmit, config.tracker_bind_addr);
+        // Every tracker binds its own socket at once, so only one can have the port
+        if let Some(addr) = config.tracker_bind_addr.filter(|addr| addr.port() != 0) {
+            if trackers.addrs.len() > 1 {
+                return Err(ClientError::SharedTrackerPort {
+                    addr,
+                    trackers: trackers.addrs.len(),
+                }
+                .into());
+            }
+        }
+        let signature = match &config.client_signature {
+            Some(signature) => <[u8; 8]>::try_from(signature.as_slice())
+                .map_err(|_| ClientError::InvalidClientSignature(signature.len()))?,
+            None => client_signature(&config.client_version),
+        };
+        let peer_id = generate_peer_id(&signature);
+        Ok(Self {
+            magnet,
+            peer_id,
+            config,
+            trackers: Mutex::new(trackers),
+            stats: std::sync::Mutex::new(StatsTracker::new()),
+        })
+    }
+    /// Progress of the current download, e.g. for rendering a progress bar.
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().snapshot()
+    }
+    /// Announces once to every connected tracker and returns the
+    /// deduplicated set of peers they reported, after any peers listed in
+    /// the magnet itself. Falls back to a DHT lookup
+    /// when the trackers come back empty; if that finds nothing either, a
+    /// [`ClientError`] explaining why no tracker was usable is returned.
+    ///
+    /// The whole flow is bounded by [`ClientConfig::discover_timeout`]; peers
+    /// that arrived before the deadline are returned rather than an error.
+    pub async fn discover_peers(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let deadline = Instant::now() + self.config.discover_timeout;
+        let mut peers = self.magnet.initial_peers.clone();
+        let mut tracker_error = None;
+        let mut trackers = self.trackers.lock().await;
+        let discovery = async {
+            if trackers.connections.is_empty() {
+                if let Err(e) = trackers.connect().await {
+                    warn!("{}", e);
+                    tracker_error = Some(e);
+                }
+            }
+            let mut announced = trackers.peer_stream(self.announce_descriptor(AnnounceEvent::Started));
+            while let Some(peer) = announced.next().await {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+        };
+        if future::timeout(self.config.discover_timeout, discovery).await.is_err() {
+            warn!("Peer discovery timed out with {} peers", peers.len());
+        }
+        drop(trackers);
+        if peers.is_empty() {
+            debug!("Trackers returned no peers, falling back to the DHT");
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let lookup = dht::get_peers(
+                &self.magnet.info_hash.bytes,
+                DHT_BOOTSTRAP_NODES,
+                self.config.dht_wanted_peers,
+            );
+            let found = future::timeout(remaining, lookup)
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("DHT lookup timed out")));
+            return match (found, tracker_error) {
+                (Ok(peers), _) if !peers.is_empty() => Ok(peers),
+                (_, Some(e)) => Err(e.into()),
+                (found, None) => found,
+            };
+        }
+        debug!("Discovered {} peers", peers.len());
+        Ok(peers)
+    }
+    /// Blocking version of [`TRipClient::discover_peers`] for callers that
+    /// don't run an async runtime. Must not be called from async code.
+    pub fn discover_peers_blocking(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        task::block_on(self.discover_peers())
+    }
+    /// Tells every connected tracker we are leaving the swarm so it stops
+    /// handing out our address. Does nothing if no tracker was contacted.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        let mut trackers = self.trackers.lock().await;
+        if trackers.connections.is_empty() {
+            return Ok(());
+        }
+        let descriptor = self.announce_descriptor(AnnounceEvent::Stopped);
+        if let Err(e) = trackers.announce(descriptor).await {
+            debug!("Stopped announce failed: {}", e);
+        }
+        Ok(())
+    }
+    /// Tells every connected tracker the download has finished. Does
+    /// nothing if no tracker was contacted.
+    pub async fn completed(&self) -> anyhow::Result<()> {
+        let mut trackers = self.trackers.lock().await;
+        if trackers.connections.is_empty() {
+            return Ok(());
+        }
+        let descriptor = self.announce_descriptor(AnnounceEvent::Completed);
+        if let Err(e) = trackers.announce(descriptor).await {
+            debug!("Completed announce failed: {}", e);
+        }
+        Ok(())
+    }
+    /// Announces `Started`, then re-announces every tracker interval until
+    /// `shutdown` fires (or its sender is dropped), finishing with a
+    /// `Stopped` announce. Each peer is sent to `peers` the first time any
+    /// tracker reports it.
+    pub async fn announce_loop(
+        &self,
+        peers: mpsc::UnboundedSender<SocketAddr>,
+        mut shutdown: oneshot::Receiver<()>,
+    ) -> anyhow::Result<()> {
+        {
+            let mut trackers = self.trackers.lock().await;
+            if trackers.connections.is_empty() {
+                trackers.connect().await?;
+            }
+        }
+        let mut seen = HashSet::new();
+        let mut event = AnnounceEvent::Started;
+        loop {
+            let (found, interval) = {
+                let mut trackers = self.trackers.lock().await;
+                let found = match trackers.announce(self.announce_descriptor(event)).await {
+                    Ok(result) => result.peers,
+                    Err(e) => {
+                        warn!("{}", e);
+                        Vec::new()
+                    }
+                };
+                (found, trackers.interval.unwrap_or(DEFAULT_ANNOUNCE_INTERVAL))
+            };
+            for peer in found.into_iter().filter(|peer| seen.insert(*peer)) {
+                if peers.unbounded_send(peer).is_err() {
+                    debug!("Peer receiver dropped, stopping announces");
+                    return self.shutdown().await;
+                }
+            }
+            event = AnnounceEvent::None;
+            let wait = Box::pin(task::sleep(interval.max(MIN_ANNOUNCE_INTERVAL)));
+            if let Either::Left(_) = futures::future::select(&mut shutdown, wait).await {
+                break;
+            }
+        }
+        self.shutdown().await
+    }
+    fn announce_descriptor(&self, event: AnnounceEvent) -> AnnounceRequestDescriptor {
+        let stats = self.stats();
+        AnnounceRequestDescriptor {
+            peer_id: self.peer_id,
+            info_hash: self.magnet.info_hash.bytes,
+            event,
+            num_want: self.config.num_want,
+            port: self.config.port,
+            downloaded: stats.bytes_downloaded,
+            left: stats.bytes_left.unwrap_or(UNKNOWN_LEFT),
+            uploaded: stats.bytes_uploaded,
+        }
+    }
+    /// Connects to the announced peers, fetches the torrent metadata from the
+    /// first peer that serves it and downloads every piece into `output_dir`.
+    pub async fn download(&self, output_dir: &Path) -> anyhow::Result<()> {
+        self.fetch(output_dir, false, futures::future::pending::<()>()).await?;
+        Ok(())
+    }
+    /// Like [`TRipClient::download`], but first re-hashes whatever is already
+    /// in `output_dir` and only downloads the pieces that are missing or
+    /// corrupt.
+    pub async fn resume(&self, output_dir: &Path) -> anyhow::Result<()> {
+        self.fetch(output_dir, true, futures::future::pending::<()>()).await?;
+        Ok(())
+    }
+    /// Like [`TRipClient::resume`], but gives up once `shutdown` fires (or
+    /// its sender is dropped), even while still fetching the metadata. The
+    /// peer connections are closed and trackers get a `Stopped` announce;
+    /// everything verified so far stays in `output_dir` for the next resume.
+    pub async fn resume_until(
+        &self,
+        output_dir: &Path,
+        shutdown: oneshot::Receiver<()>,
+    ) -> anyhow::Result<DownloadOutcome> {
+        self.fetch(output_dir, true, shutdown).await
+    }
+    /// Serves the completed download in `output_dir` to peers connecting on
+    /// [`ClientConfig::port`]. The metadata is fetched from the swarm as for
+    /// a download, and only pieces that verify on disk are offered.
+    pub async fn seed(&self, output_dir: &Path) -> anyhow::Result<()> {
+        let addr = (Ipv4Addr::UNSPECIFIED, self.config.port);
+        let listener = PeerListener::bind(addr, self.peer_opts()).await?;
+        let (_, info) = self.fetch_info().await?;
+        let storage = Storage::create(&info, output_dir)?;
+        let have = verify_existing(&info, &storage)?;
+        info!("Seeding {} of {} pieces", have.count_ones(), have.len());
+        seed_pieces(&listener, &storage, &have, &self.stats).await;
+        Ok(())
+    }
+    fn peer_opts(&self) -> PeerStreamOpts {
+        PeerStreamOpts::new(self.magnet.info_hash.bytes, self.peer_id)
+            .supports_extensions(true)
+            .supports_fast(true)
+            .connect_timeout(self.config.peer_connect_timeout)
+            .connect_retry(self.config.peer_connect_retry)
+            .max_message_len(self.config.max_message_len)
+            .read_timeout(self.config.peer_read_timeout)
+            .pipeline_depth(self.config.pipeline_depth)
+    }
+    /// Connects to the swarm and fetches the torrent metadata from the first
+    /// peer that serves it, returning the connected peers alongside it.
+    async fn fetch_info(&self) -> anyhow::Result<(Vec<PeerConnection>, Info)> {
+        let info_hash = self.magnet.info_hash.bytes;
+        let opts = self.peer_opts();
+        let addrs = self.discover_peers().await?;
+        let mut peers = connect_peers(addrs, self.config.max_concurrent_connects, opts)
+            .collect::<Vec<_>>()
+            .await;
+        if peers.is_empty() {
+            anyhow::bail!("Unable to connect to any peers");
+        }
+
+        let mut metadata = None;
+        for peer in peers.iter_mut() {
+            let fetch = future::timeout(PEER_IDLE_TIMEOUT, peer.fetch_metadata(&info_hash));
+            if let Ok(Ok(bytes)) = fetch.await {
+                metadata = Some(bytes);
+                break;
+            }
+        }
+        let info = Info::from_bytes(&metadata.context("No peer provided the torrent metadata")?)?;
+        Ok((peers, info))
+    }
+    async fn fetch(
+        &self,
+        output_dir: &Path,
+        resume: bool,
+        shutdown: impl std::future::Future,
+    ) -> anyhow::Result<DownloadOutcome> {
+        // Discovery and the metadata fetch can take a while, so the signal
+        // has to cut those short too
+        let mut shutdown = Box::pin(shutdown);
+        let fetched = futures::future::select(Box::pin(self.fetch_info()), &mut shutdown).await;
+        let (peers, info) = match fetched {
+            Either::Left((fetched, _)) => fetched?,
+            Either::Right((_, fetching)) => {
+                // Discovery may still hold the tracker lock
+                drop(fetching);
+                self.shutdown().await?;
+                return Ok(DownloadOutcome::Cancelled);
+            }
+        };
+        let storage = Storage::create(&info, output_dir)?;
+        let have = if resume {
+            let have = verify_existing(&info, &storage)?;
+            info!("Resuming with {} of {} pieces", have.count_ones(), have.len());
+            have
+        } else {
+            Bitfield::new(info.pieces.len())
+        };
+        // Completed is only for downloads that finish while we watch
+        let already_complete = have.count_ones() == have.len();
+        let outcome =
+            resume_pieces_until(peers, &info, &storage, &self.stats, have, shutdown).await?;
+        match outcome {
+            DownloadOutcome::Cancelled => self.shutdown().await?,
+            DownloadOutcome::Completed if !already_complete => self.completed().await?,
+            DownloadOutcome::Completed => {}
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::tracker_stream::tests::{
+        spawn_delayed_mock_tracker, spawn_mock_tracker, spawn_recording_mock_tracker,
+    };
+    use byteorder::{BigEndian, ByteOrder};
+    use futures::StreamExt;
+
+    fn mock_link(tracker: SocketAddr) -> String {
+        format!(
+            "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&tr=udp%3A%2F%2F{}",
+            tracker
+        )
+    }
+
+    #[test]
+    fn test_new_does_no_io() {
+        // Nothing listens on the discard port, so any I/O here would stall
+        let client = TRipClient::new(&mock_link("127.0.0.1:9".parse().unwrap())).unwrap();
+        assert_eq!(client.magnet.trackers.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_discover_peers() {
+        let tracker = spawn_mock_tracker().await;
+        let client = TRipClient::new(&mock_link(tracker)).unwrap();
+        let peers = client.discover_peers().await.unwrap();
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_discover_peers_blocking() {
+        let tracker = task::block_on(spawn_mock_tracker());
+        let client = TRipClient::new(&mock_link(tracker)).unwrap();
+        let peers = client.discover_peers_blocking().unwrap();
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[async_std::test]
+    async fn test_discover_includes_initial_peers() {
+        let tracker = spawn_mock_tracker().await;
+        let link = format!("{}&x.pe=10.0.0.1:6881&x.pe=127.0.0.1:6881", mock_link(tracker));
+        let client = TRipClient::new(&link).unwrap();
+        let peers = client.discover_peers().await.unwrap();
+        assert_eq!(
+            peers,
+            vec![
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "127.0.0.1:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_discover_deadline() {
+        let fast = spawn_mock_tracker().await;
+        // Hands out connection ids but never answers an announce
+        let slow = async_std::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let slow_addr = slow.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (_, client) = slow.recv_from(&mut buf).await.unwrap();
+                if BigEndian::read_u32(&buf[8..12]) == 0 {
+                    let mut response = vec![0u8; 16];
+                    response[4..8].copy_from_slice(&buf[12..16]);
+                    slow.send_to(&response, client).await.unwrap();
+                }
+            }
+        });
+        let link = format!("{}&tr=udp%3A%2F%2F{}", mock_link(fast), slow_addr);
+        let config = ClientConfig {
+            discover_timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let client = TRipClient::with_config(&link, config).unwrap();
+        let started = Instant::now();
+        let peers = client.discover_peers().await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[async_std::test]
+    async fn test_config_reaches_announce() {
+        let (tracker, mut announces) = spawn_recording_mock_tracker(60).await;
+        let config = ClientConfig {
+            port: 51413,
+            num_want: 25,
+            client_version: *b"0200",
+            ..Default::default()
+        };
+        let client = TRipClient::with_config(&mock_link(tracker), config).unwrap();
+        client.discover_peers().await.unwrap();
+        let request = announces.next().await.unwrap();
+        assert_eq!(&request[36..44], b"-WM0200-");
+        assert_eq!(BigEndian::read_i32(&request[92..96]), 25);
+        assert_eq!(BigEndian::read_u16(&request[96..98]), 51413);
+    }
+
+    #[test]
+    fn test_client_signature() {
+        let link = mock_link("127.0.0.1:9".parse().unwrap());
+        let config = ClientConfig {
+            client_signature: Some(b"-XY0300-".to_vec()),
+            ..Default::default()
+        };
+        let client = TRipClient::with_config(&link, config).unwrap();
+        assert_eq!(&client.peer_id[..8], b"-XY0300-");
+        let config = ClientConfig {
+            client_signature: Some(b"-XY00300-".to_vec()),
+            ..Default::default()
+        };
+        let err = TRipClient::with_config(&link, config).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::InvalidClientSignature(9))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_shared_tracker_port() {
+        let tracker = "127.0.0.1:9".parse().unwrap();
+        let config = |port| ClientConfig {
+            tracker_bind_addr: Some(SocketAddr::from(([127, 0, 0, 1], port))),
+            ..Default::default()
+        };
+        let two_trackers = format!("{}&tr=udp%3A%2F%2F127.0.0.1%3A10", mock_link(tracker));
+        let err = TRipClient::with_config(&two_trackers, config(6969)).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::SharedTrackerPort { trackers: 2, .. })
+        ));
+        // Fine with an ephemeral port, or with a single tracker
+        assert!(TRipClient::with_config(&two_trackers, config(0)).is_ok());
+        assert!(TRipClient::with_config(&mock_link(tracker), config(6969)).is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_announce_reports_left() {
+        let (tracker, mut announces) = spawn_recording_mock_tracker(60).await;
+        let client = TRipClient::new(&mock_link(tracker)).unwrap();
+        client.discover_peers().await.unwrap();
+        let left = |request: Vec<u8>| BigEndian::read_u64(&request[64..72]);
+        assert_eq!(left(announces.next().await.unwrap()), UNKNOWN_LEFT);
+        {
+            let mut stats = client.stats.lock().unwrap();
+            stats.set_total_length(100_000);
+            stats.set_pieces_completed(1, 32768);
+        }
+        client.completed().await.unwrap();
+        assert_eq!(left(announces.next().await.unwrap()), 100_000 - 32768);
+    }
+
+    #[async_std::test]
+    async fn test_announce_reports_transfer() {
+        let (tracker, mut announces) = spawn_recording_mock_tracker(60).await;
+        let client = TRipClient::new(&mock_link(tracker)).unwrap();
+        client.discover_peers().await.unwrap();
+        let request = announces.next().await.unwrap();
+        assert_eq!(BigEndian::read_u64(&request[56..64]), 0);
+        assert_eq!(BigEndian::read_u64(&request[72..80]), 0);
+        {
+            let mut stats = client.stats.lock().unwrap();
+            stats.record_block(16384);
+            stats.record_block(1000);
+            stats.record_upload(500);
+        }
+        client.completed().await.unwrap();
+        let request = announces.next().await.unwrap();
+        assert_eq!(BigEndian::read_u64(&request[56..64]), 17384);
+        assert_eq!(BigEndian::read_u64(&request[72..80]), 500);
+    }
+
+    #[async_std::test]
+    async fn test_completed_announce() {
+        let (tracker, mut announces) = spawn_recording_mock_tracker(60).await;
+        let client = TRipClient::new(&mock_link(tracker)).unwrap();
+        client.completed().await.unwrap();
+        client.discover_peers().await.unwrap();
+        client.completed().await.unwrap();
+        let event = |request: Vec<u8>| BigEndian::read_u32(&request[80..84]);
+        assert_eq!(event(announces.next().await.unwrap()), 2);
+        assert_eq!(event(announces.next().await.unwrap()), 1);
+    }
+
+    #[async_std::test]
+    async fn test_announce_loop() {
+        let (tracker, mut announces) = spawn_recording_mock_tracker(1).await;
+        let client = TRipClient::new(&mock_link(tracker)).unwrap();
+        let (peers_tx, peers_rx) = mpsc::unbounded();
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let event = |request: Vec<u8>| BigEndian::read_u32(&request[80..84]);
+        let driver = async {
+            assert_eq!(event(announces.next().await.unwrap()), AnnounceEvent::Started as u32);
+            assert_eq!(event(announces.next().await.unwrap()), AnnounceEvent::None as u32);
+            stop_tx.send(()).unwrap();
+            assert_eq!(event(announces.next().await.unwrap()), AnnounceEvent::Stopped as u32);
+        };
+        let (result, ()) = futures::join!(client.announce_loop(peers_tx, stop_rx), driver);
+        result.unwrap();
+        // The tracker reported the same peer twice, but it is only sent once
+        let peers = peers_rx.collect::<Vec<_>>().await;
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[async_std::test]
+    async fn test_announce_each_records_latency() {
+        let (slow, _) = spawn_delayed_mock_tracker(60, Duration::from_millis(200)).await;
+        let fast = spawn_mock_tracker().await;
+        let slow = Url::parse(&format!("udp://{}", slow)).unwrap();
+        let fast = Url::parse(&format!("udp://{}", fast)).unwrap();
+        let mut trackers = Trackers::new(
+            &[slow.clone(), fast.clone()],
+            RetransmitPolicy::default(),
+            None,
+        );
+        trackers.connect().await.unwrap();
+        let results = trackers
+            .announce_each(AnnounceRequestDescriptor::default())
+            .await;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert_eq!(results[0].0, fast);
+        assert!(trackers.latencies[&slow] >= Duration::from_millis(200));
+        assert!(trackers.latencies[&fast] < trackers.latencies[&slow]);
+        // The faster tracker is asked first from now on
+        assert_eq!(trackers.connections[0].addr, fast);
+    }
+
+    #[async_std::test]
+    async fn test_peer_stream_dedups() {
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            addrs.push(Url::parse(&format!("udp://{}", spawn_mock_tracker().await)).unwrap());
+        }
+        let mut trackers = Trackers::new(&addrs, RetransmitPolicy::default(), None);
+        trackers.connect().await.unwrap();
+        assert_eq!(trackers.connections.len(), 2);
+        // Both trackers report the same peer
+        let peers = trackers
+            .peer_stream(AnnounceRequestDescriptor::default())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[async_std::test]
+    async fn test_trackers_record_failures() {
+        let tracker = spawn_mock_tracker().await;
+        let good = Url::parse(&format!("udp://{}", tracker)).unwrap();
+        let bad = Url::parse("udp://tracker.invalid:1337").unwrap();
+        let mut trackers = Trackers::new(&[good.clone(), bad.clone()], RetransmitPolicy::default(), None);
+        trackers.connect().await.unwrap();
+        assert_eq!(trackers.connections.len(), 1);
+        assert_eq!(trackers.connections[0].addr, good);
+        assert_eq!(trackers.failures.len(), 1);
+        assert_eq!(trackers.failures[0].0, bad);
+        assert!(matches!(trackers.failures[0].1, TrackerError::Dns(_)));
+    }
+
+    #[async_std::test]
+    async fn test_announce_without_peers() {
+        // Answers every request with a valid header, announcing an empty swarm
+        let tracker = async_std::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+                let mut response = buf[8..16].to_vec();
+                // An interval of 900 and no leechers or seeders, which doubles as
+                // the connection id when answering a connect
+                response.extend_from_slice(&[0, 0, 3, 132, 0, 0, 0, 0, 0, 0, 0, 0]);
+                tracker.send_to(&response, client).await.unwrap();
+            }
+        });
+        let url = Url::parse(&format!("udp://{}", tracker_addr)).unwrap();
+        let mut trackers = Trackers::new(&[url], RetransmitPolicy::default(), None);
+        trackers.connect().await.unwrap();
+        let result = trackers
+            .announce(AnnounceRequestDescriptor::default())
+            .await
+            .unwrap();
+        assert!(result.peers.is_empty());
+        assert_eq!(result.interval, 900);
+        assert_eq!((result.leechers, result.seeders), (0, 0));
+        assert_eq!(trackers.interval, Some(Duration::from_secs(900)));
+        assert!(trackers.failures.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_trackers_skip_http() {
+        let tracker = spawn_mock_tracker().await;
+        let good = Url::parse(&format!("udp://{}", tracker)).unwrap();
+        // Would answer a UDP connect, if one were wrongly sent its way
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        silent.set_nonblocking(true).unwrap();
+        let http = Url::parse(&format!("http://{}/announce", silent.local_addr().unwrap())).unwrap();
+        let mut trackers = Trackers::new(&[http, good.clone()], RetransmitPolicy::default(), None);
+        assert_eq!(trackers.addrs, vec![good]);
+        trackers.connect().await.unwrap();
+        assert_eq!(trackers.connections.len(), 1);
+        assert!(trackers.failures.is_empty());
+        let mut buf = [0u8; 16];
+        assert!(silent.recv_from(&mut buf).is_err());
+    }
+
+    #[async_std::test]
+    async fn test_no_trackers() {
+        let mut trackers = Trackers::new(&[], RetransmitPolicy::default(), None);
+        assert!(matches!(trackers.connect().await, Err(ClientError::NoTrackers)));
+    }
+
+    #[async_std::test]
+    async fn test_no_trackers_reachable() {
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addrs = [
+            Url::parse(&format!("udp://{}", silent.local_addr().unwrap())).unwrap(),
+            Url::parse("udp://tracker.invalid:1337").unwrap(),
+        ];
+        let retransmit = RetransmitPolicy {
+            base_timeout: Duration::from_millis(50),
+            max_retries: 1,
+            deadline: Duration::from_millis(500),
+        };
+        let mut trackers = Trackers::new(&addrs, retransmit, None);
+        assert!(matches!(
+            trackers.connect().await,
+            Err(ClientError::NoTrackersReachable { attempted: 2 })
+        ));
+        assert_eq!(trackers.failures.len(), 2);
+        assert!(trackers
+            .failures
+            .iter()
+            .any(|(_, e)| matches!(e, TrackerError::Timeout { .. })));
+    }
+
+    #[async_std::test]
+    async fn test_shutdown_announces_stopped() {
+        let (tracker, mut announces) = spawn_recording_mock_tracker(60).await;
+        let client = TRipClient::new(&mock_link(tracker)).unwrap();
+        // Never contacted a tracker, so there is nobody to notify
+        client.shutdown().await.unwrap();
+        assert!(client.trackers.lock().await.connections.is_empty());
+
+        client.discover_peers().await.unwrap();
+        client.shutdown().await.unwrap();
+        assert!(client.trackers.lock().await.failures.is_empty());
+        let event = |request: Vec<u8>| BigEndian::read_u32(&request[80..84]);
+        assert_eq!(event(announces.next().await.unwrap()), 2);
+        assert_eq!(event(announces.next().await.unwrap()), 3);
+        // Nothing else was announced
+        assert!(announces.try_next().is_err());
+    }
+
+    #[async_std::test]
+    async fn test_resume_until_cancels_discovery() {
+        // The Started announce stalls, so discovery outlasts the signal
+        let delay = Duration::from_millis(300);
+        let (tracker, mut announces) = spawn_delayed_mock_tracker(60, delay).await;
+        let client = TRipClient::new(&mock_link(tracker)).unwrap();
+        let (stop, shutdown) = oneshot::channel();
+        let dir = std::env::temp_dir().join("t_rip_cancel_discovery");
+        let resume = client.resume_until(&dir, shutdown);
+        let cancel = async {
+            announces.next().await.unwrap();
+            stop.send(()).unwrap();
+        };
+        let (outcome, _) = futures::join!(resume, cancel);
+        assert_eq!(outcome.unwrap(), DownloadOutcome::Cancelled);
+        let event = BigEndian::read_u32(&announces.next().await.unwrap()[80..84]);
+        assert_eq!(event, 3);
+        // Cancelled before the metadata, so nothing was created
+        assert!(!dir.exists());
     }
 }
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    /// Decodes a single value that must span the whole buffer.
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Value> {
+        let (value, consumed) = Value::decode_prefix(bytes)?;
+        if consumed != bytes.len() {
+            anyhow::bail!("Trailing bytes after bencoded value");
+        }
+        Ok(value)
+    }
+    /// Decodes the value at the start of `bytes`, returning it along with the
+    /// number of bytes it occupied.
+    pub fn decode_prefix(bytes: &[u8]) -> anyhow::Result<(Value, usize)> {
+        let mut decoder = Decoder { bytes, pos: 0 };
+        let value = decoder.value(0)?;
+        Ok((value, decoder.pos))
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.encode_into(&mut bytes);
+        bytes
+    }
+    fn encode_into(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Value::Integer(i) => {
+                bytes.push(b'i');
+                bytes.extend_from_slice(i.to_string().as_bytes());
+                bytes.push(b'e');
+            }
+            Value::Bytes(b) => {
+                bytes.extend_from_slice(b.len().to_string().as_bytes());
+                bytes.push(b':');
+                bytes.extend_from_slice(b);
+            }
+            Value::List(list) => {
+                bytes.push(b'l');
+                for item in list {
+                    item.encode_into(bytes);
+                }
+                bytes.push(b'e');
+            }
+            Value::Dict(dict) => {
+                bytes.push(b'd');
+                for (key, value) in dict {
+                    Value::Bytes(key.clone()).encode_into(bytes);
+                    value.encode_into(bytes);
+                }
+                bytes.push(b'e');
+            }
+        }
+    }
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the raw encoded bytes of `key` in the top level dictionary, which
+/// is what info hashes are computed over.
+pub fn raw_dict_value<'a>(bytes: &'a [u8], key: &str) -> anyhow::Result<Option<&'a [u8]>> {
+    let mut decoder = Decoder { bytes, pos: 0 };
+    if decoder.peek()? != b'd' {
+        anyhow::bail!("Bencoded value is not a dictionary");
+    }
+    decoder.pos += 1;
+    while decoder.peek()? != b'e' {
+        let entry_key = decoder.byte_string()?;
+        let start = decoder.pos;
+        decoder.value(1)?;
+        if entry_key == key.as_bytes() {
+            return Ok(Some(&bytes[start..decoder.pos]));
+        }
+    }
+    Ok(None)
+}
+
+/// Nesting limit so hostile input can't exhaust the stack.
+const MAX_DEPTH: usize = 64;
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl Decoder<'_> {
+    fn peek(&self) -> anyhow::Result<u8> {
+        self.bytes
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Unexpected end of bencoded data"))
+    }
+    fn value(&mut self, depth: usize) -> anyhow::Result<Value> {
+        if depth > MAX_DEPTH {
+            anyhow::bail!("Bencoded data nested too deeply");
+        }
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let end = self.find(b'e')?;
+                let digits = std::str::from_utf8(&self.bytes[self.pos..end])?;
+                let value = digits.parse::<i64>()?;
+                self.pos = end + 1;
+                Ok(Value::Integer(value))
+            }
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.byte_string()?;
+                    let value = self.value(depth + 1)?;
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.byte_string()?)),
+            other => anyhow::bail!("Unexpected bencode token {:?}", other as char),
+        }
+    }
+    fn byte_string(&mut self) -> anyhow::Result<Vec<u8>> {
+        let colon = self.find(b':')?;
+        let length = std::str::from_utf8(&self.bytes[self.pos..colon])?.parse::<usize>()?;
+        let start = colon + 1;
+        let end = start
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow::anyhow!("Bencoded string overruns buffer"))?;
+        self.pos = end;
+        Ok(self.bytes[start..end].to_vec())
+    }
+    fn find(&self, byte: u8) -> anyhow::Result<usize> {
+        self.bytes[self.pos..]
+            .iter()
+            .position(|b| *b == byte)
+            .map(|offset| self.pos + offset)
+            .ok_or_else(|| anyhow::anyhow!("Unterminated bencoded value"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_values() {
+        assert_eq!(Value::decode(b"i42e").unwrap(), Value::Integer(42));
+        assert_eq!(Value::decode(b"i-3e").unwrap(), Value::Integer(-3));
+        assert_eq!(Value::decode(b"4:spam").unwrap(), Value::Bytes(b"spam".to_vec()));
+        assert_eq!(
+            Value::decode(b"l4:spami7ee").unwrap(),
+            Value::List(vec![Value::Bytes(b"spam".to_vec()), Value::Integer(7)])
+        );
+        let dict = Value::decode(b"d3:cow3:moo4:spami1ee").unwrap();
+        assert_eq!(dict.get("cow").and_then(Value::as_str), Some("moo"));
+        assert_eq!(dict.get("spam").and_then(Value::as_int), Some(1));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let bytes = b"d1:md11:ut_metadatai3ee13:metadata_sizei31235ee";
+        let value = Value::decode(bytes).unwrap();
+        assert_eq!(value.encode(), bytes.to_vec());
+    }
+
+    #[test]
+    fn test_decode_prefix() {
+        let (value, consumed) = Value::decode_prefix(b"d5:piecei0eeRAW").unwrap();
+        assert_eq!(consumed, 12);
+        assert_eq!(value.get("piece").and_then(Value::as_int), Some(0));
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        assert!(Value::decode(b"").is_err());
+        assert!(Value::decode(b"i42").is_err());
+        assert!(Value::decode(b"10:short").is_err());
+        assert!(Value::decode(b"l4:spam").is_err());
+        assert!(Value::decode(b"i1ei2e").is_err());
+        assert!(Value::decode(b"x").is_err());
+        assert!(Value::decode(&[b'l'; 100]).is_err());
+    }
+
+    #[test]
+    fn test_raw_dict_value() {
+        let bytes = b"d8:announce3:url4:infod4:name1:xee";
+        assert_eq!(raw_dict_value(bytes, "info").unwrap(), Some(&b"d4:name1:xe"[..]));
+        assert_eq!(raw_dict_value(bytes, "missing").unwrap(), None);
+        assert!(raw_dict_value(b"i1e", "info").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+use crate::peer::peer_stream::PeerConnection;
+
+/// Peers unchoked for their upload rate to us each round.
+pub const DEFAULT_UNCHOKE_SLOTS: usize = 4;
+/// How long the optimistic unchoke stays with one peer.
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The tit-for-tat choking algorithm (BEP 3). Each round unchokes the peers
+/// we download from fastest, plus one optimistically unchoked peer that is
+/// rotated every `OPTIMISTIC_UNCHOKE_INTERVAL` so others get a chance to
+/// prove themselves.
+#[derive(Debug)]
+pub struct ChokeManager {
+    slots: usize,
+    optimistic: Option<SocketAddr>,
+    rotated_at: Option<Instant>,
+}
+impl Default for ChokeManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_UNCHOKE_SLOTS)
+    }
+}
+impl ChokeManager {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            optimistic: None,
+            rotated_at: None,
+        }
+    }
+    /// The peer currently holding the optimistic unchoke.
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+    /// Picks the peers to unchoke given how fast we download from each.
+    pub fn round(&mut self, rates: &HashMap<SocketAddr, f64>) -> HashSet<SocketAddr> {
+        self.round_at(Instant::now(), rates)
+    }
+    fn round_at(&mut self, now: Instant, rates: &HashMap<SocketAddr, f64>) -> HashSet<SocketAddr> {
+        let mut by_rate = rates.iter().collect::<Vec<_>>();
+        // Fastest first, ties broken by address so rounds are stable
+        by_rate.sort_by(|a, b| b.1.total_cmp(a.1).then(a.0.cmp(b.0)));
+        let mut unchoked = by_rate
+            .iter()
+            .take(self.slots)
+            .map(|(addr, _)| **addr)
+            .collect::<HashSet<_>>();
+        let candidates = by_rate
+            .iter()
+            .skip(self.slots)
+            .map(|(addr, _)| **addr)
+            .collect::<Vec<_>>();
+        let due = self
+            .rotated_at
+            .is_none_or(|at| now.duration_since(at) >= OPTIMISTIC_UNCHOKE_INTERVAL);
+        // The optimistic peer may have left or earned a regular slot
+        let still_candidate = self
+            .optimistic
+            .is_some_and(|addr| candidates.contains(&addr));
+        if due || !still_candidate {
+            let others = candidates
+                .iter()
+                .filter(|addr| Some(**addr) != self.optimistic)
+                .copied()
+                .collect::<Vec<_>>();
+            let pool = if others.is_empty() {
+                &candidates
+            } else {
+                &others
+            };
+            self.optimistic = pool.choose(&mut rand::thread_rng()).copied();
+            self.rotated_at = Some(now);
+        }
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+    /// Runs a round over `peers`, choking or unchoking each of them. Peers
+    /// missing from `rates` count as sending us nothing. Connections that
+    /// fail to update are skipped; their sessions notice soon enough.
+    pub async fn apply(&mut self, peers: &mut [PeerConnection], rates: &HashMap<SocketAddr, f64>) {
+        let rates = peers
+            .iter()
+            .map(|peer| (peer.addr(), rates.get(&peer.addr()).copied().unwrap_or(0.0)))
+            .collect();
+        let unchoked = self.round(&rates);
+        for peer in peers {
+            let result = if unchoked.contains(&peer.addr()) {
+                peer.unchoke().await
+            } else {
+                peer.choke().await
+            };
+            if let Err(e) = result {
+                log::debug!("Failed to update choke state for {}: {}", peer.addr(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    #[test]
+    fn test_unchokes_fastest_peers() {
+        let mut manager = ChokeManager::new(2);
+        let rates = (1..=6)
+            .map(|n| (peer(n), n as f64 * 1000.0))
+            .collect::<HashMap<_, _>>();
+        let unchoked = manager.round(&rates);
+        assert_eq!(unchoked.len(), 3);
+        assert!(unchoked.contains(&peer(6)) && unchoked.contains(&peer(5)));
+        let optimistic = manager.optimistic().unwrap();
+        assert!(unchoked.contains(&optimistic));
+        assert!((1..=4).map(peer).any(|addr| addr == optimistic));
+
+        // A formerly slow peer overtakes the others
+        let mut rates = rates;
+        rates.insert(peer(1), 10000.0);
+        let unchoked = manager.round(&rates);
+        assert!(unchoked.contains(&peer(1)) && unchoked.contains(&peer(6)));
+        assert!(!unchoked.contains(&peer(5)) || manager.optimistic() == Some(peer(5)));
+    }
+
+    #[test]
+    fn test_optimistic_rotation() {
+        let mut manager = ChokeManager::new(2);
+        let rates = (1..=4)
+            .map(|n| (peer(n), n as f64))
+            .collect::<HashMap<_, _>>();
+        let start = Instant::now();
+        manager.round_at(start, &rates);
+        let first = manager.optimistic().unwrap();
+        assert!(first == peer(1) || first == peer(2));
+        // Kept until the interval is up
+        manager.round_at(start + Duration::from_secs(10), &rates);
+        assert_eq!(manager.optimistic(), Some(first));
+        // Then handed to the other choked peer
+        let later = start + OPTIMISTIC_UNCHOKE_INTERVAL;
+        let unchoked = manager.round_at(later, &rates);
+        let second = manager.optimistic().unwrap();
+        assert_ne!(second, first);
+        assert!(second == peer(1) || second == peer(2));
+        assert_eq!(unchoked, HashSet::from([peer(3), peer(4), second]));
+    }
+
+    #[test]
+    fn test_optimistic_replaced_when_promoted() {
+        let mut manager = ChokeManager::new(1);
+        let mut rates = HashMap::from([(peer(1), 5.0), (peer(2), 1.0)]);
+        manager.round(&rates);
+        assert_eq!(manager.optimistic(), Some(peer(2)));
+        rates.insert(peer(2), 10.0);
+        let unchoked = manager.round(&rates);
+        assert_eq!(manager.optimistic(), Some(peer(1)));
+        assert_eq!(unchoked, HashSet::from([peer(1), peer(2)]));
+    }
+}
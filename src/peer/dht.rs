@@ -0,0 +1,407 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_std::{
+    future,
+    net::{ToSocketAddrs, UdpSocket},
+};
+use byteorder::{BigEndian, ByteOrder};
+use log::debug;
+use rand::Rng;
+
+use crate::peer::bencode::Value;
+
+/// Well-known routers used to join the DHT (BEP 5).
+pub const DHT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Queries sent concurrently in each round of a lookup.
+const ALPHA: usize = 8;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_ROUNDS: usize = 16;
+const COMPACT_PEER_BYTES: usize = 6;
+const COMPACT_NODE_BYTES: usize = 26;
+const MAX_DATAGRAM: usize = 1500;
+
+/// A KRPC message: a bencoded dictionary sent over UDP (BEP 5).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KrpcMessage {
+    Query {
+        transaction_id: Vec<u8>,
+        method: String,
+        args: BTreeMap<Vec<u8>, Value>,
+    },
+    Response {
+        transaction_id: Vec<u8>,
+        values: BTreeMap<Vec<u8>, Value>,
+    },
+    Error {
+        transaction_id: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+impl KrpcMessage {
+    pub fn transaction_id(&self) -> &[u8] {
+        match self {
+            KrpcMessage::Query { transaction_id, .. }
+            | KrpcMessage::Response { transaction_id, .. }
+            | KrpcMessage::Error { transaction_id, .. } => transaction_id,
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"t".to_vec(), Value::Bytes(self.transaction_id().to_vec()));
+        match self {
+            KrpcMessage::Query { method, args, .. } => {
+                dict.insert(b"y".to_vec(), Value::Bytes(b"q".to_vec()));
+                dict.insert(b"q".to_vec(), Value::Bytes(method.as_bytes().to_vec()));
+                dict.insert(b"a".to_vec(), Value::Dict(args.clone()));
+            }
+            KrpcMessage::Response { values, .. } => {
+                dict.insert(b"y".to_vec(), Value::Bytes(b"r".to_vec()));
+                dict.insert(b"r".to_vec(), Value::Dict(values.clone()));
+            }
+            KrpcMessage::Error { code, message, .. } => {
+                dict.insert(b"y".to_vec(), Value::Bytes(b"e".to_vec()));
+                dict.insert(
+                    b"e".to_vec(),
+                    Value::List(vec![
+                        Value::Integer(*code),
+                        Value::Bytes(message.as_bytes().to_vec()),
+                    ]),
+                );
+            }
+        }
+        Value::Dict(dict).encode()
+    }
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let value = Value::decode(bytes)?;
+        let transaction_id = value
+            .get("t")
+            .and_then(Value::as_bytes)
+            .context("KRPC message missing transaction id")?
+            .to_vec();
+        let dict = |key| {
+            value
+                .get(key)
+                .and_then(Value::as_dict)
+                .cloned()
+                .with_context(|| format!("KRPC message missing '{}'", key))
+        };
+        match value.get("y").and_then(Value::as_bytes) {
+            Some(b"q") => Ok(KrpcMessage::Query {
+                transaction_id,
+                method: value
+                    .get("q")
+                    .and_then(Value::as_str)
+                    .context("KRPC query missing method")?
+                    .to_string(),
+                args: dict("a")?,
+            }),
+            Some(b"r") => Ok(KrpcMessage::Response {
+                transaction_id,
+                values: dict("r")?,
+            }),
+            Some(b"e") => {
+                let error = value
+                    .get("e")
+                    .and_then(Value::as_list)
+                    .context("KRPC error missing details")?;
+                Ok(KrpcMessage::Error {
+                    transaction_id,
+                    code: error.first().and_then(Value::as_int).unwrap_or_default(),
+                    message: error
+                        .get(1)
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string(),
+                })
+            }
+            _ => anyhow::bail!("Unknown KRPC message type"),
+        }
+    }
+}
+
+pub fn get_peers_query(
+    transaction_id: &[u8],
+    node_id: &[u8; 20],
+    info_hash: &[u8; 20],
+) -> KrpcMessage {
+    let mut args = BTreeMap::new();
+    args.insert(b"id".to_vec(), Value::Bytes(node_id.to_vec()));
+    args.insert(b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec()));
+    KrpcMessage::Query {
+        transaction_id: transaction_id.to_vec(),
+        method: "get_peers".to_string(),
+        args,
+    }
+}
+
+/// The useful parts of a `get_peers` response: peers for the info hash if
+/// the node knows any, and otherwise nodes closer to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GetPeersResponse {
+    pub peers: Vec<SocketAddr>,
+    pub nodes: Vec<([u8; 20], SocketAddr)>,
+}
+impl GetPeersResponse {
+    pub fn from_values(values: &BTreeMap<Vec<u8>, Value>) -> anyhow::Result<Self> {
+        let mut response = GetPeersResponse::default();
+        if let Some(peers) = values.get(&b"values"[..]) {
+            let peers = peers.as_list().context("get_peers values is not a list")?;
+            for peer in peers {
+                match peer.as_bytes() {
+                    Some(entry) if entry.len() == COMPACT_PEER_BYTES => {
+                        response.peers.push(compact_addr(entry))
+                    }
+                    _ => anyhow::bail!("Invalid compact peer in get_peers response"),
+                }
+            }
+        }
+        if let Some(nodes) = values.get(&b"nodes"[..]) {
+            let nodes = nodes
+                .as_bytes()
+                .context("get_peers nodes is not a string")?;
+            if !nodes.len().is_multiple_of(COMPACT_NODE_BYTES) {
+                anyhow::bail!("Invalid compact node list size {}", nodes.len());
+            }
+            for node in nodes.chunks(COMPACT_NODE_BYTES) {
+                let mut id = [0u8; 20];
+                id.copy_from_slice(&node[..20]);
+                response.nodes.push((id, compact_addr(&node[20..])));
+            }
+        }
+        Ok(response)
+    }
+}
+
+fn compact_addr(entry: &[u8]) -> SocketAddr {
+    let ip = Ipv4Addr::new(entry[0], entry[1], entry[2], entry[3]);
+    SocketAddr::new(IpAddr::V4(ip), BigEndian::read_u16(&entry[4..6]))
+}
+
+fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut distance = [0u8; 20];
+    for (d, (x, y)) in distance.iter_mut().zip(a.iter().zip(b)) {
+        *d = x ^ y;
+    }
+    distance
+}
+
+/// Looks `info_hash` up in the DHT, starting from `bootstrap` and walking
+/// towards the nodes closest to the hash, until `wanted` peers are found or
+/// there is nobody left to ask.
+pub async fn get_peers(
+    info_hash: &[u8; 20],
+    bootstrap: &[&str],
+    wanted: usize,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let mut node_id = [0u8; 20];
+    rand::thread_rng().fill(&mut node_id[..]);
+
+    // Bootstrap routers have unknown ids. Give them the complement of the
+    // hash, the id farthest from it by XOR distance, so the real nodes they
+    // hand out are tried first
+    let farthest = info_hash.map(|byte| !byte);
+    let mut candidates: Vec<([u8; 20], SocketAddr)> = Vec::new();
+    for node in bootstrap {
+        match node.to_socket_addrs().await {
+            Ok(addrs) => candidates.extend(
+                addrs
+                    .filter(SocketAddr::is_ipv4)
+                    .map(|addr| (farthest, addr)),
+            ),
+            Err(e) => debug!("Failed to resolve DHT node {}: {}", node, e),
+        }
+    }
+    let mut queried = HashSet::new();
+    let mut peers = Vec::new();
+    let mut next_transaction: u16 = rand::random();
+    for _ in 0..MAX_ROUNDS {
+        candidates.retain(|(_, addr)| !queried.contains(addr));
+        candidates.sort_by_key(|(id, _)| distance(id, info_hash));
+        if candidates.is_empty() {
+            break;
+        }
+        let mut pending = HashMap::new();
+        for (_, addr) in candidates.drain(..ALPHA.min(candidates.len())) {
+            next_transaction = next_transaction.wrapping_add(1);
+            let transaction_id = next_transaction.to_be_bytes().to_vec();
+            let query = get_peers_query(&transaction_id, &node_id, info_hash);
+            queried.insert(addr);
+            if socket.send_to(&query.to_bytes(), addr).await.is_ok() {
+                pending.insert(transaction_id, addr);
+            }
+        }
+        let round_started = Instant::now();
+        let mut buf = [0u8; MAX_DATAGRAM];
+        while !pending.is_empty() {
+            let remaining = QUERY_TIMEOUT.saturating_sub(round_started.elapsed());
+            let Ok(received) = future::timeout(remaining, socket.recv_from(&mut buf)).await else {
+                break;
+            };
+            // Errors such as an ICMP port unreachable surfacing here only
+            // concern one node, so the rest of the round carries on
+            let (n, from) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("DHT receive failed: {}", e);
+                    continue;
+                }
+            };
+            let Ok(message) = KrpcMessage::from_bytes(&buf[..n]) else {
+                continue;
+            };
+            if pending.get(message.transaction_id()) != Some(&from) {
+                continue;
+            }
+            pending.remove(message.transaction_id());
+            let KrpcMessage::Response { values, .. } = message else {
+                continue;
+            };
+            let Ok(response) = GetPeersResponse::from_values(&values) else {
+                continue;
+            };
+            for peer in response.peers {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+            candidates.extend(response.nodes);
+        }
+        if peers.len() >= wanted {
+            break;
+        }
+    }
+    debug!(
+        "DHT lookup found {} peers after querying {} nodes",
+        peers.len(),
+        queried.len()
+    );
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_peers_query_encoding() {
+        let query = get_peers_query(b"aa", &[b'a'; 20], &[b'b'; 20]);
+        let expected = b"d1:ad2:id20:aaaaaaaaaaaaaaaaaaaa9:info_hash20:bbbbbbbbbbbbbbbbbbbbe1:q9:get_peers1:t2:aa1:y1:qe";
+        assert_eq!(query.to_bytes(), expected);
+        assert_eq!(KrpcMessage::from_bytes(expected).unwrap(), query);
+    }
+
+    #[test]
+    fn test_decode_response_with_values() {
+        let bytes = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+        let message = KrpcMessage::from_bytes(bytes).unwrap();
+        assert_eq!(message.transaction_id(), b"aa");
+        let KrpcMessage::Response { values, .. } = &message else {
+            panic!("expected a response");
+        };
+        let response = GetPeersResponse::from_values(values).unwrap();
+        assert_eq!(
+            response.peers,
+            vec![
+                SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(b'a', b'x', b'j', b'e')),
+                    u16::from_be_bytes([b'.', b'u'])
+                ),
+                SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::new(b'i', b'd', b'h', b't')),
+                    u16::from_be_bytes([b'n', b'm'])
+                ),
+            ]
+        );
+        assert!(response.nodes.is_empty());
+        assert_eq!(message.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_decode_response_with_nodes() {
+        let mut nodes = vec![7u8; 20];
+        nodes.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1]);
+        let mut values = BTreeMap::new();
+        values.insert(b"nodes".to_vec(), Value::Bytes(nodes));
+        let response = GetPeersResponse::from_values(&values).unwrap();
+        assert_eq!(
+            response.nodes,
+            vec![([7u8; 20], "10.0.0.1:6881".parse().unwrap())]
+        );
+
+        values.insert(b"nodes".to_vec(), Value::Bytes(vec![0u8; 25]));
+        assert!(GetPeersResponse::from_values(&values).is_err());
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let bytes = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let message = KrpcMessage::from_bytes(bytes).unwrap();
+        assert_eq!(
+            message,
+            KrpcMessage::Error {
+                transaction_id: b"aa".to_vec(),
+                code: 201,
+                message: "A Generic Error Ocurred".to_string(),
+            }
+        );
+        assert_eq!(message.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(KrpcMessage::from_bytes(b"d1:y1:qe").is_err());
+        assert!(KrpcMessage::from_bytes(b"d1:t2:aa1:y1:xe").is_err());
+        assert!(KrpcMessage::from_bytes(b"d1:t2:aa1:y1:qe").is_err());
+    }
+
+    /// Answers every get_peers query with `values` or `nodes`.
+    async fn spawn_node(values: BTreeMap<Vec<u8>, Value>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut buf = [0u8; MAX_DATAGRAM];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+                let query = KrpcMessage::from_bytes(&buf[..n]).unwrap();
+                let response = KrpcMessage::Response {
+                    transaction_id: query.transaction_id().to_vec(),
+                    values: values.clone(),
+                };
+                socket.send_to(&response.to_bytes(), from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[async_std::test]
+    async fn test_lookup_follows_nodes() {
+        let mut values = BTreeMap::new();
+        values.insert(
+            b"values".to_vec(),
+            Value::List(vec![Value::Bytes(vec![10, 0, 0, 9, 0x1A, 0xE1])]),
+        );
+        let close = spawn_node(values).await;
+
+        let mut nodes = vec![1u8; 20];
+        nodes.extend_from_slice(&[127, 0, 0, 1]);
+        nodes.extend_from_slice(&close.port().to_be_bytes());
+        let mut values = BTreeMap::new();
+        values.insert(b"nodes".to_vec(), Value::Bytes(nodes));
+        let router = spawn_node(values).await.to_string();
+
+        let peers = get_peers(&[1u8; 20], &[router.as_str()], 1).await.unwrap();
+        assert_eq!(peers, vec!["10.0.0.9:6881".parse::<SocketAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,867 @@
+use std::{collections::{HashMap, HashSet}, future::Future, sync::Mutex, time::Duration};
+
+use async_std::future;
+use futures::{
+    channel::mpsc,
+    future::{select, Either},
+    stream::FuturesUnordered,
+    StreamExt,
+};
+use log::{debug, warn};
+use crate::peer::{
+    messages::{Bitfield, Message},
+    piece::PieceBuffer,
+    peer_stream::PeerConnection,
+    stats::StatsTracker,
+    storage::Storage,
+    torrent::Info,
+    verify::verify_piece,
+};
+
+/// Size of the blocks pieces are requested in.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+/// How long a peer may stay silent before we give up on it.
+pub(crate) const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Corrupt pieces tolerated from one peer before disconnecting it.
+const MAX_CORRUPT_PIECES: usize = 3;
+/// Endgame starts once every remaining piece is claimed and no more than
+/// this many blocks are left in them.
+const ENDGAME_MAX_BLOCKS: u64 = 20;
+
+/// How a download that didn't fail came to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadOutcome {
+    /// Every piece is verified and written.
+    Completed,
+    /// The shutdown signal fired first. Verified pieces stay on disk for a
+    /// later resume.
+    Cancelled,
+}
+
+/// Which pieces are done and which are being fetched.
+struct Progress {
+    completed: Bitfield,
+    in_progress: HashMap<usize, Claim>,
+    // Sessions to tell about each finished piece
+    watchers: Vec<mpsc::UnboundedSender<usize>>,
+}
+
+/// A piece some peers are currently fetching.
+#[derive(Debug, Default)]
+struct Claim {
+    peers: usize,
+    // Offsets of the blocks any of those peers has received
+    received: HashSet<u32>,
+}
+impl Progress {
+    fn resume(completed: Bitfield) -> Self {
+        Self {
+            completed,
+            in_progress: HashMap::new(),
+            watchers: Vec::new(),
+        }
+    }
+    fn next_piece(&mut self, available: &Bitfield) -> Option<usize> {
+        let index = available
+            .iter()
+            .find(|index| !self.completed.has(*index) && !self.in_progress.contains_key(index))?;
+        self.in_progress.insert(index, Claim { peers: 1, ..Default::default() });
+        Some(index)
+    }
+    /// In endgame, hands out a piece another peer is already fetching so the
+    /// last blocks don't hinge on a single slow peer.
+    fn endgame_piece(&mut self, available: &Bitfield, storage: &Storage) -> Option<usize> {
+        let unclaimed = (0..self.completed.len())
+            .any(|index| !self.completed.has(index) && !self.in_progress.contains_key(&index));
+        let remaining_blocks = self
+            .in_progress
+            .iter()
+            .map(|(index, claim)| {
+                let blocks = storage.piece_len(*index).div_ceil(BLOCK_SIZE as u64);
+                blocks.saturating_sub(claim.received.len() as u64)
+            })
+            .sum::<u64>();
+        if unclaimed || remaining_blocks > ENDGAME_MAX_BLOCKS {
+            return None;
+        }
+        let index = available
+            .iter()
+            .find(|index| self.in_progress.contains_key(index))?;
+        self.in_progress.entry(index).or_default().peers += 1;
+        Some(index)
+    }
+    /// Notes that the block at `begin` of piece `index` has arrived.
+    fn block_received(&mut self, index: usize, begin: u32) {
+        if let Some(claim) = self.in_progress.get_mut(&index) {
+            claim.received.insert(begin);
+        }
+    }
+    fn is_finished(&self, index: usize) -> bool {
+        self.completed.has(index)
+    }
+    fn finish(&mut self, index: usize) {
+        self.in_progress.remove(&index);
+        self.completed.set(index);
+        self.watchers.retain(|watcher| watcher.unbounded_send(index).is_ok());
+    }
+    /// Receives the index of every piece finished from now on.
+    fn watch(&mut self) -> mpsc::UnboundedReceiver<usize> {
+        let (watcher, finished) = mpsc::unbounded();
+        self.watchers.push(watcher);
+        finished
+    }
+    fn abandon(&mut self, index: usize) {
+        if let Some(claim) = self.in_progress.get_mut(&index) {
+            claim.peers -= 1;
+            if claim.peers == 0 {
+                self.in_progress.remove(&index);
+            }
+        }
+    }
+    fn is_complete(&self) -> bool {
+        self.completed.count_ones() == self.completed.len()
+    }
+}
+
+/// Request messages covering a piece of `piece_len` bytes in `block_size`
+/// blocks, the last of which may be shorter. `block_size` must be non-zero.
+pub fn block_requests(piece_index: u32, piece_len: u32, block_size: u32) -> Vec<Message> {
+    (0..piece_len)
+        .step_by(block_size as usize)
+        .map(|begin| Message::Request {
+            index: piece_index,
+            begin,
+            length: block_size.min(piece_len - begin),
+        })
+        .collect()
+}
+
+/// A piece being downloaded from a single peer.
+struct ActivePiece {
+    index: usize,
+    buffer: PieceBuffer,
+    // Blocks requested so far, in `requests` order
+    requested: usize,
+}
+impl ActivePiece {
+    fn new(index: usize, length: u64) -> Self {
+        Self {
+            index,
+            buffer: PieceBuffer::new(length as usize),
+            requested: 0,
+        }
+    }
+    fn requests(&self) -> Vec<Message> {
+        block_requests(self.index as u32, self.buffer.len() as u32, BLOCK_SIZE)
+    }
+    /// The next block to request, if any are left.
+    fn next_request(&mut self) -> Option<Message> {
+        let request = self.requests().into_iter().nth(self.requested)?;
+        self.requested += 1;
+        Some(request)
+    }
+    /// Cancel messages for the requested blocks that haven't arrived yet.
+    fn cancels(&self) -> Vec<Message> {
+        self.requests()
+            .into_iter()
+            .take(self.requested)
+            .filter_map(|request| match request {
+                Message::Request { index, begin, length }
+                    if !self.buffer.has_range(begin as usize, length as usize) =>
+                {
+                    Some(Message::Cancel { index, begin, length })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Downloads every piece of `info` from `peers`, writing verified pieces to
+/// `storage`. Returns once all pieces are written or every peer has failed.
+/// Progress is reported to `stats` as blocks arrive.
+pub async fn download_pieces(
+    peers: Vec<PeerConnection>,
+    info: &Info,
+    storage: &Storage,
+    stats: &Mutex<StatsTracker>,
+) -> anyhow::Result<()> {
+    let have = Bitfield::new(info.pieces.len());
+    resume_pieces(peers, info, storage, stats, have).await
+}
+
+/// Hashes every piece already in `storage`, returning which of them are
+/// intact. Pieces whose files are missing or too short count as absent.
+pub fn verify_existing(info: &Info, storage: &Storage) -> anyhow::Result<Bitfield> {
+    let mut have = Bitfield::new(info.pieces.len());
+    for (index, expected) in info.pieces.iter().enumerate() {
+        if let Some(data) = storage.read_piece(index)? {
+            if verify_piece(&data, expected) {
+                have.set(index);
+            }
+        }
+    }
+    Ok(have)
+}
+
+/// Like `download_pieces`, but only fetches the pieces missing from `have`.
+pub async fn resume_pieces(
+    peers: Vec<PeerConnection>,
+    info: &Info,
+    storage: &Storage,
+    stats: &Mutex<StatsTracker>,
+    have: Bitfield,
+) -> anyhow::Result<()> {
+    let never = futures::future::pending::<()>();
+    resume_pieces_until(peers, info, storage, stats, have, never).await?;
+    Ok(())
+}
+
+/// Like `resume_pieces`, but stops once `shutdown` completes. Pieces being
+/// fetched at that point are dropped along with the peer connections.
+pub async fn resume_pieces_until(
+    peers: Vec<PeerConnection>,
+    info: &Info,
+    storage: &Storage,
+    stats: &Mutex<StatsTracker>,
+    have: Bitfield,
+    shutdown: impl Future,
+) -> anyhow::Result<DownloadOutcome> {
+    {
+        let mut stats = stats.lock().unwrap();
+        stats.set_total_pieces(info.pieces.len());
+        stats.set_total_length(info.total_length());
+        let completed_bytes = have.iter().map(|index| storage.piece_len(index)).sum();
+        stats.set_pieces_completed(have.count_ones(), completed_bytes);
+    }
+    let progress = Mutex::new(Progress::resume(have));
+    if progress.lock().unwrap().is_complete() {
+        return Ok(DownloadOutcome::Completed);
+    }
+    let mut sessions = peers
+        .into_iter()
+        .map(|mut peer| {
+            let progress = &progress;
+            stats.lock().unwrap().peer_connected();
+            async move {
+                let result = download_from_peer(&mut peer, info, storage, progress, stats).await;
+                stats.lock().unwrap().peer_disconnected();
+                (peer.addr(), result)
+            }
+        })
+        .collect::<FuturesUnordered<_>>();
+    let mut shutdown = Box::pin(shutdown);
+    loop {
+        let (addr, result) = match select(sessions.next(), &mut shutdown).await {
+            Either::Left((Some(session), _)) => session,
+            Either::Left((None, _)) => break,
+            // Dropping the sessions closes their connections
+            Either::Right(_) => {
+                let mut stats = stats.lock().unwrap();
+                (0..sessions.len()).for_each(|_| stats.peer_disconnected());
+                let progress = progress.lock().unwrap();
+                debug!(
+                    "Download cancelled with {} of {} pieces",
+                    progress.completed.count_ones(),
+                    progress.completed.len()
+                );
+                return Ok(DownloadOutcome::Cancelled);
+            }
+        };
+        if let Err(e) = result {
+            debug!("Peer {} failed: {}", addr, e);
+        }
+        if progress.lock().unwrap().is_complete() {
+            return Ok(DownloadOutcome::Completed);
+        }
+    }
+    let progress = progress.lock().unwrap();
+    anyhow::bail!(
+        "Download incomplete: {} of {} pieces",
+        progress.completed.count_ones(),
+        progress.completed.len()
+    )
+}
+
+async fn download_from_peer(
+    peer: &mut PeerConnection,
+    info: &Info,
+    storage: &Storage,
+    progress: &Mutex<Progress>,
+    stats: &Mutex<StatsTracker>,
+) -> anyhow::Result<()> {
+    let mut active = None;
+    let result = run_session(peer, info, storage, progress, stats, &mut active).await;
+    // Hand an unfinished piece back so another peer can pick it up
+    if let Some(piece) = active {
+        progress.lock().unwrap().abandon(piece.index);
+    }
+    result
+}
+
+async fn run_session(
+    peer: &mut PeerConnection,
+    info: &Info,
+    storage: &Storage,
+    progress: &Mutex<Progress>,
+    stats: &Mutex<StatsTracker>,
+    active: &mut Option<ActivePiece>,
+) -> anyhow::Result<()> {
+    let num_pieces = info.pieces.len();
+    peer.set_piece_count(num_pieces);
+    let mut corrupt_pieces = 0;
+    let mut finished = progress.lock().unwrap().watch();
+    peer.send(Message::Interested).await?;
+    loop {
+        if progress.lock().unwrap().is_complete() {
+            return Ok(());
+        }
+        // Another peer finished our piece first, most likely in endgame
+        if let Some(piece) = active.take_if(|piece| progress.lock().unwrap().is_finished(piece.index)) {
+            for cancel in piece.cancels() {
+                peer.send(cancel).await?;
+            }
+        }
+        if peer.can_pipeline() && active.is_none() {
+            let next = {
+                let available = peer.bitfield().expect("piece count was set");
+                let mut progress = progress.lock().unwrap();
+                progress
+                    .next_piece(available)
+                    .or_else(|| progress.endgame_piece(available, storage))
+            };
+            if let Some(index) = next {
+                *active = Some(ActivePiece::new(index, storage.piece_len(index)));
+            }
+        }
+        // Keep the pipeline full, topping it up as blocks arrive
+        while peer.can_pipeline() {
+            let Some(request) = active.as_mut().and_then(ActivePiece::next_request) else {
+                break;
+            };
+            peer.send(request).await?;
+        }
+        let piece = active.as_ref().map(|piece| piece.index);
+        if !wait_readable(peer, piece, &mut finished).await? {
+            continue;
+        }
+        let message = future::timeout(PEER_IDLE_TIMEOUT, peer.read())
+            .await
+            .map_err(|_| anyhow::anyhow!("Peer went idle"))??;
+        match message {
+            Message::Choke => {
+                if let Some(piece) = active.take() {
+                    progress.lock().unwrap().abandon(piece.index);
+                }
+            }
+            // With the fast extension, a block may be refused without a
+            // choke. Give up on the piece so another peer can supply it.
+            Message::RejectRequest { index, .. } => {
+                if let Some(piece) = active.take_if(|piece| piece.index == index as usize) {
+                    progress.lock().unwrap().abandon(piece.index);
+                    for cancel in piece.cancels() {
+                        peer.send(cancel).await?;
+                    }
+                }
+            }
+            Message::Piece { index, begin, block } => {
+                let Some(piece) = active.as_mut().filter(|piece| piece.index == index as usize)
+                else {
+                    continue;
+                };
+                piece.buffer.add_block(begin, &block)?;
+                progress.lock().unwrap().block_received(piece.index, begin);
+                stats.lock().unwrap().record_block(block.len());
+                if piece.buffer.is_complete() {
+                    let piece = active.take().unwrap();
+                    if progress.lock().unwrap().is_finished(piece.index) {
+                        continue;
+                    }
+                    let data = piece.buffer.into_bytes().unwrap();
+                    if !verify_piece(&data, &info.pieces[piece.index]) {
+                        // Reject the piece so it gets requested again
+                        progress.lock().unwrap().abandon(piece.index);
+                        corrupt_pieces += 1;
+                        if corrupt_pieces >= MAX_CORRUPT_PIECES {
+                            anyhow::bail!("Peer sent too many corrupt pieces");
+                        }
+                        warn!("Peer sent corrupt data for piece {}", piece.index);
+                        continue;
+                    }
+                    storage.write_piece(piece.index, &data)?;
+                    progress.lock().unwrap().finish(piece.index);
+                    stats.lock().unwrap().piece_completed(data.len());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Waits for `peer` to send something, or returns `false` early if another
+/// peer finishes `piece` meanwhile so its requests can be cancelled right
+/// away rather than whenever this peer next speaks.
+async fn wait_readable(
+    peer: &PeerConnection,
+    piece: Option<usize>,
+    finished: &mut mpsc::UnboundedReceiver<usize>,
+) -> anyhow::Result<bool> {
+    let mut readable = Box::pin(future::timeout(PEER_IDLE_TIMEOUT, peer.readable()));
+    loop {
+        match select(readable, finished.next()).await {
+            Either::Left((result, _)) => {
+                result.map_err(|_| anyhow::anyhow!("Peer went idle"))??;
+                return Ok(true);
+            }
+            Either::Right((Some(index), _)) if Some(index) == piece => return Ok(false),
+            Either::Right((Some(_), pending)) => readable = pending,
+            Either::Right((None, pending)) => {
+                pending.await.map_err(|_| anyhow::anyhow!("Peer went idle"))??;
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::peer::{
+        messages::RawMessage,
+        peer_stream::{
+            tests::{accept_handshake, mock_handshake},
+            PeerStream, PeerStreamOpts,
+        },
+        torrent::FileLayout,
+    };
+    use async_std::{
+        net::{TcpListener, TcpStream},
+        prelude::*,
+    };
+    use byteorder::{BigEndian, ByteOrder};
+    use futures::{
+        channel::oneshot,
+        future::{self, Either},
+    };
+    use sha1::{Digest, Sha1};
+    use std::{fs, net::SocketAddr, path::PathBuf};
+
+    pub(crate) fn test_content() -> (Info, Vec<u8>) {
+        let content = (0..40000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let piece_length = 32768;
+        let pieces = content
+            .chunks(piece_length)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let info = Info {
+            name: "file.bin".to_string(),
+            piece_length: piece_length as u64,
+            pieces,
+            layout: FileLayout::Single {
+                length: content.len() as u64,
+            },
+        };
+        (info, content)
+    }
+
+    pub(crate) async fn read_frame(stream: &mut TcpStream) -> Option<RawMessage> {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).await.ok()?;
+        let mut bytes = vec![0u8; BigEndian::read_u32(&length) as usize];
+        stream.read_exact(&mut bytes).await.ok()?;
+        Some(RawMessage::from(&bytes[..]))
+    }
+
+    pub(crate) async fn write_frame(stream: &mut TcpStream, message: Message) {
+        let bytes: Vec<u8> = message.to_raw().into();
+        stream
+            .write_all(&(bytes.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&bytes).await.unwrap();
+    }
+
+    /// Serves `content` to a single leecher, corrupting the first response
+    /// to piece `corrupt_piece` if given.
+    pub(crate) async fn spawn_seeder(
+        info_hash: [u8; 20],
+        content: Vec<u8>,
+        piece_length: usize,
+        corrupt_piece: Option<u32>,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut stream = accept_handshake(&listener, mock_handshake(info_hash)).await;
+            let num_pieces = content.len().div_ceil(piece_length);
+            let mut bitfield = Bitfield::new(num_pieces);
+            (0..num_pieces).for_each(|index| bitfield.set(index));
+            write_frame(&mut stream, Message::Bitfield(bitfield.as_bytes().to_vec())).await;
+            write_frame(&mut stream, Message::Unchoke).await;
+            let mut corrupt_piece = corrupt_piece;
+            while let Some(raw) = read_frame(&mut stream).await {
+                if let Ok(Message::Request { index, begin, length }) = Message::from_raw(raw) {
+                    let start = index as usize * piece_length + begin as usize;
+                    let mut block = content[start..start + length as usize].to_vec();
+                    if corrupt_piece == Some(index) {
+                        block[0] ^= 0xFF;
+                        corrupt_piece = None;
+                    }
+                    write_frame(&mut stream, Message::Piece { index, begin, block }).await;
+                }
+            }
+        });
+        addr
+    }
+
+    pub(crate) fn test_opts(info_hash: [u8; 20]) -> PeerStreamOpts {
+        PeerStreamOpts::new(info_hash, [1u8; 20])
+    }
+
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("t_rip_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_block_requests_exact_multiple() {
+        let requests = block_requests(1, 32768, 16384);
+        assert_eq!(
+            requests,
+            vec![
+                Message::Request { index: 1, begin: 0, length: 16384 },
+                Message::Request { index: 1, begin: 16384, length: 16384 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_block_requests_remainder() {
+        let requests = block_requests(2, 20000, 16384);
+        assert_eq!(
+            requests,
+            vec![
+                Message::Request { index: 2, begin: 0, length: 16384 },
+                Message::Request { index: 2, begin: 16384, length: 3616 },
+            ]
+        );
+        assert_eq!(
+            block_requests(0, 100, 16384),
+            vec![Message::Request { index: 0, begin: 0, length: 100 }]
+        );
+        assert!(block_requests(0, 0, 16384).is_empty());
+    }
+
+    #[test]
+    fn test_active_piece_requests() {
+        let piece = ActivePiece::new(3, 40000);
+        let requests = piece.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[2],
+            Message::Request {
+                index: 3,
+                begin: 32768,
+                length: 40000 - 32768,
+            }
+        );
+    }
+
+    #[async_std::test]
+    async fn test_download_pieces() {
+        let (info, content) = test_content();
+        let info_hash = [5u8; 20];
+        let addr = spawn_seeder(info_hash, content.clone(), 32768, None).await;
+        let peer = PeerStream::connect(addr, test_opts(info_hash)).await.unwrap();
+        let peer = PeerConnection::new(peer);
+        let dir = temp_dir("download");
+        let storage = Storage::create(&info, &dir).unwrap();
+        let stats = Mutex::new(StatsTracker::new());
+        download_pieces(vec![peer], &info, &storage, &stats).await.unwrap();
+        assert_eq!(fs::read(dir.join("file.bin")).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+        let stats = stats.lock().unwrap().snapshot();
+        assert_eq!(stats.bytes_downloaded, content.len() as u64);
+        assert_eq!(stats.pieces_completed, 2);
+        assert_eq!(stats.total_pieces, 2);
+        assert_eq!(stats.active_peers, 0);
+    }
+
+    #[async_std::test]
+    async fn test_download_rerequests_corrupt_piece() {
+        let (info, content) = test_content();
+        let info_hash = [6u8; 20];
+        let addr = spawn_seeder(info_hash, content.clone(), 32768, Some(1)).await;
+        let peer = PeerStream::connect(addr, test_opts(info_hash)).await.unwrap();
+        let peer = PeerConnection::new(peer);
+        let dir = temp_dir("corrupt");
+        let storage = Storage::create(&info, &dir).unwrap();
+        let stats = Mutex::new(StatsTracker::new());
+        download_pieces(vec![peer], &info, &storage, &stats).await.unwrap();
+        assert_eq!(fs::read(dir.join("file.bin")).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_endgame_piece() {
+        let (info, _) = test_content();
+        let dir = temp_dir("endgame");
+        let storage = Storage::create(&info, &dir).unwrap();
+        let mut available = Bitfield::new(2);
+        available.set(0);
+        available.set(1);
+        let mut progress = Progress::resume(Bitfield::new(2));
+        assert_eq!(progress.next_piece(&available), Some(0));
+        // Piece 1 is still up for grabs, so it's too early for endgame
+        assert_eq!(progress.endgame_piece(&available, &storage), None);
+        assert_eq!(progress.next_piece(&available), Some(1));
+        assert_eq!(progress.next_piece(&available), None);
+        assert_eq!(progress.endgame_piece(&available, &storage), Some(0));
+        // The duplicate giving up leaves the original claim in place
+        progress.abandon(0);
+        assert!(progress.in_progress.contains_key(&0));
+        progress.finish(0);
+        assert!(progress.is_finished(0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_endgame_counts_missing_blocks() {
+        // Two pieces of 32 blocks each, far more than ENDGAME_MAX_BLOCKS
+        let piece_length = 32 * BLOCK_SIZE as u64;
+        let info = Info {
+            name: "large.bin".to_string(),
+            piece_length,
+            pieces: vec![[0u8; 20]; 2],
+            layout: FileLayout::Single {
+                length: 2 * piece_length,
+            },
+        };
+        let dir = temp_dir("endgame_large");
+        let storage = Storage::create(&info, &dir).unwrap();
+        let available = Bitfield::full(2);
+        let mut progress = Progress::resume(Bitfield::new(2));
+        assert_eq!(progress.next_piece(&available), Some(0));
+        assert_eq!(progress.next_piece(&available), Some(1));
+        assert_eq!(progress.endgame_piece(&available, &storage), None);
+        // Once few enough blocks are still missing, endgame starts
+        (0..30).for_each(|block| progress.block_received(0, block * BLOCK_SIZE));
+        (0..31).for_each(|block| progress.block_received(1, block * BLOCK_SIZE));
+        assert_eq!(progress.endgame_piece(&available, &storage), Some(0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_active_piece_cancels() {
+        let mut piece = ActivePiece::new(3, 40000);
+        // Nothing requested, nothing to cancel
+        assert!(piece.cancels().is_empty());
+        while piece.next_request().is_some() {}
+        piece.buffer.add_block(16384, &[0u8; 16384]).unwrap();
+        assert_eq!(
+            piece.cancels(),
+            vec![
+                Message::Cancel { index: 3, begin: 0, length: 16384 },
+                Message::Cancel { index: 3, begin: 32768, length: 40000 - 32768 },
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_cancels_piece_finished_elsewhere() {
+        let (info, _) = test_content();
+        let info_hash = [7u8; 20];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (requested_tx, requested_rx) = oneshot::channel();
+        let (cancels_tx, cancels_rx) = oneshot::channel();
+        // Offers piece 1 but never serves it, and stays silent after that
+        async_std::task::spawn(async move {
+            let mut stream = accept_handshake(&listener, mock_handshake(info_hash)).await;
+            let mut bitfield = Bitfield::new(2);
+            bitfield.set(1);
+            write_frame(&mut stream, Message::Bitfield(bitfield.as_bytes().to_vec())).await;
+            write_frame(&mut stream, Message::Unchoke).await;
+            let mut requested_tx = Some(requested_tx);
+            let mut cancels = Vec::new();
+            while let Some(raw) = read_frame(&mut stream).await {
+                match Message::from_raw(raw) {
+                    Ok(Message::Request { index: 1, .. }) => {
+                        if let Some(requested_tx) = requested_tx.take() {
+                            requested_tx.send(()).unwrap();
+                        }
+                    }
+                    Ok(cancel @ Message::Cancel { .. }) => {
+                        cancels.push(cancel);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            cancels_tx.send(cancels).unwrap();
+            async_std::task::sleep(Duration::from_secs(5)).await;
+        });
+        let peer = PeerStream::connect(addr, test_opts(info_hash)).await.unwrap();
+        let mut peer = PeerConnection::new(peer);
+        let dir = temp_dir("cancel");
+        let storage = Storage::create(&info, &dir).unwrap();
+        let progress = Mutex::new(Progress::resume(Bitfield::new(2)));
+        let stats = Mutex::new(StatsTracker::new());
+        let mut active = None;
+        let session = run_session(&mut peer, &info, &storage, &progress, &stats, &mut active);
+        let elsewhere = async {
+            requested_rx.await.unwrap();
+            progress.lock().unwrap().finish(1);
+            cancels_rx.await.unwrap()
+        };
+        match future::select(Box::pin(session), Box::pin(elsewhere)).await {
+            Either::Right((cancels, _)) => assert_eq!(
+                cancels,
+                vec![Message::Cancel { index: 1, begin: 0, length: 40000 - 32768 }]
+            ),
+            Either::Left((result, _)) => panic!("Session ended early: {:?}", result.err()),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_shutdown_mid_download() {
+        let (info, content) = test_content();
+        let info_hash = [5u8; 20];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        // Serves whichever piece is requested first and stalls on the other,
+        // reporting when the leecher hangs up
+        async_std::task::spawn(async move {
+            let mut stream = accept_handshake(&listener, mock_handshake(info_hash)).await;
+            write_frame(&mut stream, Message::Bitfield(Bitfield::full(2).as_bytes().to_vec()))
+                .await;
+            write_frame(&mut stream, Message::Unchoke).await;
+            let mut served = None;
+            while let Some(raw) = read_frame(&mut stream).await {
+                if let Ok(Message::Request { index, begin, length }) = Message::from_raw(raw) {
+                    if *served.get_or_insert(index) != index {
+                        continue;
+                    }
+                    let start = index as usize * 32768 + begin as usize;
+                    let block = content[start..start + length as usize].to_vec();
+                    write_frame(&mut stream, Message::Piece { index, begin, block }).await;
+                }
+            }
+            closed_tx.send(()).unwrap();
+        });
+        let peer = PeerStream::connect(addr, test_opts(info_hash)).await.unwrap();
+        let peer = PeerConnection::new(peer);
+        let dir = temp_dir("shutdown");
+        let storage = Storage::create(&info, &dir).unwrap();
+        let stats = Mutex::new(StatsTracker::new());
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let have = Bitfield::new(2);
+        let download = resume_pieces_until(vec![peer], &info, &storage, &stats, have, stop_rx);
+        let driver = async {
+            while stats.lock().unwrap().snapshot().pieces_completed < 1 {
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+            stop_tx.send(()).unwrap();
+        };
+        let (outcome, ()) = futures::join!(download, driver);
+        assert_eq!(outcome.unwrap(), DownloadOutcome::Cancelled);
+        // The connection was closed and the finished piece kept
+        async_std::future::timeout(Duration::from_secs(5), closed_rx).await.unwrap().unwrap();
+        let have = verify_existing(&info, &storage).unwrap();
+        assert_eq!(have.count_ones(), 1);
+        let stats = stats.lock().unwrap().snapshot();
+        assert_eq!(stats.active_peers, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_pipelines_requests() {
+        let content = (0..5 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let info = Info {
+            name: "pipelined.bin".to_string(),
+            piece_length: content.len() as u64,
+            pieces: vec![Sha1::digest(&content).into()],
+            layout: FileLayout::Single {
+                length: content.len() as u64,
+            },
+        };
+        let info_hash = [9u8; 20];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (burst_tx, burst_rx) = oneshot::channel();
+        // Holds back every block until the leecher stops requesting, so the
+        // first burst shows how deep it pipelines
+        let seeded = content.clone();
+        async_std::task::spawn(async move {
+            let mut stream = accept_handshake(&listener, mock_handshake(info_hash)).await;
+            write_frame(&mut stream, Message::Bitfield(vec![0b1000_0000])).await;
+            write_frame(&mut stream, Message::Unchoke).await;
+            let mut burst = Vec::new();
+            let quiet = Duration::from_millis(200);
+            while let Ok(Some(raw)) =
+                async_std::future::timeout(quiet, read_frame(&mut stream)).await
+            {
+                if let Ok(request @ Message::Request { .. }) = Message::from_raw(raw) {
+                    burst.push(request);
+                }
+            }
+            let mut queued = burst.clone();
+            burst_tx.send(burst).unwrap();
+            loop {
+                for request in queued.drain(..) {
+                    if let Message::Request { index, begin, length } = request {
+                        let start = begin as usize;
+                        let block = seeded[start..start + length as usize].to_vec();
+                        write_frame(&mut stream, Message::Piece { index, begin, block }).await;
+                    }
+                }
+                let Some(raw) = read_frame(&mut stream).await else {
+                    break;
+                };
+                if let Ok(request @ Message::Request { .. }) = Message::from_raw(raw) {
+                    queued.push(request);
+                }
+            }
+        });
+        let opts = test_opts(info_hash).pipeline_depth(3);
+        let peer = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        let dir = temp_dir("pipeline");
+        let storage = Storage::create(&info, &dir).unwrap();
+        let stats = Mutex::new(StatsTracker::new());
+        download_pieces(vec![peer], &info, &storage, &stats).await.unwrap();
+        assert_eq!(
+            burst_rx.await.unwrap(),
+            block_requests(0, 5 * BLOCK_SIZE, BLOCK_SIZE)[..3].to_vec()
+        );
+        assert_eq!(fs::read(dir.join("pipelined.bin")).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_resume_fetches_missing_pieces() {
+        let (info, content) = test_content();
+        let dir = temp_dir("resume");
+        let storage = Storage::create(&info, &dir).unwrap();
+        storage.write_piece(0, &content[..32768]).unwrap();
+        let have = verify_existing(&info, &storage).unwrap();
+        assert!(have.has(0));
+        assert!(!have.has(1));
+
+        let info_hash = [8u8; 20];
+        let addr = spawn_seeder(info_hash, content.clone(), 32768, None).await;
+        let peer = PeerStream::connect(addr, test_opts(info_hash)).await.unwrap();
+        let stats = Mutex::new(StatsTracker::new());
+        resume_pieces(vec![PeerConnection::new(peer)], &info, &storage, &stats, have)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(dir.join("file.bin")).unwrap(), content);
+        // Only the second, shorter piece came over the wire
+        let stats = stats.lock().unwrap().snapshot();
+        assert_eq!(stats.bytes_downloaded, (content.len() - 32768) as u64);
+        assert_eq!(stats.pieces_completed, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::peer::{bencode::Value, tracker_stream::decode_compact_peers};
+
+/// Extended message id reserved for the extension handshake (BEP 10).
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// Id we ask peers to use when sending us ut_metadata messages.
+pub const UT_METADATA_ID: u8 = 1;
+/// Id we ask peers to use when sending us ut_pex messages.
+pub const UT_PEX_ID: u8 = 2;
+/// Metadata is exchanged in 16KiB pieces, only the last may be shorter (BEP 9).
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+/// Upper bound on the advertised metadata size we are willing to download.
+pub const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    pub extensions: BTreeMap<String, u8>,
+    pub metadata_size: Option<usize>,
+}
+impl ExtendedHandshake {
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.extensions.get(name).copied().filter(|id| *id != 0)
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), Value::Integer(*id as i64)))
+            .collect();
+        let mut dict = BTreeMap::new();
+        dict.insert(b"m".to_vec(), Value::Dict(extensions));
+        if let Some(size) = self.metadata_size {
+            dict.insert(b"metadata_size".to_vec(), Value::Integer(size as i64));
+        }
+        Value::Dict(dict).encode()
+    }
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let value = Value::decode(bytes)?;
+        let mut extensions = BTreeMap::new();
+        if let Some(m) = value.get("m").and_then(Value::as_dict) {
+            for (name, id) in m {
+                let (Ok(name), Some(id)) = (String::from_utf8(name.clone()), id.as_int()) else {
+                    continue;
+                };
+                if let Ok(id) = u8::try_from(id) {
+                    extensions.insert(name, id);
+                }
+            }
+        }
+        let metadata_size = value
+            .get("metadata_size")
+            .and_then(Value::as_int)
+            .and_then(|size| usize::try_from(size).ok());
+        Ok(Self {
+            extensions,
+            metadata_size,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request { piece: usize },
+    Data { piece: usize, total_size: usize, data: Vec<u8> },
+    Reject { piece: usize },
+}
+impl MetadataMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (0, piece),
+            MetadataMessage::Data { piece, total_size, .. } => {
+                dict.insert(b"total_size".to_vec(), Value::Integer(*total_size as i64));
+                (1, piece)
+            }
+            MetadataMessage::Reject { piece } => (2, piece),
+        };
+        dict.insert(b"msg_type".to_vec(), Value::Integer(msg_type));
+        dict.insert(b"piece".to_vec(), Value::Integer(*piece as i64));
+        let mut bytes = Value::Dict(dict).encode();
+        if let MetadataMessage::Data { data, .. } = self {
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let (value, consumed) = Value::decode_prefix(bytes)?;
+        let int_field = |key: &str| {
+            value
+                .get(key)
+                .and_then(Value::as_int)
+                .and_then(|v| usize::try_from(v).ok())
+                .ok_or_else(|| anyhow::anyhow!("ut_metadata message missing {}", key))
+        };
+        let piece = int_field("piece")?;
+        match int_field("msg_type")? {
+            0 => Ok(MetadataMessage::Request { piece }),
+            1 => Ok(MetadataMessage::Data {
+                piece,
+                total_size: int_field("total_size")?,
+                data: bytes[consumed..].to_vec(),
+            }),
+            2 => Ok(MetadataMessage::Reject { piece }),
+            other => anyhow::bail!("Unknown ut_metadata msg_type {}", other),
+        }
+    }
+}
+
+/// A ut_pex message listing peers the sender connected to or dropped since
+/// its last one. Addresses are compact: 6 bytes for IPv4, 18 for IPv6.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<SocketAddr>,
+    pub dropped: Vec<SocketAddr>,
+}
+impl PexMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        let (added, added6) = encode_compact(&self.added);
+        let (dropped, dropped6) = encode_compact(&self.dropped);
+        // One flags byte per IPv4 peer; we know nothing about them to share
+        let flags = vec![0u8; added.len() / 6];
+        dict.insert(b"added.f".to_vec(), Value::Bytes(flags));
+        dict.insert(b"added".to_vec(), Value::Bytes(added));
+        dict.insert(b"added6".to_vec(), Value::Bytes(added6));
+        dict.insert(b"dropped".to_vec(), Value::Bytes(dropped));
+        dict.insert(b"dropped6".to_vec(), Value::Bytes(dropped6));
+        Value::Dict(dict).encode()
+    }
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let value = Value::decode(bytes)?;
+        let list = |key: &str, ipv6: bool| -> anyhow::Result<Vec<SocketAddr>> {
+            match value.get(key) {
+                Some(list) => {
+                    let list = list
+                        .as_bytes()
+                        .ok_or_else(|| anyhow::anyhow!("ut_pex {} is not a string", key))?;
+                    decode_compact_peers(list, ipv6).ok_or_else(|| {
+                        anyhow::anyhow!("Invalid compact peer list size {}", list.len())
+                    })
+                }
+                None => Ok(Vec::new()),
+            }
+        };
+        let mut added = list("added", false)?;
+        added.extend(list("added6", true)?);
+        let mut dropped = list("dropped", false)?;
+        dropped.extend(list("dropped6", true)?);
+        Ok(Self { added, dropped })
+    }
+}
+
+fn encode_compact(addrs: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let (mut v4, mut v6) = (Vec::new(), Vec::new());
+    for addr in addrs {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend_from_slice(&ip.octets());
+                v4.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                v6.extend_from_slice(&ip.octets());
+                v6.extend_from_slice(&addr.port().to_be_bytes());
+            }
+        }
+    }
+    (v4, v6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extended_handshake_round_trip() {
+        let mut handshake = ExtendedHandshake {
+            metadata_size: Some(31235),
+            ..Default::default()
+        };
+        handshake.extensions.insert("ut_metadata".to_string(), 3);
+        let bytes = handshake.to_bytes();
+        assert_eq!(bytes, b"d1:md11:ut_metadatai3ee13:metadata_sizei31235ee".to_vec());
+        let parsed = ExtendedHandshake::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, handshake);
+        assert_eq!(parsed.extension_id("ut_metadata"), Some(3));
+        assert_eq!(parsed.extension_id("ut_pex"), None);
+    }
+
+    #[test]
+    fn test_metadata_message_round_trip() {
+        let messages = vec![
+            MetadataMessage::Request { piece: 0 },
+            MetadataMessage::Data {
+                piece: 1,
+                total_size: 20000,
+                data: vec![1, 2, 3],
+            },
+            MetadataMessage::Reject { piece: 2 },
+        ];
+        for message in messages {
+            assert_eq!(MetadataMessage::from_bytes(&message.to_bytes()).unwrap(), message);
+        }
+        assert_eq!(
+            MetadataMessage::Request { piece: 0 }.to_bytes(),
+            b"d8:msg_typei0e5:piecei0ee".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_decode_pex_message() {
+        let mut bytes = b"d5:added12:".to_vec();
+        bytes.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1, 10, 0, 0, 2, 0x1A, 0xE2]);
+        bytes.extend_from_slice(b"7:added.f2:");
+        bytes.extend_from_slice(&[0x10, 0x02]);
+        bytes.extend_from_slice(b"6:added618:");
+        bytes.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80]);
+        bytes.extend_from_slice(b"7:dropped6:");
+        bytes.extend_from_slice(&[192, 168, 0, 5, 0x1F, 0x90]);
+        bytes.push(b'e');
+        let message = PexMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            message.added,
+            vec![
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:6882".parse().unwrap(),
+                "[2001:db8::1]:80".parse().unwrap(),
+            ]
+        );
+        assert_eq!(message.dropped, vec!["192.168.0.5:8080".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(PexMessage::from_bytes(&message.to_bytes()).unwrap(), message);
+    }
+
+    #[test]
+    fn test_decode_invalid_pex_message() {
+        assert!(PexMessage::from_bytes(b"d5:added5:abcdee").is_err());
+        assert!(PexMessage::from_bytes(b"d5:addedi1ee").is_err());
+        assert_eq!(PexMessage::from_bytes(b"de").unwrap(), PexMessage::default());
+    }
+}
@@ -0,0 +1,146 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Context;
+
+use crate::peer::{bencode::Value, tracker_stream::decode_compact_peers};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpPeer {
+    pub addr: SocketAddr,
+    /// Only present in the non-compact (`compact=0`) format.
+    pub peer_id: Option<Vec<u8>>,
+}
+
+/// A bencoded HTTP tracker announce response (BEP 3). Peers may come in the
+/// compact form (BEP 23), a string of packed address/port entries, or as a
+/// list of `{ip, port, peer id}` dictionaries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpAnnounceResponse {
+    pub interval: u32,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub peers: Vec<HttpPeer>,
+}
+impl HttpAnnounceResponse {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let value = Value::decode(bytes)?;
+        if let Some(reason) = value.get("failure reason") {
+            anyhow::bail!(
+                "Tracker refused announce: {}",
+                String::from_utf8_lossy(reason.as_bytes().unwrap_or_default())
+            );
+        }
+        let interval = value
+            .get("interval")
+            .and_then(Value::as_int)
+            .and_then(|interval| u32::try_from(interval).ok())
+            .context("Announce response missing interval")?;
+        let count = |key| {
+            value
+                .get(key)
+                .and_then(Value::as_int)
+                .and_then(|count| u32::try_from(count).ok())
+        };
+        let mut peers = match value.get("peers") {
+            Some(Value::Bytes(compact)) => compact_peers(compact, false)?,
+            Some(Value::List(list)) => list.iter().map(dict_peer).collect::<anyhow::Result<_>>()?,
+            Some(_) => anyhow::bail!("Announce response has malformed peers"),
+            None => Vec::new(),
+        };
+        if let Some(compact) = value.get("peers6").and_then(Value::as_bytes) {
+            peers.extend(compact_peers(compact, true)?);
+        }
+        Ok(Self {
+            interval,
+            seeders: count("complete"),
+            leechers: count("incomplete"),
+            peers,
+        })
+    }
+}
+
+fn compact_peers(bytes: &[u8], ipv6: bool) -> anyhow::Result<Vec<HttpPeer>> {
+    let addrs = decode_compact_peers(bytes, ipv6)
+        .with_context(|| format!("Invalid compact peer list size {}", bytes.len()))?;
+    Ok(addrs
+        .into_iter()
+        .map(|addr| HttpPeer { addr, peer_id: None })
+        .collect())
+}
+
+fn dict_peer(value: &Value) -> anyhow::Result<HttpPeer> {
+    let ip = value
+        .get("ip")
+        .and_then(Value::as_str)
+        .context("Peer entry missing ip")?
+        .parse::<IpAddr>()
+        .context("Peer entry has an invalid ip")?;
+    let port = value
+        .get("port")
+        .and_then(Value::as_int)
+        .and_then(|port| u16::try_from(port).ok())
+        .context("Peer entry missing port")?;
+    Ok(HttpPeer {
+        addr: SocketAddr::new(ip, port),
+        peer_id: value.get("peer id").and_then(Value::as_bytes).map(<[u8]>::to_vec),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_peers() {
+        let mut bytes = b"d8:completei5e10:incompletei3e8:intervali1800e5:peers12:".to_vec();
+        bytes.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1, 192, 168, 1, 2, 0, 80]);
+        bytes.extend_from_slice(b"6:peers618:");
+        bytes.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        bytes.extend_from_slice(&[0x1A, 0xE1]);
+        bytes.push(b'e');
+        let response = HttpAnnounceResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.seeders, Some(5));
+        assert_eq!(response.leechers, Some(3));
+        let addrs = response.peers.iter().map(|peer| peer.addr).collect::<Vec<_>>();
+        assert_eq!(
+            addrs,
+            vec![
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "192.168.1.2:80".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ]
+        );
+        assert!(response.peers.iter().all(|peer| peer.peer_id.is_none()));
+    }
+
+    #[test]
+    fn test_dict_peers() {
+        let bytes = b"d8:intervali900e5:peersld2:ip8:10.0.0.17:peer id20:-TR3000-abcdefghijkl4:porti6881eed2:ip11:2001:db8::14:porti51413eeee";
+        let response = HttpAnnounceResponse::from_bytes(bytes).unwrap();
+        assert_eq!(response.interval, 900);
+        assert_eq!(response.seeders, None);
+        assert_eq!(
+            response.peers,
+            vec![
+                HttpPeer {
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                    peer_id: Some(b"-TR3000-abcdefghijkl".to_vec()),
+                },
+                HttpPeer {
+                    addr: "[2001:db8::1]:51413".parse().unwrap(),
+                    peer_id: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed_responses() {
+        assert!(HttpAnnounceResponse::from_bytes(b"d14:failure reason6:bannede").is_err());
+        assert!(HttpAnnounceResponse::from_bytes(b"d5:peers0:e").is_err());
+        assert!(HttpAnnounceResponse::from_bytes(b"d8:intervali1e5:peers5:abcdee").is_err());
+        assert!(HttpAnnounceResponse::from_bytes(b"d8:intervali1e5:peersld4:porti1eeee").is_err());
+        assert!(HttpAnnounceResponse::from_bytes(b"d8:intervali1e5:peersi3ee").is_err());
+    }
+}
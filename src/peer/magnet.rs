@@ -1,7 +1,7 @@
-use std::{fmt::Display, str::FromStr};
+use std::{fmt::Display, net::SocketAddr, str::FromStr};
 use url::Url;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InfoHash {
     pub bytes: [u8; 20],
 }
@@ -12,48 +12,337 @@ impl Display for InfoHash {
     }
 }
 
+/// A file index or inclusive index range from the `so` parameter (BEP 53).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileSelection {
+    Single(usize),
+    Range(usize, usize),
+}
+impl FileSelection {
+    pub fn contains(&self, index: usize) -> bool {
+        match *self {
+            FileSelection::Single(single) => single == index,
+            FileSelection::Range(start, end) => (start..=end).contains(&index),
+        }
+    }
+}
+impl Display for FileSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            FileSelection::Single(single) => write!(f, "{}", single),
+            FileSelection::Range(start, end) => write!(f, "{}-{}", start, end),
+        }
+    }
+}
+impl FromStr for FileSelection {
+    type Err = MagnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || MagnetError::BadFileSelection(s.to_string());
+        match s.split_once('-') {
+            Some((start, end)) => {
+                let start = start.parse().map_err(|_| bad())?;
+                let end = end.parse().map_err(|_| bad())?;
+                if start > end {
+                    return Err(bad());
+                }
+                Ok(FileSelection::Range(start, end))
+            }
+            None => s.parse().map(FileSelection::Single).map_err(|_| bad()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Magnet {
     pub info_hash: InfoHash,
+    /// SHA-256 info hash of a v2 or hybrid torrent, from `urn:btmh:`.
+    pub info_hash_v2: Option<[u8; 32]>,
     pub display_name: String,
     pub trackers: Vec<Url>,
+    pub exact_topics: Vec<String>,
+    /// HTTP sources from `ws` (BEP 19) and `as` parameters.
+    pub web_seeds: Vec<Url>,
+    /// Files to download; empty means all of them.
+    pub selected_files: Vec<FileSelection>,
+    /// Peers to contact directly, from `x.pe` parameters (BEP 9).
+    pub initial_peers: Vec<SocketAddr>,
+    /// Search terms from `kt` parameters.
+    pub keywords: Vec<String>,
 }
 
-impl Magnet {
-    pub fn from_link(link: &str) -> anyhow::Result<Self> {
-        let decoded = urlencoding::decode(link)?;
-        let slice = &decoded[8..];
-        let split = slice.split("&").collect::<Vec<_>>();
+#[derive(thiserror::Error, Debug)]
+pub enum MagnetError {
+    #[error("Link is not a magnet uri")]
+    NotMagnet,
+    #[error("Failed to decode magnet link")]
+    Decode(#[from] std::string::FromUtf8Error),
+    #[error("Malformed magnet parameter: {0}")]
+    MalformedParameter(String),
+    #[error("Magnet link is missing an exact topic")]
+    MissingExactTopic,
+    #[error("Info hash has invalid length {0}")]
+    BadHashLength(usize),
+    #[error("Unsupported multihash function 0x{0:02x}")]
+    UnsupportedMultihash(u8),
+    #[error("Info hash is not valid hex")]
+    BadHex(#[from] hex::FromHexError),
+    #[error("Magnet link has no trackers")]
+    NoTrackers,
+    #[error("Invalid file selection: {0}")]
+    BadFileSelection(String),
+    #[error("Invalid peer address: {0}")]
+    BadPeerAddress(String),
+}
+
+const MAGNET_PREFIX: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+const BTMH_PREFIX: &str = "urn:btmh:";
+const INFO_HASH_HEX_LEN: usize = 40;
+/// Multihash function code for SHA-256, the only one v2 torrents use.
+const MULTIHASH_SHA256: u8 = 0x12;
+const SHA256_LEN: usize = 32;
+const TRACKER_SCHEMES: [&str; 3] = ["udp", "http", "https"];
+
+impl FromStr for Magnet {
+    type Err = MagnetError;
+
+    fn from_str(link: &str) -> Result<Self, Self::Err> {
+        let slice = link
+            .strip_prefix(MAGNET_PREFIX)
+            .ok_or(MagnetError::NotMagnet)?;
+        let split = slice.split('&').collect::<Vec<_>>();
 
         let mut trackers = Vec::new();
-        let mut exact_topic = [0u8; 20];
+        let mut exact_topics = Vec::new();
         let mut display_name = String::new();
+        let mut selected_files = Vec::new();
+        let mut web_seeds = Vec::new();
+        let mut initial_peers = Vec::new();
+        let mut keywords = Vec::new();
         for item in split {
-            let (id, value) = item.split_once("=").unwrap();
+            let (id, raw_value) = item
+                .split_once('=')
+                .ok_or_else(|| MagnetError::MalformedParameter(item.to_string()))?;
+            // Values are decoded individually so escaped '&' and '=' survive
+            let decoded = urlencoding::decode(raw_value)?;
+            let value = decoded.as_ref();
             match id {
                 "xt" => {
-                    let info_string = value[value.len() - 40..].as_bytes();
-                    let bytes = hex::decode(info_string)?;
-                    exact_topic.copy_from_slice(bytes.as_slice());
+                    exact_topics.push(String::from(value));
                 }
                 "dn" => {
-                    display_name = String::from(value);
+                    // Query encoding uses '+' for spaces; swap them before
+                    // decoding so an escaped %2B stays a literal plus
+                    display_name = urlencoding::decode(&raw_value.replace('+', " "))?.into_owned();
+                }
+                "kt" => {
+                    // '+' separates the terms, so split before decoding
+                    for keyword in raw_value.split('+').filter(|keyword| !keyword.is_empty()) {
+                        keywords.push(urlencoding::decode(keyword)?.into_owned());
+                    }
+                }
+                "ws" | "as" => {
+                    if let Ok(seed) = Url::from_str(value) {
+                        web_seeds.push(seed);
+                    }
+                }
+                "x.pe" => {
+                    if let Some(peer) = parse_peer_address(value)? {
+                        if !initial_peers.contains(&peer) {
+                            initial_peers.push(peer);
+                        }
+                    }
+                }
+                "so" => {
+                    for selection in value.split(',') {
+                        selected_files.push(selection.parse()?);
+                    }
                 }
                 "tr" => {
-                    if let Some(tracker) = Url::from_str(value).ok() {
-                        trackers.push(tracker);
+                    if let Ok(tracker) = Url::from_str(value) {
+                        if is_supported_tracker(&tracker)
+                            && !trackers.iter().any(|t| same_tracker(t, &tracker))
+                        {
+                            trackers.push(tracker);
+                        }
                     }
                 }
                 &_ => (),
             }
         }
+        let info_hash_v2 = exact_topics
+            .iter()
+            .find_map(|topic| topic.strip_prefix(BTMH_PREFIX))
+            .map(parse_multihash)
+            .transpose()?;
+        let info_string = exact_topics
+            .iter()
+            .find_map(|topic| topic.strip_prefix(BTIH_PREFIX))
+            .ok_or(MagnetError::MissingExactTopic)?;
+        if info_string.len() != INFO_HASH_HEX_LEN {
+            return Err(MagnetError::BadHashLength(info_string.len()));
+        }
+        let mut exact_topic = [0u8; 20];
+        hex::decode_to_slice(info_string, &mut exact_topic)?;
+        if trackers.is_empty() {
+            return Err(MagnetError::NoTrackers);
+        }
         Ok(Self {
             info_hash: InfoHash { bytes: exact_topic },
+            info_hash_v2,
             display_name,
             trackers,
+            exact_topics,
+            web_seeds,
+            selected_files,
+            initial_peers,
+            keywords,
         })
     }
 }
 
+impl Magnet {
+    /// Same as `link.parse::<Magnet>()`.
+    pub fn from_link(link: &str) -> Result<Self, MagnetError> {
+        link.parse()
+    }
+
+    /// Serializes the magnet back into a link that parses to the same
+    /// `Magnet`. Exact topics are written as-is; the display name, keywords,
+    /// trackers and web seeds are percent-encoded. Web seeds are all written
+    /// as `ws`.
+    pub fn to_link(&self) -> String {
+        let mut params = Vec::new();
+        if self.exact_topics.is_empty() {
+            params.push(format!("xt={}{}", BTIH_PREFIX, hex::encode(self.info_hash.bytes)));
+            if let Some(hash) = self.info_hash_v2 {
+                let multihash = [&[MULTIHASH_SHA256, SHA256_LEN as u8][..], &hash].concat();
+                params.push(format!("xt={}{}", BTMH_PREFIX, hex::encode(multihash)));
+            }
+        }
+        for topic in &self.exact_topics {
+            params.push(format!("xt={}", topic));
+        }
+        if !self.display_name.is_empty() {
+            params.push(format!("dn={}", urlencoding::encode(&self.display_name)));
+        }
+        if !self.keywords.is_empty() {
+            let keywords = self
+                .keywords
+                .iter()
+                .map(|keyword| urlencoding::encode(keyword))
+                .collect::<Vec<_>>();
+            params.push(format!("kt={}", keywords.join("+")));
+        }
+        for tracker in &self.trackers {
+            params.push(format!("tr={}", urlencoding::encode(tracker.as_str())));
+        }
+        for seed in &self.web_seeds {
+            params.push(format!("ws={}", urlencoding::encode(seed.as_str())));
+        }
+        for peer in &self.initial_peers {
+            params.push(format!("x.pe={}", urlencoding::encode(&peer.to_string())));
+        }
+        if !self.selected_files.is_empty() {
+            let selections = self
+                .selected_files
+                .iter()
+                .map(FileSelection::to_string)
+                .collect::<Vec<_>>();
+            params.push(format!("so={}", selections.join(",")));
+        }
+        format!("{}{}", MAGNET_PREFIX, params.join("&"))
+    }
+
+    /// Whether the file at `index` should be downloaded.
+    pub fn is_file_selected(&self, index: usize) -> bool {
+        self.selected_files.is_empty()
+            || self.selected_files.iter().any(|selection| selection.contains(index))
+    }
+
+    /// The trackers spoken to over UDP (BEP 15).
+    pub fn udp_trackers(&self) -> Vec<&Url> {
+        self.trackers_with_scheme(&["udp"])
+    }
+
+    /// The trackers spoken to over HTTP or HTTPS (BEP 3).
+    pub fn http_trackers(&self) -> Vec<&Url> {
+        self.trackers_with_scheme(&["http", "https"])
+    }
+
+    fn trackers_with_scheme(&self, schemes: &[&str]) -> Vec<&Url> {
+        self.trackers
+            .iter()
+            .filter(|tracker| schemes.contains(&tracker.scheme()))
+            .collect()
+    }
+
+    /// Groups trackers by host, keeping hosts and the trackers within them in
+    /// the order they appeared in the link. UDP and HTTP trackers are both
+    /// retained, so a host serving both schemes forms a single tier.
+    pub fn tracker_tiers(&self) -> Vec<Vec<Url>> {
+        let mut tiers: Vec<Vec<Url>> = Vec::new();
+        for tracker in &self.trackers {
+            match tiers
+                .iter_mut()
+                .find(|tier| tier[0].host_str() == tracker.host_str())
+            {
+                Some(tier) => tier.push(tracker.clone()),
+                None => tiers.push(vec![tracker.clone()]),
+            }
+        }
+        tiers
+    }
+}
+
+/// Decodes the hex multihash of a `urn:btmh:` topic: a function code, the
+/// digest length and the digest itself, which must be SHA-256.
+fn parse_multihash(value: &str) -> Result<[u8; 32], MagnetError> {
+    let bytes = hex::decode(value)?;
+    match bytes.as_slice() {
+        [MULTIHASH_SHA256, len, digest @ ..]
+            if *len as usize == SHA256_LEN && digest.len() == SHA256_LEN =>
+        {
+            Ok(digest.try_into().unwrap())
+        }
+        [MULTIHASH_SHA256, ..] | [] => Err(MagnetError::BadHashLength(value.len())),
+        [code, ..] => Err(MagnetError::UnsupportedMultihash(*code)),
+    }
+}
+
+/// Parses an `x.pe` value of the form `ipv4:port` or `[ipv6]:port`.
+/// `hostname:port` is also valid but would need a DNS lookup, so such peers
+/// are skipped rather than rejected.
+fn parse_peer_address(value: &str) -> Result<Option<SocketAddr>, MagnetError> {
+    if let Ok(addr) = value.parse() {
+        return Ok(Some(addr));
+    }
+    match value.rsplit_once(':') {
+        Some((host, port))
+            if !host.is_empty()
+                && !host.contains([':', '[', ']'])
+                && host.parse::<std::net::IpAddr>().is_err()
+                && port.parse::<u16>().is_ok() =>
+        {
+            Ok(None)
+        }
+        _ => Err(MagnetError::BadPeerAddress(value.to_string())),
+    }
+}
+
+fn is_supported_tracker(url: &Url) -> bool {
+    url.host_str().is_some() && TRACKER_SCHEMES.contains(&url.scheme())
+}
+
+/// Trackers are considered the same when scheme, host and port match; the
+/// announce path is ignored.
+fn same_tracker(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme()
+        && a.host_str().map(str::to_ascii_lowercase) == b.host_str().map(str::to_ascii_lowercase)
+        && a.port_or_known_default() == b.port_or_known_default()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -61,27 +350,324 @@ mod tests {
     #[test]
     fn test_parse_info_hash() {
         let link = "magnet:?xt=urn:btih:62B9305B850F2219B960929EC4CBD2E826004D73&dn=Eminem+-+Curtain+Call+2+%28Explicit%29+%282022%29+Mp3+320kbps+%5BPMEDIA%5D+%E2%AD%90%EF%B8%8F&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce&tr=udp%3A%2F%2Ftracker.openbittorrent.com%3A6969%2Fannounce&tr=udp%3A%2F%2Fopen.demonii.com%3A1337&tr=udp%3A%2F%2F9.rarbg.me%3A2980%2Fannounce&tr=udp%3A%2F%2Fexodus.desync.com%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.moeking.me%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.torrent.eu.org%3A451%2Fannounce&tr=udp%3A%2F%2Fexplodie.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fretracker.lanta-net.ru%3A2710%2Fannounce&tr=udp%3A%2F%2Ftracker.tiny-vps.com%3A6969%2Fannounce&tr=http%3A%2F%2Ftracker.files.fm%3A6969%2Fannounce&tr=udp%3A%2F%2Ffe.dealclub.de%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.leech.ie%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=http%3A%2F%2Ftracker.openbittorrent.com%3A80%2Fannounce&tr=udp%3A%2F%2Fopentracker.i2p.rocks%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.internetwarriors.net%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.leechers-paradise.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fcoppersurfer.tk%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.zer0day.to%3A1337%2Fannounce";
-        let magnet = Magnet::from_link(&link).unwrap();
+        let magnet = Magnet::from_link(link).unwrap();
+        let encoded = hex::encode(magnet.info_hash.bytes).to_uppercase();
+        assert_eq!(encoded, "62B9305B850F2219B960929EC4CBD2E826004D73");
+    }
+
+    #[test]
+    fn test_parse_with_from_str() {
+        let link = "magnet:?xt=urn:btih:62B9305B850F2219B960929EC4CBD2E826004D73&dn=Eminem+-+Curtain+Call+2+%28Explicit%29+%282022%29+Mp3+320kbps+%5BPMEDIA%5D+%E2%AD%90%EF%B8%8F&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce&tr=udp%3A%2F%2Ftracker.openbittorrent.com%3A6969%2Fannounce&tr=udp%3A%2F%2Fopen.demonii.com%3A1337&tr=udp%3A%2F%2F9.rarbg.me%3A2980%2Fannounce&tr=udp%3A%2F%2Fexodus.desync.com%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.moeking.me%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.torrent.eu.org%3A451%2Fannounce&tr=udp%3A%2F%2Fexplodie.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fretracker.lanta-net.ru%3A2710%2Fannounce&tr=udp%3A%2F%2Ftracker.tiny-vps.com%3A6969%2Fannounce&tr=http%3A%2F%2Ftracker.files.fm%3A6969%2Fannounce&tr=udp%3A%2F%2Ffe.dealclub.de%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.leech.ie%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=http%3A%2F%2Ftracker.openbittorrent.com%3A80%2Fannounce&tr=udp%3A%2F%2Fopentracker.i2p.rocks%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.internetwarriors.net%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.leechers-paradise.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fcoppersurfer.tk%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.zer0day.to%3A1337%2Fannounce";
+        let magnet = link.parse::<Magnet>().unwrap();
         let encoded = hex::encode(magnet.info_hash.bytes).to_uppercase();
         assert_eq!(encoded, "62B9305B850F2219B960929EC4CBD2E826004D73");
+        assert!(matches!("not a magnet".parse::<Magnet>(), Err(MagnetError::NotMagnet)));
     }
 
     #[test]
     fn test_parse_display_name() {
         let link = "magnet:?xt=urn:btih:62B9305B850F2219B960929EC4CBD2E826004D73&dn=Eminem+-+Curtain+Call+2+%28Explicit%29+%282022%29+Mp3+320kbps+%5BPMEDIA%5D+%E2%AD%90%EF%B8%8F&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce&tr=udp%3A%2F%2Ftracker.openbittorrent.com%3A6969%2Fannounce&tr=udp%3A%2F%2Fopen.demonii.com%3A1337&tr=udp%3A%2F%2F9.rarbg.me%3A2980%2Fannounce&tr=udp%3A%2F%2Fexodus.desync.com%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.moeking.me%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.torrent.eu.org%3A451%2Fannounce&tr=udp%3A%2F%2Fexplodie.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fretracker.lanta-net.ru%3A2710%2Fannounce&tr=udp%3A%2F%2Ftracker.tiny-vps.com%3A6969%2Fannounce&tr=http%3A%2F%2Ftracker.files.fm%3A6969%2Fannounce&tr=udp%3A%2F%2Ffe.dealclub.de%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.leech.ie%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=http%3A%2F%2Ftracker.openbittorrent.com%3A80%2Fannounce&tr=udp%3A%2F%2Fopentracker.i2p.rocks%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.internetwarriors.net%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.leechers-paradise.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fcoppersurfer.tk%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.zer0day.to%3A1337%2Fannounce";
-        let magnet = Magnet::from_link(&link).unwrap();
-        let expected = "Eminem+-+Curtain+Call+2+(Explicit)+(2022)+Mp3+320kbps+[PMEDIA]+⭐\u{fe0f}";
+        let magnet = Magnet::from_link(link).unwrap();
+        let expected = "Eminem - Curtain Call 2 (Explicit) (2022) Mp3 320kbps [PMEDIA] ⭐\u{fe0f}";
         assert_eq!(magnet.display_name, expected);
     }
 
     #[test]
     fn test_parse_trackers() {
         let link = "magnet:?xt=urn:btih:62B9305B850F2219B960929EC4CBD2E826004D73&dn=Eminem+-+Curtain+Call+2+%28Explicit%29+%282022%29+Mp3+320kbps+%5BPMEDIA%5D+%E2%AD%90%EF%B8%8F&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce&tr=udp%3A%2F%2Ftracker.openbittorrent.com%3A6969%2Fannounce&tr=udp%3A%2F%2Fopen.demonii.com%3A1337&tr=udp%3A%2F%2F9.rarbg.me%3A2980%2Fannounce&tr=udp%3A%2F%2Fexodus.desync.com%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.moeking.me%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.torrent.eu.org%3A451%2Fannounce&tr=udp%3A%2F%2Fexplodie.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fretracker.lanta-net.ru%3A2710%2Fannounce&tr=udp%3A%2F%2Ftracker.tiny-vps.com%3A6969%2Fannounce&tr=http%3A%2F%2Ftracker.files.fm%3A6969%2Fannounce&tr=udp%3A%2F%2Ffe.dealclub.de%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.leech.ie%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=http%3A%2F%2Ftracker.openbittorrent.com%3A80%2Fannounce&tr=udp%3A%2F%2Fopentracker.i2p.rocks%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.internetwarriors.net%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.leechers-paradise.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fcoppersurfer.tk%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.zer0day.to%3A1337%2Fannounce";
-        let magnet = Magnet::from_link(&link).unwrap();
-        assert!(magnet.trackers.len() == 21);
+        let magnet = Magnet::from_link(link).unwrap();
+        // 21 entries, with udp://tracker.opentrackr.org:1337 listed twice
+        assert!(magnet.trackers.len() == 20);
         assert_eq!(
             magnet.trackers.first().unwrap().as_str(),
             "udp://tracker.opentrackr.org:1337/announce"
         );
     }
+
+    #[test]
+    fn test_trackers_by_protocol() {
+        let link = "magnet:?xt=urn:btih:62B9305B850F2219B960929EC4CBD2E826004D73&dn=Eminem+-+Curtain+Call+2+%28Explicit%29+%282022%29+Mp3+320kbps+%5BPMEDIA%5D+%E2%AD%90%EF%B8%8F&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce&tr=udp%3A%2F%2Ftracker.openbittorrent.com%3A6969%2Fannounce&tr=udp%3A%2F%2Fopen.demonii.com%3A1337&tr=udp%3A%2F%2F9.rarbg.me%3A2980%2Fannounce&tr=udp%3A%2F%2Fexodus.desync.com%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.moeking.me%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.torrent.eu.org%3A451%2Fannounce&tr=udp%3A%2F%2Fexplodie.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fretracker.lanta-net.ru%3A2710%2Fannounce&tr=udp%3A%2F%2Ftracker.tiny-vps.com%3A6969%2Fannounce&tr=http%3A%2F%2Ftracker.files.fm%3A6969%2Fannounce&tr=udp%3A%2F%2Ffe.dealclub.de%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.leech.ie%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=http%3A%2F%2Ftracker.openbittorrent.com%3A80%2Fannounce&tr=udp%3A%2F%2Fopentracker.i2p.rocks%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.internetwarriors.net%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.leechers-paradise.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fcoppersurfer.tk%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.zer0day.to%3A1337%2Fannounce";
+        let magnet = Magnet::from_link(link).unwrap();
+        assert_eq!(magnet.udp_trackers().len(), 18);
+        let http = magnet.http_trackers();
+        assert_eq!(http.len(), 2);
+        assert_eq!(http[0].as_str(), "http://tracker.files.fm:6969/announce");
+        assert_eq!(http[1].as_str(), "http://tracker.openbittorrent.com/announce");
+    }
+
+    #[test]
+    fn test_not_a_magnet() {
+        let result = Magnet::from_link("http://example.com");
+        assert!(matches!(result, Err(MagnetError::NotMagnet)));
+    }
+
+    #[test]
+    fn test_malformed_parameter() {
+        let result = Magnet::from_link("magnet:?xt");
+        assert!(matches!(result, Err(MagnetError::MalformedParameter(_))));
+    }
+
+    #[test]
+    fn test_missing_exact_topic() {
+        let link = "magnet:?dn=test&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce";
+        let result = Magnet::from_link(link);
+        assert!(matches!(result, Err(MagnetError::MissingExactTopic)));
+    }
+
+    #[test]
+    fn test_truncated_hash() {
+        let link = "magnet:?xt=urn:btih:62B9305B&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce";
+        let result = Magnet::from_link(link);
+        assert!(matches!(result, Err(MagnetError::BadHashLength(8))));
+    }
+
+    #[test]
+    fn test_v2_length_hash() {
+        // A 32-byte SHA-256 hash where a 20-byte SHA-1 one belongs
+        let hash = "a".repeat(64);
+        let link = format!(
+            "magnet:?xt=urn:btih:{}&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce",
+            hash
+        );
+        let result = Magnet::from_link(&link);
+        assert!(matches!(result, Err(MagnetError::BadHashLength(64))));
+    }
+
+    #[test]
+    fn test_bad_hex() {
+        let link = "magnet:?xt=urn:btih:ZZB9305B850F2219B960929EC4CBD2E826004D73&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce";
+        let result = Magnet::from_link(link);
+        assert!(matches!(result, Err(MagnetError::BadHex(_))));
+    }
+
+    #[test]
+    fn test_no_trackers() {
+        let link = "magnet:?xt=urn:btih:62B9305B850F2219B960929EC4CBD2E826004D73&dn=test";
+        let result = Magnet::from_link(link);
+        assert!(matches!(result, Err(MagnetError::NoTrackers)));
+    }
+
+    #[test]
+    fn test_dual_hash_prefers_v1() {
+        let link = "magnet:?xt=urn:btmh:1220CAF1E1C30E81CB361B9EE167C4AA64228A7FA4FA9F6105232B28AD099F3A302E&xt=urn:btih:631A31DD0A46257D5078C0DEE4E66E26F73E42AC&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce";
+        let magnet = Magnet::from_link(link).unwrap();
+        let encoded = hex::encode(magnet.info_hash.bytes).to_uppercase();
+        assert_eq!(encoded, "631A31DD0A46257D5078C0DEE4E66E26F73E42AC");
+        assert_eq!(magnet.exact_topics.len(), 2);
+        assert!(magnet.exact_topics[0].starts_with("urn:btmh:"));
+    }
+
+    #[test]
+    fn test_parse_v2_hash() {
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&xt=urn:btmh:1220d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb&dn=bittorrent-v1-v2-hybrid-test&tr=udp%3A%2F%2Ftracker.example.org%3A1337";
+        let magnet = Magnet::from_link(link).unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash_v2.unwrap()),
+            "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb"
+        );
+        assert_eq!(
+            hex::encode(magnet.info_hash.bytes),
+            "631a31dd0a46257d5078c0dee4e66e26f73e42ac"
+        );
+
+        let v1_only = Magnet::from_link(&link.replacen("&xt=urn:btmh:", "&x=", 1)).unwrap();
+        assert_eq!(v1_only.info_hash_v2, None);
+        let mut rebuilt = magnet.clone();
+        rebuilt.exact_topics.clear();
+        let reparsed = Magnet::from_link(&rebuilt.to_link()).unwrap();
+        assert_eq!(reparsed.info_hash_v2, magnet.info_hash_v2);
+    }
+
+    #[test]
+    fn test_bad_multihash() {
+        let sha1 = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&xt=urn:btmh:1114631a31dd0a46257d5078c0dee4e66e26f73e42ac&tr=udp%3A%2F%2Ftracker.example.org%3A1337";
+        let result = Magnet::from_link(sha1);
+        assert!(matches!(result, Err(MagnetError::UnsupportedMultihash(0x11))));
+        let short = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&xt=urn:btmh:1220d8dd&tr=udp%3A%2F%2Ftracker.example.org%3A1337";
+        let result = Magnet::from_link(short);
+        assert!(matches!(result, Err(MagnetError::BadHashLength(8))));
+    }
+
+    #[test]
+    fn test_v2_only_is_missing_v1_topic() {
+        let link = "magnet:?xt=urn:btmh:1220CAF1E1C30E81CB361B9EE167C4AA64228A7FA4FA9F6105232B28AD099F3A302E&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce";
+        let result = Magnet::from_link(link);
+        assert!(matches!(result, Err(MagnetError::MissingExactTopic)));
+    }
+
+    #[test]
+    fn test_tracker_tiers() {
+        let link = "magnet:?xt=urn:btih:62B9305B850F2219B960929EC4CBD2E826004D73&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce&tr=http%3A%2F%2Ftracker.opentrackr.org%3A80%2Fannounce&tr=udp%3A%2F%2Fexplodie.org%3A6969%2Fannounce";
+        let magnet = Magnet::from_link(link).unwrap();
+        let tiers = magnet.tracker_tiers();
+        assert_eq!(tiers.len(), 3);
+        assert_eq!(tiers[0].len(), 2);
+        assert_eq!(tiers[0][0].as_str(), "udp://tracker.opentrackr.org:1337/announce");
+        assert_eq!(tiers[0][1].as_str(), "http://tracker.opentrackr.org/announce");
+        assert_eq!(tiers[1][0].host_str(), Some("open.stealth.si"));
+        assert_eq!(tiers[2][0].host_str(), Some("explodie.org"));
+    }
+
+    #[test]
+    fn test_skips_unsupported_and_duplicate_trackers() {
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&tr=wss%3A%2F%2Ftracker.btorrent.xyz&tr=udp%3Anohost&tr=udp%3A%2F%2FTracker.Example.org%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.example.org%3A1337%2Fother&tr=http%3A%2F%2Ftracker.example.org%2Fannounce&tr=http%3A%2F%2Ftracker.example.org%3A80%2Fannounce";
+        let magnet = Magnet::from_link(link).unwrap();
+        let trackers = magnet.trackers.iter().map(Url::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            trackers,
+            [
+                "udp://Tracker.Example.org:1337/announce",
+                "http://tracker.example.org/announce"
+            ]
+        );
+    }
+
+    fn selection_link(so: &str) -> String {
+        format!(
+            "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&tr=udp%3A%2F%2Ftracker.example.org%3A1337&so={}",
+            so
+        )
+    }
+
+    #[test]
+    fn test_select_single_file() {
+        let magnet = Magnet::from_link(&selection_link("0")).unwrap();
+        assert_eq!(magnet.selected_files, [FileSelection::Single(0)]);
+        assert!(magnet.is_file_selected(0));
+        assert!(!magnet.is_file_selected(1));
+    }
+
+    #[test]
+    fn test_select_file_range() {
+        let magnet = Magnet::from_link(&selection_link("0-2")).unwrap();
+        assert_eq!(magnet.selected_files, [FileSelection::Range(0, 2)]);
+        assert!(magnet.is_file_selected(2));
+        assert!(!magnet.is_file_selected(3));
+    }
+
+    #[test]
+    fn test_select_mixed_files() {
+        let magnet = Magnet::from_link(&selection_link("0-2,5,7-9")).unwrap();
+        assert_eq!(
+            magnet.selected_files,
+            [
+                FileSelection::Range(0, 2),
+                FileSelection::Single(5),
+                FileSelection::Range(7, 9)
+            ]
+        );
+        let selected = (0..11).filter(|i| magnet.is_file_selected(*i)).collect::<Vec<_>>();
+        assert_eq!(selected, [0, 1, 2, 5, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_no_selection_selects_everything() {
+        let magnet = Magnet::from_link(&selection_link("0").replace("&so=0", "")).unwrap();
+        assert!(magnet.selected_files.is_empty());
+        assert!(magnet.is_file_selected(42));
+    }
+
+    #[test]
+    fn test_malformed_selection() {
+        for so in ["", "a", "1-", "-1", "3-1", "1-2-3"] {
+            let result = Magnet::from_link(&selection_link(so));
+            assert!(matches!(result, Err(MagnetError::BadFileSelection(_))), "{}", so);
+        }
+    }
+
+    #[test]
+    fn test_parse_web_seeds() {
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&tr=udp%3A%2F%2Ftracker.example.org%3A1337&ws=https%3A%2F%2Fseed.example.org%2Ffiles%2F&as=http%3A%2F%2Fmirror.example.org%2Ffile.bin";
+        let magnet = Magnet::from_link(link).unwrap();
+        let seeds = magnet.web_seeds.iter().map(Url::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            seeds,
+            ["https://seed.example.org/files/", "http://mirror.example.org/file.bin"]
+        );
+        assert_eq!(magnet.trackers.len(), 1);
+        assert_eq!(magnet.trackers[0].host_str(), Some("tracker.example.org"));
+    }
+
+    #[test]
+    fn test_to_link_round_trip() {
+        let link = "magnet:?xt=urn:btih:62B9305B850F2219B960929EC4CBD2E826004D73&dn=Eminem+-+Curtain+Call+2+%28Explicit%29+%282022%29+Mp3+320kbps+%5BPMEDIA%5D+%E2%AD%90%EF%B8%8F&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce&tr=http%3A%2F%2Ftracker.files.fm%3A6969%2Fannounce&ws=https%3A%2F%2Fseed.example.org%2Ffiles%2F&so=0-2,5";
+        let magnet = Magnet::from_link(link).unwrap();
+        let serialized = magnet.to_link();
+        assert!(serialized.starts_with("magnet:?xt=urn:btih:62B9305B850F2219B960929EC4CBD2E826004D73&dn=Eminem%20-%20"));
+        assert_eq!(Magnet::from_link(&serialized).unwrap(), magnet);
+    }
+
+    #[test]
+    fn test_parse_keywords() {
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&kt=foo+bar&tr=udp%3A%2F%2Ftracker.example.org%3A1337";
+        let magnet = Magnet::from_link(link).unwrap();
+        assert_eq!(magnet.keywords, vec!["foo", "bar"]);
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&kt=c%2B%2B+caf%C3%A9&tr=udp%3A%2F%2Ftracker.example.org%3A1337";
+        let magnet = Magnet::from_link(link).unwrap();
+        assert_eq!(magnet.keywords, vec!["c++", "café"]);
+        assert_eq!(Magnet::from_link(&magnet.to_link()).unwrap(), magnet);
+    }
+
+    #[test]
+    fn test_magnet_equality() {
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&dn=test&tr=udp%3A%2F%2Ftracker.example.org%3A1337";
+        let magnet = Magnet::from_link(link).unwrap();
+        let mut copy = magnet.clone();
+        assert_eq!(copy, magnet);
+        copy.display_name.push('2');
+        assert_ne!(copy, magnet);
+        assert_eq!(Magnet::from_link(link).unwrap(), magnet);
+    }
+
+    #[test]
+    fn test_to_link_escapes_reserved_characters() {
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&dn=C%2B%2B+Primer%26Notes&tr=udp%3A%2F%2Ftracker.example.org%3A1337%2Fannounce%3Fkey%3Da%26b";
+        let magnet = Magnet::from_link(link).unwrap();
+        let reparsed = Magnet::from_link(&magnet.to_link()).unwrap();
+        assert_eq!(reparsed.display_name, "C++ Primer&Notes");
+        assert_eq!(
+            reparsed.trackers[0].as_str(),
+            "udp://tracker.example.org:1337/announce?key=a&b"
+        );
+    }
+
+    #[test]
+    fn test_parse_ipv4_initial_peer() {
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&tr=udp%3A%2F%2Ftracker.example.org%3A1337&x.pe=10.0.0.1%3A6881&x.pe=seed.example.org:51413";
+        let magnet = Magnet::from_link(link).unwrap();
+        assert_eq!(magnet.initial_peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(Magnet::from_link(&magnet.to_link()).unwrap(), magnet);
+    }
+
+    #[test]
+    fn test_parse_ipv6_initial_peer() {
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&tr=udp%3A%2F%2Ftracker.example.org%3A1337&x.pe=[2001:db8::1]:6881&x.pe=%5B2001%3Adb8%3A%3A2%5D%3A51413";
+        let magnet = Magnet::from_link(link).unwrap();
+        assert_eq!(
+            magnet.initial_peers,
+            vec![
+                "[2001:db8::1]:6881".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::2]:51413".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_initial_peer() {
+        for peer in ["10.0.0.1", "10.0.0.1:port", "2001:db8::1:6881", "[2001:db8::1]", ":6881"] {
+            let link = format!(
+                "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&tr=udp%3A%2F%2Ftracker.example.org%3A1337&x.pe={}",
+                peer
+            );
+            let result = Magnet::from_link(&link);
+            assert!(matches!(result, Err(MagnetError::BadPeerAddress(_))), "{}", peer);
+        }
+    }
+
+    #[test]
+    fn test_display_name_keeps_escaped_plus() {
+        let link = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&dn=C%2B%2B+Primer%26Notes&tr=udp%3A%2F%2Ftracker.example.org%3A1337";
+        let magnet = Magnet::from_link(link).unwrap();
+        assert_eq!(magnet.display_name, "C++ Primer&Notes");
+    }
 }
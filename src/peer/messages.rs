@@ -1,21 +1,73 @@
 use byteorder::{BigEndian, ByteOrder};
 
 
-pub trait PeerMessage {
+pub trait PeerMessage: Sized {
     fn to_bytes(&self) -> Vec<u8>;
-    fn from_bytes(bytes: &[u8]) -> Self;
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self>;
+}
+
+/// Length of a handshake excluding the protocol string.
+const HANDSHAKE_BASE_LEN: usize = 49;
+
+/// Reserved byte and mask advertising the extension protocol (BEP 10).
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+/// Last reserved byte and masks advertising the DHT (BEP 5) and the fast
+/// extension (BEP 6).
+const DHT_BYTE: usize = 7;
+const DHT_BIT: u8 = 0x01;
+const FAST_BIT: u8 = 0x04;
+
+/// Optional protocols a peer advertises in its handshake's reserved bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions {
+    pub dht: bool,
+    pub extension_protocol: bool,
+    pub fast: bool,
+}
+impl Extensions {
+    pub fn from_reserved(reserved: &[u8; 8]) -> Self {
+        Self {
+            dht: reserved[DHT_BYTE] & DHT_BIT != 0,
+            extension_protocol: reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0,
+            fast: reserved[DHT_BYTE] & FAST_BIT != 0,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct HandShake {
     pub pstr: Vec<u8>,
+    pub reserved: [u8; 8],
     pub info_hash: Vec<u8>,
     pub peer_id: Vec<u8>,
 }
+impl HandShake {
+    pub fn extensions(&self) -> Extensions {
+        Extensions::from_reserved(&self.reserved)
+    }
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
+    pub fn set_supports_fast(&mut self, supported: bool) {
+        if supported {
+            self.reserved[DHT_BYTE] |= FAST_BIT;
+        } else {
+            self.reserved[DHT_BYTE] &= !FAST_BIT;
+        }
+    }
+    pub fn set_supports_extensions(&mut self, supported: bool) {
+        if supported {
+            self.reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+        } else {
+            self.reserved[EXTENSION_PROTOCOL_BYTE] &= !EXTENSION_PROTOCOL_BIT;
+        }
+    }
+}
 impl PeerMessage for HandShake {
     fn to_bytes(&self) -> Vec<u8> {
         let pstrlen = self.pstr.len();
-        let size = 49 + pstrlen;
+        let size = HANDSHAKE_BASE_LEN + pstrlen;
         let mut bytes = vec![0u8; size];
         // pstrlen
         BigEndian::write_int(&mut bytes, pstrlen as i64, 1);
@@ -24,8 +76,7 @@ impl PeerMessage for HandShake {
         bytes[1..end_pstr].copy_from_slice(&self.pstr);
         // reserved
         let end_reserved = end_pstr + 8;
-        let reserved = vec![0u8; 8];
-        bytes[end_pstr..end_reserved].copy_from_slice(&reserved);
+        bytes[end_pstr..end_reserved].copy_from_slice(&self.reserved);
         // info hash
         let end_info_hash = end_reserved + 20;
         bytes[end_reserved..end_info_hash].copy_from_slice(&self.info_hash);
@@ -34,30 +85,58 @@ impl PeerMessage for HandShake {
         bytes[end_info_hash..end_peer_id].copy_from_slice(&self.peer_id);
         bytes
     }
-    fn from_bytes(bytes: &[u8]) -> Self {
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self::try_from(bytes)?)
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    #[error("Empty handshake")]
+    Empty,
+    #[error("Handshake too short ({actual} of {expected} bytes)")]
+    Truncated { expected: usize, actual: usize },
+}
+
+impl TryFrom<&[u8]> for HandShake {
+    type Error = HandshakeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
         // pstrlen
-        let pstrlen = BigEndian::read_int(bytes, 1) as usize;
+        let Some(&pstrlen) = bytes.first() else {
+            return Err(HandshakeError::Empty);
+        };
+        let pstrlen = pstrlen as usize;
+        if bytes.len() < HANDSHAKE_BASE_LEN + pstrlen {
+            return Err(HandshakeError::Truncated {
+                expected: HANDSHAKE_BASE_LEN + pstrlen,
+                actual: bytes.len(),
+            });
+        }
         let end_pstr = pstrlen + 1;
         // pstr
         let pstr = bytes[1..end_pstr].to_vec();
         // reserved
         let end_reserved = end_pstr + 8;
-        bytes[end_pstr..end_reserved].to_vec();
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&bytes[end_pstr..end_reserved]);
         // info hash
         let end_info_hash = end_reserved + 20;
         let info_hash = bytes[end_reserved..end_info_hash].to_vec();
         // peer id
         let end_peer_id = end_info_hash + 20;
         let peer_id = bytes[end_info_hash..end_peer_id].to_vec();
-        Self {
+        Ok(Self {
             pstr,
+            reserved,
             info_hash,
             peer_id,
-        }
+        })
     }
 }
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageTypes {
     Choke = 0,
     Unchoke = 1,
@@ -69,48 +148,101 @@ pub enum MessageTypes {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    // Fast extension (BEP 6), only valid once both peers advertised it
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
-impl From<u8> for MessageTypes {
-    fn from(value: u8) -> Self {
+impl MessageTypes {
+    /// Whether the message belongs to the fast extension (BEP 6).
+    pub fn is_fast(self) -> bool {
+        matches!(
+            self,
+            MessageTypes::SuggestPiece
+                | MessageTypes::HaveAll
+                | MessageTypes::HaveNone
+                | MessageTypes::RejectRequest
+                | MessageTypes::AllowedFast
+        )
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("Unknown message id {0}")]
+pub struct UnknownMessage(pub u8);
+
+impl TryFrom<u8> for MessageTypes {
+    type Error = UnknownMessage;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => MessageTypes::Unchoke,
-            2 => MessageTypes::Interested,
-            3 => MessageTypes::NotInterested,
-            4 => MessageTypes::Have,
-            5 => MessageTypes::Bitfield,
-            6 => MessageTypes::Request,
-            7 => MessageTypes::Piece,
-            8 => MessageTypes::Cancel,
-            9 => MessageTypes::Port,
-            _ => panic!("Invalid value for message type"),
+            0 => Ok(MessageTypes::Choke),
+            1 => Ok(MessageTypes::Unchoke),
+            2 => Ok(MessageTypes::Interested),
+            3 => Ok(MessageTypes::NotInterested),
+            4 => Ok(MessageTypes::Have),
+            5 => Ok(MessageTypes::Bitfield),
+            6 => Ok(MessageTypes::Request),
+            7 => Ok(MessageTypes::Piece),
+            8 => Ok(MessageTypes::Cancel),
+            9 => Ok(MessageTypes::Port),
+            13 => Ok(MessageTypes::SuggestPiece),
+            14 => Ok(MessageTypes::HaveAll),
+            15 => Ok(MessageTypes::HaveNone),
+            16 => Ok(MessageTypes::RejectRequest),
+            17 => Ok(MessageTypes::AllowedFast),
+            20 => Ok(MessageTypes::Extended),
+            _ => Err(UnknownMessage(value)),
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
 pub struct RawMessage {
     pub message_id: u8,
     pub payload: Vec<u8>,
+    /// Set for keep-alives, which are framed with a zero length prefix and
+    /// carry no id. Without it they would look like a Choke.
+    pub keep_alive: bool,
+}
+impl RawMessage {
+    pub fn keep_alive() -> Self {
+        Self {
+            message_id: 0,
+            payload: Vec::new(),
+            keep_alive: true,
+        }
+    }
+    /// The message's type, or an error for ids we don't know. Peers are
+    /// untrusted, so callers should branch on this rather than assume.
+    pub fn message_type(&self) -> Result<MessageTypes, UnknownMessage> {
+        MessageTypes::try_from(self.message_id)
+    }
 }
 impl From<&[u8]> for RawMessage {
     fn from(bytes: &[u8]) -> Self {
-        if bytes.len() == 0 {
-            return Self {
-                message_id: 0,
-                payload: Vec::new(),
-            };
-        }
-        let payload_length = bytes.len() - 1 as usize;
-        let message_id = BigEndian::read_int(&bytes, 1) as u8;
+        if bytes.is_empty() {
+            return Self::keep_alive();
+        }
+        let payload_length = bytes.len() - 1;
+        let message_id = BigEndian::read_int(bytes, 1) as u8;
         let mut payload = vec![0u8; payload_length];
         payload.copy_from_slice(&bytes[1..]);
         Self {
             message_id,
             payload,
+            keep_alive: false,
         }
     }
 }
 impl From<RawMessage> for Vec<u8> {
     fn from(raw_message: RawMessage) -> Self {
+        if raw_message.keep_alive {
+            return Vec::new();
+        }
         let mut bytes = vec![0u8; raw_message.payload.len() + 1];
         bytes[0] = raw_message.message_id;
         bytes[1..].copy_from_slice(&raw_message.payload);
@@ -118,6 +250,274 @@ impl From<RawMessage> for Vec<u8> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Port(u16),
+    SuggestPiece(u32),
+    /// Shorthand for a bitfield with every piece set.
+    HaveAll,
+    /// Shorthand for an empty bitfield.
+    HaveNone,
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    AllowedFast(u32),
+    Extended { id: u8, payload: Vec<u8> },
+}
+impl Message {
+    /// Whether the message belongs to the fast extension (BEP 6).
+    pub fn is_fast(&self) -> bool {
+        matches!(
+            self,
+            Message::SuggestPiece(_)
+                | Message::HaveAll
+                | Message::HaveNone
+                | Message::RejectRequest { .. }
+                | Message::AllowedFast(_)
+        )
+    }
+    /// Decodes a typed message from a raw one.
+    pub fn from_raw(raw: RawMessage) -> anyhow::Result<Message> {
+        if raw.keep_alive {
+            return Ok(Message::KeepAlive);
+        }
+        let message_type = raw.message_type()?;
+        let payload = raw.payload;
+        let expect_len = |len: usize| {
+            if payload.len() != len {
+                anyhow::bail!(
+                    "Invalid payload length {} for {:?} message",
+                    payload.len(),
+                    message_type
+                );
+            }
+            Ok(())
+        };
+        let message = match message_type {
+            MessageTypes::Choke => {
+                expect_len(0)?;
+                Message::Choke
+            }
+            MessageTypes::Unchoke => {
+                expect_len(0)?;
+                Message::Unchoke
+            }
+            MessageTypes::Interested => {
+                expect_len(0)?;
+                Message::Interested
+            }
+            MessageTypes::NotInterested => {
+                expect_len(0)?;
+                Message::NotInterested
+            }
+            MessageTypes::Have => {
+                expect_len(4)?;
+                Message::Have(BigEndian::read_u32(&payload))
+            }
+            MessageTypes::Bitfield => Message::Bitfield(payload),
+            MessageTypes::Request => {
+                expect_len(12)?;
+                Message::Request {
+                    index: BigEndian::read_u32(&payload[0..4]),
+                    begin: BigEndian::read_u32(&payload[4..8]),
+                    length: BigEndian::read_u32(&payload[8..12]),
+                }
+            }
+            MessageTypes::Piece => {
+                if payload.len() < 8 {
+                    anyhow::bail!("Piece message too short ({} bytes)", payload.len());
+                }
+                Message::Piece {
+                    index: BigEndian::read_u32(&payload[0..4]),
+                    begin: BigEndian::read_u32(&payload[4..8]),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            MessageTypes::Cancel => {
+                expect_len(12)?;
+                Message::Cancel {
+                    index: BigEndian::read_u32(&payload[0..4]),
+                    begin: BigEndian::read_u32(&payload[4..8]),
+                    length: BigEndian::read_u32(&payload[8..12]),
+                }
+            }
+            MessageTypes::Port => {
+                expect_len(2)?;
+                Message::Port(BigEndian::read_u16(&payload))
+            }
+            MessageTypes::SuggestPiece => {
+                expect_len(4)?;
+                Message::SuggestPiece(BigEndian::read_u32(&payload))
+            }
+            MessageTypes::HaveAll => {
+                expect_len(0)?;
+                Message::HaveAll
+            }
+            MessageTypes::HaveNone => {
+                expect_len(0)?;
+                Message::HaveNone
+            }
+            MessageTypes::RejectRequest => {
+                expect_len(12)?;
+                Message::RejectRequest {
+                    index: BigEndian::read_u32(&payload[0..4]),
+                    begin: BigEndian::read_u32(&payload[4..8]),
+                    length: BigEndian::read_u32(&payload[8..12]),
+                }
+            }
+            MessageTypes::AllowedFast => {
+                expect_len(4)?;
+                Message::AllowedFast(BigEndian::read_u32(&payload))
+            }
+            MessageTypes::Extended => {
+                let Some((&id, payload)) = payload.split_first() else {
+                    anyhow::bail!("Extended message missing extension id");
+                };
+                Message::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                }
+            }
+        };
+        Ok(message)
+    }
+    pub fn to_raw(&self) -> RawMessage {
+        let (message_type, payload) = match self {
+            Message::KeepAlive => return RawMessage::keep_alive(),
+            Message::Choke => (MessageTypes::Choke, Vec::new()),
+            Message::Unchoke => (MessageTypes::Unchoke, Vec::new()),
+            Message::Interested => (MessageTypes::Interested, Vec::new()),
+            Message::NotInterested => (MessageTypes::NotInterested, Vec::new()),
+            Message::Have(index) => (MessageTypes::Have, index.to_be_bytes().to_vec()),
+            Message::Bitfield(bitfield) => (MessageTypes::Bitfield, bitfield.clone()),
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => (MessageTypes::Request, block_triple(*index, *begin, *length)),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let mut payload = vec![0u8; 8 + block.len()];
+                BigEndian::write_u32(&mut payload[0..4], *index);
+                BigEndian::write_u32(&mut payload[4..8], *begin);
+                payload[8..].copy_from_slice(block);
+                (MessageTypes::Piece, payload)
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => (MessageTypes::Cancel, block_triple(*index, *begin, *length)),
+            Message::Port(port) => (MessageTypes::Port, port.to_be_bytes().to_vec()),
+            Message::SuggestPiece(index) => {
+                (MessageTypes::SuggestPiece, index.to_be_bytes().to_vec())
+            }
+            Message::HaveAll => (MessageTypes::HaveAll, Vec::new()),
+            Message::HaveNone => (MessageTypes::HaveNone, Vec::new()),
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => (MessageTypes::RejectRequest, block_triple(*index, *begin, *length)),
+            Message::AllowedFast(index) => {
+                (MessageTypes::AllowedFast, index.to_be_bytes().to_vec())
+            }
+            Message::Extended { id, payload } => {
+                let mut bytes = vec![*id];
+                bytes.extend_from_slice(payload);
+                (MessageTypes::Extended, bytes)
+            }
+        };
+        RawMessage {
+            message_id: message_type as u8,
+            payload,
+            keep_alive: false,
+        }
+    }
+}
+
+/// Pieces a peer has, one bit per piece with the high bit of the first byte
+/// representing piece 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    num_pieces: usize,
+}
+impl Bitfield {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            bytes: vec![0u8; num_pieces.div_ceil(8)],
+            num_pieces,
+        }
+    }
+    /// A bitfield with all `num_pieces` set, as implied by HaveAll.
+    pub fn full(num_pieces: usize) -> Self {
+        let mut bitfield = Self::new(num_pieces);
+        (0..num_pieces).for_each(|index| bitfield.set(index));
+        bitfield
+    }
+    /// Validates a bitfield received from a peer: it must be exactly large
+    /// enough for `num_pieces` and the spare trailing bits must be clear.
+    pub fn from_bytes(bytes: Vec<u8>, num_pieces: usize) -> anyhow::Result<Self> {
+        if bytes.len() != num_pieces.div_ceil(8) {
+            anyhow::bail!(
+                "Bitfield of {} bytes does not match {} pieces",
+                bytes.len(),
+                num_pieces
+            );
+        }
+        let spare_bits = bytes.len() * 8 - num_pieces;
+        if let Some(last) = bytes.last() {
+            let spare_mask = ((1u16 << spare_bits) - 1) as u8;
+            if last & spare_mask != 0 {
+                anyhow::bail!("Bitfield has spare bits set");
+            }
+        }
+        Ok(Self { bytes, num_pieces })
+    }
+    pub fn len(&self) -> usize {
+        self.num_pieces
+    }
+    pub fn is_empty(&self) -> bool {
+        self.num_pieces == 0
+    }
+    pub fn has(&self, index: usize) -> bool {
+        index < self.num_pieces && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+    pub fn set(&mut self, index: usize) {
+        if index < self.num_pieces {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_pieces).filter(|index| self.has(*index))
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+fn block_triple(index: u32, begin: u32, length: u32) -> Vec<u8> {
+    let mut payload = vec![0u8; 12];
+    BigEndian::write_u32(&mut payload[0..4], index);
+    BigEndian::write_u32(&mut payload[4..8], begin);
+    BigEndian::write_u32(&mut payload[8..12], length);
+    payload
+}
 
 #[cfg(test)]
 mod tests {
@@ -132,11 +532,34 @@ mod tests {
         assert_eq!(raw_message.payload, expected_payload);
     }
 
+    #[test]
+    fn test_port_message_round_trip() {
+        let raw = Message::Port(6881).to_raw();
+        assert_eq!(raw.message_id, 9);
+        assert_eq!(raw.payload, vec![0x1A, 0xE1]);
+        assert_eq!(Message::from_raw(raw).unwrap(), Message::Port(6881));
+        let truncated = RawMessage {
+            message_id: 9,
+            payload: vec![0x1A],
+            keep_alive: false,
+        };
+        assert!(Message::from_raw(truncated).is_err());
+    }
+
+    #[test]
+    fn test_raw_message_type() {
+        let raw_message = RawMessage::from(&[7u8, 0, 0, 0, 1][..]);
+        assert_eq!(raw_message.message_type(), Ok(MessageTypes::Piece));
+        let raw_message = RawMessage::from(&[42u8, 1, 2][..]);
+        assert_eq!(raw_message.message_type(), Err(UnknownMessage(42)));
+    }
+
     #[test]
     fn test_raw_message_into_bytes() {
         let raw_message = RawMessage {
             message_id: 5,
             payload: vec![1,2,3,4,5],
+            keep_alive: false,
         };
         let expected_bytes: Vec<u8> = vec![5, 1, 2, 3, 4, 5];
         let bytes: Vec<u8> = raw_message.into();
@@ -150,6 +573,19 @@ mod tests {
         assert_eq!(raw_message.message_id, 0);
         let expected_payload: Vec<u8> = vec![];
         assert_eq!(raw_message.payload, expected_payload);
+        assert!(raw_message.keep_alive);
+        assert_eq!(Message::from_raw(raw_message).unwrap(), Message::KeepAlive);
+    }
+
+    #[test]
+    fn test_keep_alive_is_not_choke() {
+        let choke = RawMessage::from(&[0u8][..]);
+        assert!(!choke.keep_alive);
+        assert_eq!(Message::from_raw(choke).unwrap(), Message::Choke);
+        let bytes: Vec<u8> = Message::KeepAlive.to_raw().into();
+        assert!(bytes.is_empty());
+        let bytes: Vec<u8> = Message::Choke.to_raw().into();
+        assert_eq!(bytes, vec![0]);
     }
 
     #[test]
@@ -157,6 +593,7 @@ mod tests {
         let raw_message = RawMessage {
             message_id: 0,
             payload: vec![],
+            keep_alive: false,
         };
         let expected_bytes: Vec<u8> = vec![0];
         let bytes: Vec<u8> = raw_message.into();
@@ -173,14 +610,259 @@ mod tests {
         peer_id.copy_from_slice("abcdefghijklmnopijll".as_bytes());
         let handshake = HandShake {
             pstr,
+            reserved: [0, 0, 0, 0, 0, 0x10, 0, 0x05],
             info_hash,
             peer_id,
         };
 
         let bytes: Vec<u8> = handshake.to_bytes();
-        let new_handshake = HandShake::from_bytes(&bytes);
+        let new_handshake = HandShake::from_bytes(&bytes).unwrap();
         assert_eq!(handshake, new_handshake);
     }
-    
 
+    #[test]
+    fn test_extensions_from_reserved() {
+        let extensions = Extensions::from_reserved(&[0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert_eq!(
+            extensions,
+            Extensions {
+                dht: true,
+                extension_protocol: true,
+                fast: false,
+            }
+        );
+        assert_eq!(Extensions::from_reserved(&[0u8; 8]), Extensions::default());
+        assert!(Extensions::from_reserved(&[0, 0, 0, 0, 0, 0, 0, 0x04]).fast);
+    }
+
+    #[test]
+    fn test_handshake_try_from() {
+        let handshake = HandShake {
+            pstr: b"BitTorrent protocol".to_vec(),
+            reserved: [0u8; 8],
+            info_hash: vec![1u8; 20],
+            peer_id: vec![2u8; 20],
+        };
+        let bytes = handshake.to_bytes();
+        let parsed: HandShake = bytes[..].try_into().unwrap();
+        assert_eq!(parsed, handshake);
+        assert_eq!(
+            HandShake::try_from(&bytes[..60]),
+            Err(HandshakeError::Truncated { expected: 68, actual: 60 })
+        );
+        assert_eq!(HandShake::try_from(&[][..]), Err(HandshakeError::Empty));
+        // A pstrlen pointing past the end of the buffer
+        let mut overrun = bytes.clone();
+        overrun[0] = 255;
+        assert_eq!(
+            HandShake::try_from(&overrun[..]),
+            Err(HandshakeError::Truncated { expected: 304, actual: 68 })
+        );
+    }
+
+    #[test]
+    fn test_handshake_from_short_bytes() {
+        assert!(HandShake::from_bytes(&[]).is_err());
+        assert!(HandShake::from_bytes(&[19]).is_err());
+        let mut bytes = vec![0u8; 67];
+        bytes[0] = 19;
+        assert!(HandShake::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_message_types_try_from() {
+        let expected = [
+            MessageTypes::Choke,
+            MessageTypes::Unchoke,
+            MessageTypes::Interested,
+            MessageTypes::NotInterested,
+            MessageTypes::Have,
+            MessageTypes::Bitfield,
+            MessageTypes::Request,
+            MessageTypes::Piece,
+            MessageTypes::Cancel,
+            MessageTypes::Port,
+        ];
+        for (id, message_type) in expected.iter().enumerate() {
+            assert_eq!(MessageTypes::try_from(id as u8), Ok(*message_type));
+            assert_eq!(*message_type as u8, id as u8);
+        }
+    }
+
+    #[test]
+    fn test_message_types_try_from_unknown() {
+        assert_eq!(MessageTypes::try_from(10), Err(UnknownMessage(10)));
+        assert_eq!(MessageTypes::try_from(18), Err(UnknownMessage(18)));
+        assert_eq!(MessageTypes::try_from(20), Ok(MessageTypes::Extended));
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let messages = vec![
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(3),
+            Message::Bitfield(vec![0b1010_0000, 0xFF]),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 1,
+                begin: 16384,
+                block: vec![1, 2, 3, 4],
+            },
+            Message::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Port(6881),
+            Message::Extended {
+                id: 0,
+                payload: b"de".to_vec(),
+            },
+        ];
+        for message in messages {
+            let raw = message.to_raw();
+            assert_eq!(Message::from_raw(raw).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_fast_message_round_trip() {
+        let messages = [
+            (Message::SuggestPiece(7), 13, vec![0, 0, 0, 7]),
+            (Message::HaveAll, 14, vec![]),
+            (Message::HaveNone, 15, vec![]),
+            (
+                Message::RejectRequest {
+                    index: 1,
+                    begin: 2,
+                    length: 3,
+                },
+                16,
+                vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3],
+            ),
+            (Message::AllowedFast(258), 17, vec![0, 0, 1, 2]),
+        ];
+        for (message, id, payload) in messages {
+            assert!(message.is_fast());
+            let raw = message.to_raw();
+            assert_eq!(raw.message_id, id);
+            assert_eq!(raw.payload, payload);
+            assert!(raw.message_type().unwrap().is_fast());
+            assert_eq!(Message::from_raw(raw).unwrap(), message);
+        }
+        assert!(!Message::Have(1).is_fast());
+        let raw = RawMessage {
+            message_id: 14,
+            payload: vec![0],
+            keep_alive: false,
+        };
+        assert!(Message::from_raw(raw).is_err());
+    }
+
+    #[test]
+    fn test_message_to_raw_layout() {
+        let raw = Message::Request {
+            index: 1,
+            begin: 2,
+            length: 3,
+        }
+        .to_raw();
+        assert_eq!(raw.message_id, 6);
+        assert_eq!(raw.payload, vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+        let raw = Message::Have(258).to_raw();
+        assert_eq!(raw.message_id, 4);
+        assert_eq!(raw.payload, vec![0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_message_from_raw_bad_payload() {
+        let raw = RawMessage {
+            message_id: 4,
+            payload: vec![0, 0, 1],
+            keep_alive: false,
+        };
+        assert!(Message::from_raw(raw).is_err());
+        let raw = RawMessage {
+            message_id: 7,
+            payload: vec![0, 0, 0, 1],
+            keep_alive: false,
+        };
+        assert!(Message::from_raw(raw).is_err());
+        let raw = RawMessage {
+            message_id: 42,
+            payload: vec![],
+            keep_alive: false,
+        };
+        assert!(Message::from_raw(raw).is_err());
+    }
+
+    #[test]
+    fn test_bitfield_set_and_has() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes().len(), 2);
+        bitfield.set(0);
+        bitfield.set(7);
+        bitfield.set(8);
+        bitfield.set(9);
+        assert!(bitfield.has(0));
+        assert!(!bitfield.has(1));
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0001, 0b1100_0000]);
+        assert_eq!(bitfield.count_ones(), 4);
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![0, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_bitfield_set_out_of_range() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(10);
+        bitfield.set(15);
+        assert_eq!(bitfield.count_ones(), 0);
+    }
+
+    #[test]
+    fn test_bitfield_full() {
+        let bitfield = Bitfield::full(10);
+        assert_eq!(bitfield.as_bytes(), &[0xFF, 0b1100_0000]);
+        assert_eq!(bitfield.count_ones(), 10);
+    }
+
+    #[test]
+    fn test_bitfield_from_bytes() {
+        let bitfield = Bitfield::from_bytes(vec![0xFF, 0b1100_0000], 10).unwrap();
+        assert_eq!(bitfield.count_ones(), 10);
+        assert!(Bitfield::from_bytes(vec![0xFF, 0b1110_0000], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xFF], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xFF, 0xFF], 16).is_ok());
+    }
+
+    #[test]
+    fn test_handshake_extension_bit() {
+        let mut handshake = HandShake {
+            pstr: "BitTorrent protocol".as_bytes().to_vec(),
+            reserved: [0u8; 8],
+            info_hash: vec![1u8; 20],
+            peer_id: vec![2u8; 20],
+        };
+        assert!(!handshake.supports_extensions());
+        handshake.set_supports_extensions(true);
+        let bytes = handshake.to_bytes();
+        assert_eq!(&bytes[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0]);
+        let parsed = HandShake::from_bytes(&bytes).unwrap();
+        assert!(parsed.supports_extensions());
+        handshake.set_supports_extensions(false);
+        assert_eq!(handshake.reserved, [0u8; 8]);
+        handshake.set_supports_fast(true);
+        assert!(handshake.extensions().fast);
+        handshake.set_supports_fast(false);
+        assert_eq!(handshake.reserved, [0u8; 8]);
+    }
 }
@@ -1,4 +1,17 @@
 pub mod messages;
 pub mod peer_stream;
 pub mod tracker_stream;
+pub mod http_tracker;
+pub mod dht;
 pub mod magnet;
+pub mod bencode;
+pub mod extension;
+pub mod torrent;
+pub mod storage;
+pub mod piece;
+pub mod download;
+pub mod verify;
+pub mod stats;
+pub mod choke;
+pub mod seed;
+pub mod pool;
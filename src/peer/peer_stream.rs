@@ -1,25 +1,297 @@
 use async_std::prelude::*;
 use async_std::{
+    future,
     io::{Read, Write},
-    net::TcpStream,
-};
-use std::{
-    cmp::{max, min},
-    net::{SocketAddr, ToSocketAddrs},
-    pin::Pin,
-    task::Poll,
-    time::Duration,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    task,
 };
+use futures::{stream, Stream, StreamExt};
+use rand::Rng;
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
 
-use crate::peer::messages::{HandShake, PeerMessage, RawMessage};
+use crate::peer::extension::{
+    ExtendedHandshake, MetadataMessage, EXTENDED_HANDSHAKE_ID, MAX_METADATA_SIZE,
+    METADATA_PIECE_LEN, UT_METADATA_ID, PexMessage, UT_PEX_ID,
+};
+use crate::peer::messages::{
+    Bitfield, Extensions, HandShake, HandshakeError, Message, MessageTypes, PeerMessage, RawMessage,
+};
+use crate::peer::verify::verify_info_hash;
 use anyhow::Context;
 use byteorder::{BigEndian, ByteOrder};
 
+/// Choke and interest flags for both sides of a connection. Connections
+/// start out choked and not interested in either direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+impl Default for ConnectionState {
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+impl ConnectionState {
+    pub fn on_received(&mut self, message: &Message) {
+        match message {
+            Message::Choke => self.peer_choking = true,
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+    }
+    pub fn on_sent(&mut self, message: &Message) {
+        match message {
+            Message::Choke => self.am_choking = true,
+            Message::Unchoke => self.am_choking = false,
+            Message::Interested => self.am_interested = true,
+            Message::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+    }
+    pub fn can_request(&self) -> bool {
+        !self.peer_choking && self.am_interested
+    }
+}
+
 pub struct PeerConnection {
     stream: PeerStream,
+    state: ConnectionState,
+    dht_port: Option<u16>,
+    // Peers learned through ut_pex and not yet handed out
+    pex_peers: Vec<SocketAddr>,
+    // Messages read by `prepare_download`, replayed by `read`
+    pending: VecDeque<Message>,
+    // Pieces the peer has, tracked once the piece count is known
+    have: Option<Bitfield>,
+    // Blocks requested and neither received nor cancelled, as
+    // (index, begin, length)
+    outstanding: VecDeque<(u32, u32, u32)>,
 }
 impl PeerConnection {
-
+    pub fn new(stream: PeerStream) -> Self {
+        Self {
+            stream,
+            state: ConnectionState::default(),
+            dht_port: None,
+            pex_peers: Vec::new(),
+            pending: VecDeque::new(),
+            have: None,
+            outstanding: VecDeque::new(),
+        }
+    }
+    pub fn addr(&self) -> SocketAddr {
+        self.stream.addr()
+    }
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+    pub fn am_choking(&self) -> bool {
+        self.state.am_choking
+    }
+    pub fn am_interested(&self) -> bool {
+        self.state.am_interested
+    }
+    pub fn peer_choking(&self) -> bool {
+        self.state.peer_choking
+    }
+    pub fn peer_interested(&self) -> bool {
+        self.state.peer_interested
+    }
+    /// The optional protocols the peer advertised in its handshake, to
+    /// decide which of them to use with it.
+    pub fn extensions(&self) -> Extensions {
+        self.stream.extensions()
+    }
+    /// Whether both sides advertised the fast extension (BEP 6).
+    pub fn fast_enabled(&self) -> bool {
+        self.stream.fast_enabled()
+    }
+    /// The DHT node port the peer advertised with a Port message, if any.
+    pub fn dht_port(&self) -> Option<u16> {
+        self.dht_port
+    }
+    /// Starts tracking which of `num_pieces` pieces the peer has from its
+    /// Bitfield and Have messages. Until then they are passed on unchecked.
+    pub fn set_piece_count(&mut self, num_pieces: usize) {
+        if self.have.as_ref().map(Bitfield::len) != Some(num_pieces) {
+            self.have = Some(Bitfield::new(num_pieces));
+        }
+    }
+    /// The pieces the peer has announced, once `set_piece_count` was called.
+    pub fn bitfield(&self) -> Option<&Bitfield> {
+        self.have.as_ref()
+    }
+    /// How many block requests to keep in flight, from
+    /// [`PeerStreamOpts::pipeline_depth`].
+    pub fn pipeline_depth(&self) -> usize {
+        self.stream.pipeline_depth
+    }
+    /// Requests sent that the peer has neither answered nor had cancelled.
+    pub fn outstanding_requests(&self) -> impl Iterator<Item = Message> + '_ {
+        self.outstanding
+            .iter()
+            .map(|&(index, begin, length)| Message::Request { index, begin, length })
+    }
+    /// Whether another request fits in the pipeline.
+    pub fn can_pipeline(&self) -> bool {
+        self.state.can_request() && self.outstanding.len() < self.pipeline_depth()
+    }
+    /// Takes the peers this connection has learned about through ut_pex
+    /// (BEP 11) since the last call.
+    pub fn take_pex_peers(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.pex_peers)
+    }
+    /// Waits until a message is buffered or the socket is readable.
+    /// See [`PeerStream::readable`].
+    pub async fn readable(&self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            return Ok(());
+        }
+        self.stream.readable().await
+    }
+    /// Reads the next message we understand, updating the connection state.
+    /// Messages with ids we don't support are skipped.
+    pub async fn read(&mut self) -> anyhow::Result<Message> {
+        // Already applied to the state when first read, but the piece count
+        // may only have become known since
+        if let Some(message) = self.pending.pop_front() {
+            self.record_availability(&message)?;
+            return Ok(message);
+        }
+        loop {
+            let raw = self.stream.read().await?;
+            if raw.message_type().is_err() {
+                continue;
+            }
+            let message = Message::from_raw(raw)?;
+            if message.is_fast() && !self.stream.fast_enabled() {
+                return Err(PeerError::FastNotNegotiated)?;
+            }
+            self.state.on_received(&message);
+            self.record_availability(&message)?;
+            match &message {
+                Message::Port(port) => self.dht_port = Some(*port),
+                Message::Piece { index, begin, block } => {
+                    self.forget_request(*index, *begin, block.len() as u32)
+                }
+                // Peers drop all pending requests when choking, unless the
+                // fast extension obliges them to reject each explicitly
+                Message::Choke if !self.stream.fast_enabled() => self.outstanding.clear(),
+                Message::RejectRequest { index, begin, length } => {
+                    self.forget_request(*index, *begin, *length)
+                }
+                Message::Extended { id: UT_PEX_ID, payload } => self.add_pex_peers(payload),
+                _ => {}
+            }
+            return Ok(message);
+        }
+    }
+    pub async fn fetch_metadata(&mut self, info_hash: &[u8; 20]) -> anyhow::Result<Vec<u8>> {
+        self.stream.fetch_metadata(info_hash).await
+    }
+    /// Declares interest and waits up to `UNCHOKE_TIMEOUT` for the peer to
+    /// unchoke us, after which blocks may be requested. Messages that arrive
+    /// meanwhile, typically the peer's bitfield, are still returned by
+    /// later calls to `read`.
+    pub async fn prepare_download(&mut self) -> anyhow::Result<()> {
+        self.prepare_download_within(UNCHOKE_TIMEOUT).await
+    }
+    async fn prepare_download_within(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        if !self.state.am_interested {
+            self.send(Message::Interested).await?;
+        }
+        let mut received = VecDeque::new();
+        let unchoked = future::timeout(timeout, async {
+            while self.state.peer_choking {
+                received.push_back(self.read().await?);
+            }
+            anyhow::Ok(())
+        })
+        .await;
+        received.extend(self.pending.drain(..));
+        self.pending = received;
+        unchoked.map_err(|_| PeerError::UnchokeTimeout)?
+    }
+    fn record_availability(&mut self, message: &Message) -> anyhow::Result<()> {
+        let Some(have) = self.have.as_mut() else {
+            return Ok(());
+        };
+        match message {
+            Message::Bitfield(bytes) => *have = Bitfield::from_bytes(bytes.clone(), have.len())?,
+            Message::HaveAll => *have = Bitfield::full(have.len()),
+            Message::HaveNone => *have = Bitfield::new(have.len()),
+            Message::Have(index) => {
+                if *index as usize >= have.len() {
+                    return Err(PeerError::InvalidPieceIndex(*index))?;
+                }
+                have.set(*index as usize);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    fn add_pex_peers(&mut self, payload: &[u8]) {
+        match PexMessage::from_bytes(payload) {
+            Ok(pex) => {
+                for peer in pex.added {
+                    if !self.pex_peers.contains(&peer) {
+                        self.pex_peers.push(peer);
+                    }
+                }
+                self.pex_peers.retain(|peer| !pex.dropped.contains(peer));
+            }
+            Err(e) => log::debug!("Ignoring malformed ut_pex message: {}", e),
+        }
+    }
+    pub async fn send(&mut self, message: Message) -> anyhow::Result<()> {
+        if matches!(message, Message::Request { .. }) && !self.state.can_request() {
+            return Err(PeerError::RequestWhileChoked)?;
+        }
+        if message.is_fast() && !self.stream.fast_enabled() {
+            return Err(PeerError::FastNotNegotiated)?;
+        }
+        self.stream.write(message.to_raw()).await?;
+        self.state.on_sent(&message);
+        match message {
+            Message::Request { index, begin, length } => {
+                self.outstanding.push_back((index, begin, length))
+            }
+            Message::Cancel { index, begin, length } => self.forget_request(index, begin, length),
+            _ => {}
+        }
+        Ok(())
+    }
+    /// Chokes the peer, telling it we won't serve its requests. Does
+    /// nothing if it is already choked.
+    pub async fn choke(&mut self) -> anyhow::Result<()> {
+        if self.state.am_choking {
+            return Ok(());
+        }
+        self.send(Message::Choke).await
+    }
+    /// Unchokes the peer so it may request blocks from us. Does nothing if
+    /// it is already unchoked.
+    pub async fn unchoke(&mut self) -> anyhow::Result<()> {
+        if !self.state.am_choking {
+            return Ok(());
+        }
+        self.send(Message::Unchoke).await
+    }
+    fn forget_request(&mut self, index: u32, begin: u32, length: u32) {
+        if let Some(position) = self.outstanding.iter().position(|r| *r == (index, begin, length)) {
+            self.outstanding.remove(position);
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -28,42 +300,428 @@ pub enum PeerError {
     BadProtocol,
     #[error("Peer info hash mismatch")]
     BadInfoHash,
+    #[error("Peer id mismatch")]
+    BadPeerId,
+    #[error("Malformed peer handshake: {0}")]
+    BadHandshake(#[from] HandshakeError),
+    #[error("Peer address {0} resolved to no addresses")]
+    NoAddresses(String),
+    #[error("Peer message length {0} exceeds maximum")]
+    MessageTooLarge(usize),
+    #[error("Timed out connecting to peer")]
+    ConnectTimeout,
+    #[error("Timed out reading a message from peer")]
+    ReadTimeout,
+    #[error("Peer did not unchoke us in time")]
+    UnchokeTimeout,
+    #[error("Peer referenced nonexistent piece {0}")]
+    InvalidPieceIndex(u32),
+    #[error("Fast extension message without fast extension support")]
+    FastNotNegotiated,
+    #[error("Cannot request blocks while choked or uninterested")]
+    RequestWhileChoked,
+    #[error("Peer does not support the extension protocol")]
+    ExtensionProtocolUnsupported,
+    #[error("Peer does not support ut_metadata")]
+    MetadataUnsupported,
+    #[error("Invalid metadata size {0}")]
+    InvalidMetadataSize(usize),
+    #[error("Metadata piece {piece} has wrong length {length}")]
+    MetadataPieceLength { piece: usize, length: usize },
+    #[error("Peer rejected metadata piece {0}")]
+    MetadataRejected(usize),
+    #[error("Metadata does not match info hash")]
+    MetadataHashMismatch,
 }
-struct PeerStreamOpts {
-    protocol: Vec<u8>,
-    info_hash: Vec<u8>,
-    peer_id: Vec<u8>,
+
+/// Largest message a peer may send. Blocks are at most 16KiB plus a small
+/// header, so anything bigger is treated as hostile.
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a started message may take to arrive in full.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long `PeerConnection::prepare_download` waits for an unchoke.
+pub const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Block requests kept in flight per peer unless configured otherwise.
+pub const DEFAULT_PIPELINE_DEPTH: usize = 5;
+pub const BITTORRENT_PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// Two-letter client code in our Azureus-style peer ids.
+pub const CLIENT_CODE: &[u8; 2] = b"WM";
+pub const CLIENT_VERSION: &[u8; 4] = b"0001";
+
+/// The `-WM<version>-` prefix of our peer ids.
+pub fn client_signature(version: &[u8; 4]) -> [u8; 8] {
+    let mut signature = [b'-'; 8];
+    signature[1..3].copy_from_slice(CLIENT_CODE);
+    signature[3..7].copy_from_slice(version);
+    signature
 }
 
-struct PeerStream {
+/// Generates an Azureus-style peer id, `signature` followed by twelve
+/// random bytes. See [`client_signature`] for ours.
+pub fn generate_peer_id(signature: &[u8; 8]) -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    rand::thread_rng().fill(&mut peer_id[8..]);
+    peer_id[..8].copy_from_slice(signature);
+    peer_id
+}
+
+/// Handshake and connection settings. Build with `PeerStreamOpts::new`,
+/// which guarantees 20-byte hashes and the standard protocol string.
+#[derive(Clone)]
+pub struct PeerStreamOpts {
+    pub(crate) protocol: Vec<u8>,
+    pub(crate) info_hash: Vec<u8>,
+    pub(crate) peer_id: Vec<u8>,
+    pub(crate) expected_peer_id: Option<Vec<u8>>,
+    pub(crate) supports_extensions: bool,
+    pub(crate) supports_fast: bool,
+    // Bounds both the TCP connect and the handshake, so peers that accept
+    // but never answer are dropped as quickly as unreachable ones
+    pub(crate) connect_timeout: Duration,
+    pub(crate) max_message_len: usize,
+    pub(crate) read_timeout: Duration,
+    pub(crate) pipeline_depth: usize,
+    pub(crate) connect_retry: ConnectRetryPolicy,
+}
+impl PeerStreamOpts {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            protocol: BITTORRENT_PROTOCOL.to_vec(),
+            info_hash: info_hash.to_vec(),
+            peer_id: peer_id.to_vec(),
+            expected_peer_id: None,
+            supports_extensions: false,
+            supports_fast: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_message_len: MAX_MESSAGE_LEN,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            connect_retry: ConnectRetryPolicy::default(),
+        }
+    }
+    /// Rejects the peer unless its handshake carries this id.
+    pub fn expected_peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.expected_peer_id = Some(peer_id.to_vec());
+        self
+    }
+    /// Advertises the extension protocol (BEP 10) in our handshake.
+    pub fn supports_extensions(mut self, supports_extensions: bool) -> Self {
+        self.supports_extensions = supports_extensions;
+        self
+    }
+    /// Advertises the fast extension (BEP 6) in our handshake. Its messages
+    /// are only exchanged with peers that advertise it too.
+    pub fn supports_fast(mut self, supports_fast: bool) -> Self {
+        self.supports_fast = supports_fast;
+        self
+    }
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+    /// Drops the peer if it announces a message longer than this.
+    pub fn max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = max_message_len;
+        self
+    }
+    /// Drops the peer if a message body takes longer than this to arrive
+    /// after its length prefix.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+    /// How `connect_peers` retries peers that fail to connect.
+    pub fn connect_retry(mut self, connect_retry: ConnectRetryPolicy) -> Self {
+        self.connect_retry = connect_retry;
+        self
+    }
+    /// Keeps up to this many block requests outstanding per peer. Zero is
+    /// treated as one.
+    pub fn pipeline_depth(mut self, pipeline_depth: usize) -> Self {
+        self.pipeline_depth = pipeline_depth.max(1);
+        self
+    }
+}
+
+/// Retry schedule for peers that fail to connect, since many are only
+/// briefly unreachable. Attempt `n` after the first waits
+/// `base_delay * 2^(n-2)` before dialing again.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectRetryPolicy {
+    /// Total attempts per peer, including the first.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+impl Default for ConnectRetryPolicy {
+    /// A single attempt, no retries.
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::from_secs(1),
+        }
+    }
+}
+impl ConnectRetryPolicy {
+    fn delay_before(&self, retry: u32) -> Duration {
+        self.base_delay.saturating_mul(2u32.saturating_pow(retry))
+    }
+}
+
+/// Dials `peers` with at most `max_concurrent` connections and handshakes in
+/// flight at once, yielding connections as they complete. Peers that fail to
+/// connect are retried according to `opts`' retry policy before being skipped.
+pub fn connect_peers(
+    peers: Vec<SocketAddr>,
+    max_concurrent: usize,
+    opts: PeerStreamOpts,
+) -> impl Stream<Item = PeerConnection> {
+    stream::iter(peers)
+        .map(move |addr| PeerStream::connect_with_retry(addr, opts.clone()))
+        .buffer_unordered(max_concurrent.max(1))
+        .filter_map(|result| async move {
+            match result {
+                Ok(stream) => Some(PeerConnection::new(stream)),
+                Err(e) => {
+                    log::debug!("Skipping peer: {}", e);
+                    None
+                }
+            }
+        })
+}
+
+/// Handshakes completed concurrently by `PeerListener::incoming`.
+const MAX_PENDING_HANDSHAKES: usize = 16;
+
+/// Accepts connections from peers, e.g. on the port we announce to
+/// trackers. Only peers asking for the torrent in its opts get through.
+pub struct PeerListener {
+    listener: TcpListener,
+    opts: PeerStreamOpts,
+}
+impl PeerListener {
+    pub async fn bind(addr: impl ToSocketAddrs, opts: PeerStreamOpts) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context("Failed to listen for peers")?;
+        Ok(Self { listener, opts })
+    }
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+    /// Waits for the next peer to connect and completes its handshake.
+    /// Peers asking for another torrent fail with `PeerError::BadInfoHash`.
+    pub async fn accept(&self) -> anyhow::Result<PeerConnection> {
+        let (stream, _) = self
+            .listener
+            .accept()
+            .await
+            .context("Failed to accept peer")?;
+        let stream = PeerStream::accept(stream, self.opts.clone()).await?;
+        Ok(PeerConnection::new(stream))
+    }
+    /// Yields every peer that completes a handshake, handshaking with
+    /// several at once. Failed connections are logged and skipped.
+    pub fn incoming(&self) -> impl Stream<Item = PeerConnection> + '_ {
+        self.listener
+            .incoming()
+            .filter_map(|stream| async move {
+                stream
+                    .map_err(|e| log::debug!("Failed to accept peer: {}", e))
+                    .ok()
+            })
+            .map(|stream| PeerStream::accept(stream, self.opts.clone()))
+            .buffer_unordered(MAX_PENDING_HANDSHAKES)
+            .filter_map(|result| async move {
+                match result {
+                    Ok(stream) => Some(PeerConnection::new(stream)),
+                    Err(e) => {
+                        log::debug!("Rejected incoming peer: {}", e);
+                        None
+                    }
+                }
+            })
+    }
+}
+
+pub struct PeerStream {
     addr: SocketAddr,
     stream: TcpStream,
     handshake: HandShake,
+    // Messages read while waiting for something else, e.g. a bitfield that
+    // arrived during the metadata exchange
+    pending: VecDeque<RawMessage>,
+    // Both sides advertised the fast extension
+    fast: bool,
+    max_message_len: usize,
+    read_timeout: Duration,
+    pipeline_depth: usize,
+    // Reused for every message body, which only grows it up to the largest
+    // message seen
+    read_buf: Vec<u8>,
 }
 impl PeerStream {
+    /// Waits until there is something to read without consuming it, so
+    /// unlike `read` the wait can be abandoned without losing our place in
+    /// the stream. A closed connection counts as readable; the next `read`
+    /// reports it.
+    pub async fn readable(&self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            return Ok(());
+        }
+        self.stream.peek(&mut [0u8; 1]).await.map(|_| ())
+    }
     pub async fn read(&mut self) -> anyhow::Result<RawMessage> {
-        PeerStream::read_message(&self.stream).await
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        PeerStream::read_message(
+            &self.stream,
+            &mut self.read_buf,
+            self.max_message_len,
+            self.read_timeout,
+        )
+        .await
+    }
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// The remote peer's id from its handshake.
+    pub fn peer_id(&self) -> &[u8] {
+        &self.handshake.peer_id
+    }
+    /// The remote peer's reserved handshake bytes.
+    pub fn reserved(&self) -> &[u8] {
+        &self.handshake.reserved
+    }
+    /// The optional protocols the remote peer advertised.
+    pub fn extensions(&self) -> Extensions {
+        self.handshake.extensions()
+    }
+    /// Whether both sides advertised the fast extension (BEP 6).
+    pub fn fast_enabled(&self) -> bool {
+        self.fast
+    }
+    /// Whether the remote peer advertised the extension protocol (BEP 10).
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.handshake.supports_extensions()
+    }
+    pub async fn write(&mut self, message: RawMessage) -> anyhow::Result<()> {
+        PeerStream::write_message(&self.stream, message).await
+    }
+    pub async fn write_keep_alive(&mut self) -> anyhow::Result<()> {
+        PeerStream::write_frame(&self.stream, &[]).await
+    }
+    /// Downloads the bencoded info dictionary from the peer using ut_metadata
+    /// (BEP 9), verifying it hashes to `info_hash`.
+    pub async fn fetch_metadata(&mut self, info_hash: &[u8; 20]) -> anyhow::Result<Vec<u8>> {
+        if !self.supports_extension_protocol() {
+            return Err(PeerError::ExtensionProtocolUnsupported)?;
+        }
+        PeerStream::exchange_metadata(
+            &self.stream,
+            info_hash,
+            &mut self.read_buf,
+            self.max_message_len,
+            self.read_timeout,
+            &mut self.pending,
+        )
+        .await
     }
     pub async fn connect(addr: SocketAddr, opts: PeerStreamOpts) -> anyhow::Result<PeerStream> {
-        let stream = TcpStream::connect(&addr)
+        let (stream, response_handshake) = future::timeout(opts.connect_timeout, async {
+            let stream = TcpStream::connect(&addr)
+                .await
+                .context("Failed to connect to peer")?;
+            let handshake = PeerStream::handshake(&stream, opts.clone()).await?;
+            anyhow::Ok((stream, handshake))
+        })
+        .await
+        .map_err(|_| PeerError::ConnectTimeout)??;
+        Ok(PeerStream::established(addr, stream, response_handshake, &opts))
+    }
+    /// Like `connect`, but takes a `host:port` string, e.g. from a magnet's
+    /// `x.pe`. Each address the host resolves to is tried in turn until one
+    /// completes a handshake.
+    pub async fn connect_str(host_port: &str, opts: PeerStreamOpts) -> anyhow::Result<PeerStream> {
+        let addrs = host_port
+            .to_socket_addrs()
             .await
-            .context("Failed to connect to peer")?;
-        let response_handshake = PeerStream::handshake(&stream, opts).await?;
-        Ok(PeerStream {
+            .with_context(|| format!("Failed to resolve peer {}", host_port))?;
+        let mut last_error = None;
+        for addr in addrs {
+            match PeerStream::connect(addr, opts.clone()).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    log::debug!("Connecting to {} failed: {}", addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| PeerError::NoAddresses(host_port.to_string()).into()))
+    }
+    /// Completes the handshake on a connection a peer opened to us. The peer
+    /// speaks first, and is only answered if it asks for our torrent.
+    pub async fn accept(stream: TcpStream, opts: PeerStreamOpts) -> anyhow::Result<PeerStream> {
+        let addr = stream.peer_addr().context("Failed to get peer address")?;
+        let request_handshake = future::timeout(
+            opts.connect_timeout,
+            PeerStream::respond_handshake(&stream, opts.clone()),
+        )
+        .await
+        .map_err(|_| PeerError::ConnectTimeout)??;
+        Ok(PeerStream::established(addr, stream, request_handshake, &opts))
+    }
+    fn established(
+        addr: SocketAddr,
+        stream: TcpStream,
+        handshake: HandShake,
+        opts: &PeerStreamOpts,
+    ) -> PeerStream {
+        let fast = opts.supports_fast && handshake.extensions().fast;
+        PeerStream {
             addr,
             stream,
-            handshake: response_handshake,
-        })
+            handshake,
+            pending: VecDeque::new(),
+            fast,
+            max_message_len: opts.max_message_len,
+            read_timeout: opts.read_timeout,
+            pipeline_depth: opts.pipeline_depth,
+            read_buf: Vec::new(),
+        }
+    }
+    /// Like `connect`, but retries failed attempts as `opts` allows. Peers
+    /// that answer for a different torrent or protocol are not retried.
+    pub async fn connect_with_retry(
+        addr: SocketAddr,
+        opts: PeerStreamOpts,
+    ) -> anyhow::Result<PeerStream> {
+        let retry = opts.connect_retry;
+        let mut attempt = 1;
+        loop {
+            let error = match PeerStream::connect(addr, opts.clone()).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => e,
+            };
+            let permanent = matches!(
+                error.downcast_ref::<PeerError>(),
+                Some(PeerError::BadProtocol | PeerError::BadInfoHash | PeerError::BadPeerId)
+            );
+            if permanent || attempt >= retry.max_attempts {
+                return Err(error);
+            }
+            log::debug!("Connecting to {} failed, retrying: {}", addr, error);
+            task::sleep(retry.delay_before(attempt - 1)).await;
+            attempt += 1;
+        }
     }
     async fn handshake(
-        ref mut stream: impl Read + Write + Unpin,
+        mut stream: impl Read + Write + Unpin,
         opts: PeerStreamOpts,
     ) -> anyhow::Result<HandShake> {
-        let request_handshake = HandShake {
-            pstr: opts.protocol,
-            info_hash: opts.info_hash,
-            peer_id: opts.peer_id,
-        };
+        let request_handshake = PeerStream::local_handshake(&opts);
         stream
             .write_all(&request_handshake.to_bytes())
             .await
@@ -73,69 +731,276 @@ impl PeerStream {
             .read_exact(&mut bytes)
             .await
             .context("Failed to read handshake")?;
-        let response_handshake = HandShake::from_bytes(&bytes);
-        if request_handshake.pstr != response_handshake.pstr {
-            return Err(PeerError::BadProtocol)?;
-        } else if request_handshake.info_hash != response_handshake.info_hash {
-            return Err(PeerError::BadInfoHash)?;
-        }
+        let response_handshake = HandShake::try_from(&bytes[..]).map_err(PeerError::from)?;
+        PeerStream::check_handshake(&request_handshake, &response_handshake, &opts)?;
         Ok(response_handshake)
     }
-    async fn read_message(ref mut stream: impl Read + Write + Unpin) -> anyhow::Result<RawMessage> {
-        let mut length = vec![0u8; 4];
+    /// The responder side of `handshake`: reads the peer's handshake first
+    /// and only replies once it checks out.
+    async fn respond_handshake(
+        mut stream: impl Read + Write + Unpin,
+        opts: PeerStreamOpts,
+    ) -> anyhow::Result<HandShake> {
+        let response_handshake = PeerStream::local_handshake(&opts);
+        let mut pstrlen = [0u8; 1];
+        stream
+            .read_exact(&mut pstrlen)
+            .await
+            .context("Failed to read handshake")?;
+        // Protocol string, reserved bytes, info hash and peer id
+        let mut bytes = vec![0u8; 1 + pstrlen[0] as usize + 8 + 20 + 20];
+        bytes[0] = pstrlen[0];
+        stream
+            .read_exact(&mut bytes[1..])
+            .await
+            .context("Failed to read handshake")?;
+        let request_handshake = HandShake::try_from(&bytes[..]).map_err(PeerError::from)?;
+        PeerStream::check_handshake(&response_handshake, &request_handshake, &opts)?;
+        stream
+            .write_all(&response_handshake.to_bytes())
+            .await
+            .context("Failed to write handshake")?;
+        Ok(request_handshake)
+    }
+    fn local_handshake(opts: &PeerStreamOpts) -> HandShake {
+        let mut handshake = HandShake {
+            pstr: opts.protocol.clone(),
+            reserved: [0u8; 8],
+            info_hash: opts.info_hash.clone(),
+            peer_id: opts.peer_id.clone(),
+        };
+        handshake.set_supports_extensions(opts.supports_extensions);
+        handshake.set_supports_fast(opts.supports_fast);
+        handshake
+    }
+    fn check_handshake(
+        ours: &HandShake,
+        theirs: &HandShake,
+        opts: &PeerStreamOpts,
+    ) -> Result<(), PeerError> {
+        if ours.pstr != theirs.pstr {
+            Err(PeerError::BadProtocol)
+        } else if ours.info_hash != theirs.info_hash {
+            Err(PeerError::BadInfoHash)
+        } else if opts.expected_peer_id.as_ref().is_some_and(|id| *id != theirs.peer_id) {
+            Err(PeerError::BadPeerId)
+        } else {
+            Ok(())
+        }
+    }
+    /// Reads one length-prefixed message. Waiting for the prefix is
+    /// unbounded since peers may legitimately go quiet, but once it arrives
+    /// the body must follow within `read_timeout`. The body is read into
+    /// `buf` and only its payload copied out.
+    async fn read_message(
+        mut stream: impl Read + Write + Unpin,
+        buf: &mut Vec<u8>,
+        max_message_len: usize,
+        read_timeout: Duration,
+    ) -> anyhow::Result<RawMessage> {
+        let mut length = [0u8; 4];
         stream
             .read_exact(&mut length)
             .await
             .context("Failed to read message length")?;
-        let length = BigEndian::read_int(&length, 4) as usize;
-        let mut message_bytes = vec![0u8; length];
-        stream
-            .read_exact(&mut message_bytes)
+        let length = BigEndian::read_u32(&length) as usize;
+        if length > max_message_len {
+            return Err(PeerError::MessageTooLarge(length))?;
+        }
+        buf.resize(length, 0);
+        future::timeout(read_timeout, stream.read_exact(buf))
             .await
+            .map_err(|_| PeerError::ReadTimeout)?
             .context("Failed to read message")?;
-        Ok(RawMessage::from(&message_bytes[..]))
+        Ok(RawMessage::from(&buf[..]))
+    }
+    async fn exchange_metadata(
+        mut stream: impl Read + Write + Unpin,
+        info_hash: &[u8; 20],
+        buf: &mut Vec<u8>,
+        max_message_len: usize,
+        read_timeout: Duration,
+        skipped: &mut VecDeque<RawMessage>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut handshake = ExtendedHandshake::default();
+        handshake
+            .extensions
+            .insert("ut_metadata".to_string(), UT_METADATA_ID);
+        handshake.extensions.insert("ut_pex".to_string(), UT_PEX_ID);
+        let message = Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: handshake.to_bytes(),
+        };
+        PeerStream::write_message(&mut stream, message.to_raw()).await?;
+
+        let peer_handshake = loop {
+            let raw =
+                PeerStream::read_message(&mut stream, buf, max_message_len, read_timeout).await?;
+            if raw.message_id != MessageTypes::Extended as u8 {
+                skipped.push_back(raw);
+                continue;
+            }
+            if let Ok(Message::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload,
+            }) = Message::from_raw(raw)
+            {
+                break ExtendedHandshake::from_bytes(&payload)?;
+            }
+        };
+        let peer_metadata_id = peer_handshake
+            .extension_id("ut_metadata")
+            .ok_or(PeerError::MetadataUnsupported)?;
+        let metadata_size = peer_handshake
+            .metadata_size
+            .context("Peer did not advertise a metadata size")?;
+        if metadata_size == 0 || metadata_size > MAX_METADATA_SIZE {
+            return Err(PeerError::InvalidMetadataSize(metadata_size))?;
+        }
+
+        let mut metadata = vec![0u8; metadata_size];
+        for (piece, chunk) in metadata.chunks_mut(METADATA_PIECE_LEN).enumerate() {
+            let request = Message::Extended {
+                id: peer_metadata_id,
+                payload: MetadataMessage::Request { piece }.to_bytes(),
+            };
+            PeerStream::write_message(&mut stream, request.to_raw()).await?;
+            loop {
+                let raw =
+                    PeerStream::read_message(&mut stream, buf, max_message_len, read_timeout)
+                        .await?;
+                if raw.message_id != MessageTypes::Extended as u8 {
+                    skipped.push_back(raw);
+                    continue;
+                }
+                let Ok(Message::Extended {
+                    id: UT_METADATA_ID,
+                    payload,
+                }) = Message::from_raw(raw)
+                else {
+                    continue;
+                };
+                match MetadataMessage::from_bytes(&payload)? {
+                    MetadataMessage::Data { piece: p, data, .. } if p == piece => {
+                        if data.len() != chunk.len() {
+                            let length = data.len();
+                            return Err(PeerError::MetadataPieceLength { piece, length })?;
+                        }
+                        chunk.copy_from_slice(&data);
+                        break;
+                    }
+                    MetadataMessage::Reject { .. } => {
+                        return Err(PeerError::MetadataRejected(piece))?;
+                    }
+                    _ => continue,
+                }
+            }
+        }
+
+        if !verify_info_hash(&metadata, info_hash) {
+            return Err(PeerError::MetadataHashMismatch)?;
+        }
+        Ok(metadata)
+    }
+    async fn write_message(stream: impl Read + Write + Unpin, message: RawMessage) -> anyhow::Result<()> {
+        let bytes: Vec<u8> = message.into();
+        PeerStream::write_frame(stream, &bytes).await
+    }
+    async fn write_frame(mut stream: impl Read + Write + Unpin, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut frame = vec![0u8; 4 + bytes.len()];
+        BigEndian::write_u32(&mut frame[0..4], bytes.len() as u32);
+        frame[4..].copy_from_slice(bytes);
+        stream
+            .write_all(&frame)
+            .await
+            .context("Failed to write message")?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::peer::messages::Message;
+    use sha1::{Digest, Sha1};
+    use futures::channel::mpsc;
+    use std::{cmp::min, pin::Pin, task::Poll};
+
+    #[derive(Default)]
     struct MockTcpStream {
         read_data: Vec<u8>,
+        // Chunks delivered one poll at a time once read_data runs dry
+        stages: VecDeque<Vec<u8>>,
+        // Once out of data, stay pending instead of reporting EOF
+        stall: bool,
         write_data: Vec<u8>,
     }
+    impl MockTcpStream {
+        fn new(read_data: Vec<u8>) -> Self {
+            Self {
+                read_data,
+                ..Default::default()
+            }
+        }
+        /// Delivers `chunks` one at a time with a pending poll before each,
+        /// like a peer whose data trickles in.
+        fn staged(chunks: Vec<Vec<u8>>) -> Self {
+            Self {
+                stages: chunks.into(),
+                ..Default::default()
+            }
+        }
+        /// Never delivers another byte after the data runs out, like a peer
+        /// that stalls mid-message.
+        fn stalling(mut self) -> Self {
+            self.stall = true;
+            self
+        }
+    }
     impl Read for MockTcpStream {
         fn poll_read(
             self: Pin<&mut Self>,
             cx: &mut std::task::Context<'_>,
             buf: &mut [u8],
         ) -> Poll<std::io::Result<usize>> {
-            let end = min(buf.len(), self.read_data.len());
-            buf[..end].copy_from_slice(&self.read_data[..end]);
-            self.get_mut().read_data = self.read_data[end..].to_vec();
+            let this = self.get_mut();
+            if this.read_data.is_empty() {
+                match this.stages.pop_front() {
+                    Some(chunk) => {
+                        this.read_data = chunk;
+                        cx.waker().wake_by_ref();
+                        return Poll::Pending;
+                    }
+                    None if this.stall => return Poll::Pending,
+                    None => return Poll::Ready(Ok(0)),
+                }
+            }
+            let end = min(buf.len(), this.read_data.len());
+            buf[..end].copy_from_slice(&this.read_data[..end]);
+            this.read_data.drain(..end);
             Poll::Ready(Ok(end))
         }
     }
     impl Write for MockTcpStream {
         fn poll_write(
             self: Pin<&mut Self>,
-            cx: &mut std::task::Context<'_>,
+            _cx: &mut std::task::Context<'_>,
             buf: &[u8],
         ) -> Poll<std::io::Result<usize>> {
-            self.get_mut().write_data = Vec::from(buf);
+            self.get_mut().write_data.extend_from_slice(buf);
             Poll::Ready(Ok(buf.len()))
         }
 
         fn poll_flush(
             self: std::pin::Pin<&mut Self>,
-            cx: &mut std::task::Context<'_>,
+            _cx: &mut std::task::Context<'_>,
         ) -> Poll<std::io::Result<()>> {
             Poll::Ready(Ok(()))
         }
 
         fn poll_close(
             self: std::pin::Pin<&mut Self>,
-            cx: &mut std::task::Context<'_>,
+            _cx: &mut std::task::Context<'_>,
         ) -> Poll<std::io::Result<()>> {
             Poll::Ready(Ok(()))
         }
@@ -148,16 +1013,22 @@ mod tests {
             protocol: "test_protocol".as_bytes().to_vec(),
             info_hash: vec![1u8; 20],
             peer_id: vec![2u8; 20],
+            expected_peer_id: None,
+            supports_extensions: false,
+            supports_fast: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_message_len: MAX_MESSAGE_LEN,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            connect_retry: ConnectRetryPolicy::default(),
         };
         let expected_response = HandShake {
             pstr: "test_protocol".as_bytes().to_vec(),
+            reserved: [0u8; 8],
             info_hash: vec![1u8; 20],
             peer_id: vec![2u8; 20],
         };
-        let mut stream = MockTcpStream {
-            read_data: expected_response.to_bytes().to_vec(),
-            write_data: Vec::new(),
-        };
+        let mut stream = MockTcpStream::new(expected_response.to_bytes().to_vec());
         let response = PeerStream::handshake(&mut stream, opts).await.unwrap();
         assert_eq!(response.pstr, "test_protocol".as_bytes());
     }
@@ -168,16 +1039,22 @@ mod tests {
             protocol: "test_protocol".as_bytes().to_vec(),
             info_hash: vec![0u8; 20],
             peer_id: vec![2u8; 20],
+            expected_peer_id: None,
+            supports_extensions: false,
+            supports_fast: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_message_len: MAX_MESSAGE_LEN,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            connect_retry: ConnectRetryPolicy::default(),
         };
         let expected_response = HandShake {
             pstr: "test_protocol".as_bytes().to_vec(),
+            reserved: [0u8; 8],
             info_hash: vec![1u8; 20],
             peer_id: vec![2u8; 20],
         };
-        let mut stream = MockTcpStream {
-            read_data: expected_response.to_bytes().to_vec(),
-            write_data: Vec::new(),
-        };
+        let mut stream = MockTcpStream::new(expected_response.to_bytes().to_vec());
         let response = PeerStream::handshake(&mut stream, opts).await;
         assert!(response.is_err());
         assert_eq!(
@@ -192,16 +1069,22 @@ mod tests {
             protocol: "test_protocol".as_bytes().to_vec(),
             info_hash: vec![1u8; 20],
             peer_id: vec![0u8; 20],
+            expected_peer_id: None,
+            supports_extensions: false,
+            supports_fast: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_message_len: MAX_MESSAGE_LEN,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            connect_retry: ConnectRetryPolicy::default(),
         };
         let expected_response = HandShake {
             pstr: "test_protocok".as_bytes().to_vec(),
+            reserved: [0u8; 8],
             info_hash: vec![1u8; 20],
             peer_id: vec![2u8; 20],
         };
-        let mut stream = MockTcpStream {
-            read_data: expected_response.to_bytes().to_vec(),
-            write_data: Vec::new(),
-        };
+        let mut stream = MockTcpStream::new(expected_response.to_bytes().to_vec());
         let response = PeerStream::handshake(&mut stream, opts).await;
         assert!(response.is_err());
         assert_eq!(
@@ -210,25 +1093,758 @@ mod tests {
         );
     }
 
+    async fn read(stream: &mut MockTcpStream) -> anyhow::Result<RawMessage> {
+        PeerStream::read_message(stream, &mut Vec::new(), MAX_MESSAGE_LEN, DEFAULT_READ_TIMEOUT)
+            .await
+    }
+
     #[async_std::test]
     async fn test_peerstream_read_message() {
-        let mut stream = MockTcpStream {
-            read_data: vec![0, 0, 0, 4, 1, 2, 2, 4],
-            write_data: Vec::new(),
-        };
-        let response = PeerStream::read_message(&mut stream).await.unwrap();
+        let mut stream = MockTcpStream::new(vec![0, 0, 0, 4, 1, 2, 2, 4]);
+        let response = read(&mut stream).await.unwrap();
         assert_eq!(response.message_id, 1);
         assert_eq!(response.payload, vec![2, 2, 4]);
     }
 
+    #[async_std::test]
+    async fn test_peerstream_read_message_in_chunks() {
+        // The length prefix and the start of the body arrive before the rest
+        let mut stream = MockTcpStream::staged(vec![vec![0, 0, 0, 4, 1], vec![2, 2, 4]]);
+        let response = read(&mut stream).await.unwrap();
+        assert_eq!(response.message_id, 1);
+        assert_eq!(response.payload, vec![2, 2, 4]);
+        assert!(stream.stages.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_read_message_prefix_then_body() {
+        // Each read sees only what one TCP segment carried: a bare length
+        // prefix, then a body split in two that runs into the next message
+        let mut stream = MockTcpStream::staged(vec![
+            vec![0, 0, 0, 5],
+            vec![4, 0],
+            vec![0, 0, 9, 0, 0, 0, 1],
+            vec![2],
+        ]);
+        let have = read(&mut stream).await.unwrap();
+        assert_eq!(Message::from_raw(have).unwrap(), Message::Have(9));
+        let interested = read(&mut stream).await.unwrap();
+        assert_eq!(Message::from_raw(interested).unwrap(), Message::Interested);
+        assert!(stream.stages.is_empty());
+        assert!(read(&mut stream).await.is_err());
+    }
+
     #[async_std::test]
     async fn test_peerstream_read_message_keep_alive() {
-        let mut stream = MockTcpStream {
-            read_data: vec![0, 0, 0, 0],
-            write_data: Vec::new(),
-        };
-        let response = PeerStream::read_message(&mut stream).await.unwrap();
+        let mut stream = MockTcpStream::new(vec![0, 0, 0, 0]);
+        let response = read(&mut stream).await.unwrap();
         assert_eq!(response.message_id, 0);
         assert_eq!(response.payload, vec![]);
+        assert!(response.keep_alive);
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_read_message_choke() {
+        let mut stream = MockTcpStream::new(vec![0, 0, 0, 1, 0]);
+        let response = read(&mut stream).await.unwrap();
+        assert!(!response.keep_alive);
+        assert_eq!(Message::from_raw(response).unwrap(), Message::Choke);
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_read_message_reuses_buffer() {
+        let mut data = frame(Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![7u8; 1000],
+        });
+        data.extend(frame(Message::Have(3)));
+        data.extend(frame(Message::Request { index: 1, begin: 2, length: 3 }));
+        data.extend([0, 0, 0, 0]);
+        let mut stream = MockTcpStream::new(data);
+        let mut buf = Vec::new();
+        let timeout = DEFAULT_READ_TIMEOUT;
+        let first = PeerStream::read_message(&mut stream, &mut buf, 2000, timeout).await.unwrap();
+        assert_eq!(first.payload, [vec![0u8; 8], vec![7u8; 1000]].concat());
+        let allocation = buf.as_ptr();
+        let have = PeerStream::read_message(&mut stream, &mut buf, 2000, timeout).await.unwrap();
+        assert_eq!(Message::from_raw(have).unwrap(), Message::Have(3));
+        let request = PeerStream::read_message(&mut stream, &mut buf, 2000, timeout).await.unwrap();
+        assert_eq!(
+            Message::from_raw(request).unwrap(),
+            Message::Request { index: 1, begin: 2, length: 3 }
+        );
+        let keep_alive = PeerStream::read_message(&mut stream, &mut buf, 2000, timeout).await;
+        assert!(keep_alive.unwrap().keep_alive);
+        // Later, smaller messages fit in the buffer grown by the first
+        assert_eq!(buf.as_ptr(), allocation);
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_write_message() {
+        let mut stream = MockTcpStream::default();
+        let message = Message::Interested.to_raw();
+        PeerStream::write_message(&mut stream, message).await.unwrap();
+        assert_eq!(stream.write_data, vec![0, 0, 0, 1, 2]);
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_write_keep_alive() {
+        let mut stream = MockTcpStream::default();
+        PeerStream::write_frame(&mut stream, &[]).await.unwrap();
+        assert_eq!(stream.write_data, vec![0, 0, 0, 0]);
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_read_message_timeout() {
+        // Announces a 4 byte message but only delivers one byte of it
+        let mut stream = MockTcpStream::new(vec![0, 0, 0, 4, 1]).stalling();
+        let timeout = Duration::from_millis(50);
+        let response =
+            PeerStream::read_message(&mut stream, &mut Vec::new(), MAX_MESSAGE_LEN, timeout).await;
+        assert!(matches!(
+            response.unwrap_err().downcast_ref::<PeerError>(),
+            Some(PeerError::ReadTimeout)
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_read_message_too_large() {
+        let mut stream = MockTcpStream::new(vec![0xFF, 0xFF, 0xFF, 0xFF, 1]);
+        let response = read(&mut stream).await;
+        assert!(matches!(
+            response.unwrap_err().downcast_ref::<PeerError>(),
+            Some(PeerError::MessageTooLarge(0xFFFFFFFF))
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_expected_peer_id() {
+        let response_handshake = HandShake {
+            pstr: "test_protocol".as_bytes().to_vec(),
+            reserved: [0u8; 8],
+            info_hash: vec![1u8; 20],
+            peer_id: vec![3u8; 20],
+        };
+        for (expected, ok) in [(vec![3u8; 20], true), (vec![4u8; 20], false)] {
+            let opts = PeerStreamOpts {
+                protocol: "test_protocol".as_bytes().to_vec(),
+                info_hash: vec![1u8; 20],
+                peer_id: vec![2u8; 20],
+                expected_peer_id: Some(expected),
+                supports_extensions: false,
+                supports_fast: false,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                max_message_len: MAX_MESSAGE_LEN,
+                read_timeout: DEFAULT_READ_TIMEOUT,
+                pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+                connect_retry: ConnectRetryPolicy::default(),
+            };
+            let mut stream = MockTcpStream::new(response_handshake.to_bytes());
+            let response = PeerStream::handshake(&mut stream, opts).await;
+            if ok {
+                assert_eq!(response.unwrap().peer_id, vec![3u8; 20]);
+            } else {
+                assert_eq!(response.unwrap_err().to_string(), "Peer id mismatch");
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_handshake_extensions() {
+        let opts = PeerStreamOpts {
+            protocol: "test_protocol".as_bytes().to_vec(),
+            info_hash: vec![1u8; 20],
+            peer_id: vec![2u8; 20],
+            expected_peer_id: None,
+            supports_extensions: true,
+            supports_fast: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_message_len: MAX_MESSAGE_LEN,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            connect_retry: ConnectRetryPolicy::default(),
+        };
+        let mut response_handshake = HandShake {
+            pstr: "test_protocol".as_bytes().to_vec(),
+            reserved: [0u8; 8],
+            info_hash: vec![1u8; 20],
+            peer_id: vec![3u8; 20],
+        };
+        response_handshake.set_supports_extensions(true);
+        let mut stream = MockTcpStream::new(response_handshake.to_bytes());
+        let response = PeerStream::handshake(&mut stream, opts).await.unwrap();
+        assert!(response.supports_extensions());
+        assert_eq!(stream.write_data[19], 0x10);
+    }
+
+    fn frame(message: Message) -> Vec<u8> {
+        let bytes: Vec<u8> = message.to_raw().into();
+        let mut frame = (bytes.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&bytes);
+        frame
+    }
+
+    fn metadata_peer_data(metadata: &[u8]) -> Vec<u8> {
+        let mut handshake = ExtendedHandshake {
+            metadata_size: Some(metadata.len()),
+            ..Default::default()
+        };
+        handshake.extensions.insert("ut_metadata".to_string(), 3);
+        let mut read_data = frame(Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: handshake.to_bytes(),
+        });
+        read_data.extend(frame(Message::Unchoke));
+        for (piece, chunk) in metadata.chunks(METADATA_PIECE_LEN).enumerate() {
+            let data = MetadataMessage::Data {
+                piece,
+                total_size: metadata.len(),
+                data: chunk.to_vec(),
+            };
+            read_data.extend(frame(Message::Extended {
+                id: UT_METADATA_ID,
+                payload: data.to_bytes(),
+            }));
+        }
+        read_data
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_exchange_metadata() {
+        let mut metadata = b"d4:name4:test12:piece lengthi16384e6:pieces20000:".to_vec();
+        metadata.extend(vec![7u8; 20000]);
+        metadata.push(b'e');
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+        let mut stream = MockTcpStream::new(metadata_peer_data(&metadata));
+        let mut skipped = VecDeque::new();
+        let fetched = PeerStream::exchange_metadata(
+            &mut stream,
+            &info_hash,
+            &mut Vec::new(),
+            MAX_MESSAGE_LEN,
+            DEFAULT_READ_TIMEOUT,
+            &mut skipped,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fetched, metadata);
+        assert_eq!(skipped, vec![Message::Unchoke.to_raw()]);
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_exchange_metadata_bad_hash() {
+        let metadata = b"d4:name4:teste".to_vec();
+        let mut stream = MockTcpStream::new(metadata_peer_data(&metadata));
+        let result = PeerStream::exchange_metadata(
+            &mut stream,
+            &[0u8; 20],
+            &mut Vec::new(),
+            MAX_MESSAGE_LEN,
+            DEFAULT_READ_TIMEOUT,
+            &mut VecDeque::new(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Metadata does not match info hash"
+        );
+    }
+
+    #[test]
+    fn test_connection_state_transitions() {
+        let mut state = ConnectionState::default();
+        assert!(state.am_choking && state.peer_choking);
+        assert!(!state.am_interested && !state.peer_interested);
+        assert!(!state.can_request());
+
+        state.on_sent(&Message::Interested);
+        assert!(state.am_interested);
+        assert!(!state.can_request());
+
+        state.on_received(&Message::Unchoke);
+        assert!(!state.peer_choking);
+        assert!(state.can_request());
+
+        state.on_received(&Message::Interested);
+        state.on_sent(&Message::Unchoke);
+        assert!(state.peer_interested);
+        assert!(!state.am_choking);
+
+        state.on_received(&Message::Have(1));
+        assert!(state.can_request());
+
+        state.on_received(&Message::Choke);
+        assert!(!state.can_request());
+
+        state.on_received(&Message::Unchoke);
+        state.on_sent(&Message::NotInterested);
+        assert!(!state.can_request());
+        state.on_received(&Message::NotInterested);
+        assert!(!state.peer_interested);
+    }
+
+    #[async_std::test]
+    async fn test_connect_timeout() {
+        // Accepts the connection but never answers the handshake
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            async_std::task::sleep(Duration::from_secs(10)).await;
+        });
+        let opts = PeerStreamOpts {
+            protocol: b"BitTorrent protocol".to_vec(),
+            info_hash: vec![1u8; 20],
+            peer_id: vec![2u8; 20],
+            expected_peer_id: None,
+            supports_extensions: false,
+            supports_fast: false,
+            connect_timeout: Duration::from_millis(100),
+            max_message_len: MAX_MESSAGE_LEN,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            connect_retry: ConnectRetryPolicy::default(),
+        };
+        let started = std::time::Instant::now();
+        let result = PeerStream::connect(addr, opts).await;
+        assert!(matches!(
+            result.err().unwrap().downcast_ref::<PeerError>(),
+            Some(PeerError::ConnectTimeout)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[async_std::test]
+    async fn test_connect_peers_respects_cap() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_seen = Arc::new(AtomicUsize::new(0));
+        let mut peers = Vec::new();
+        for _ in 0..6 {
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(listener.local_addr().unwrap());
+            let (active, max_seen) = (active.clone(), max_seen.clone());
+            async_std::task::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                max_seen.fetch_max(now, Ordering::SeqCst);
+                async_std::task::sleep(Duration::from_millis(50)).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+                respond_handshake(&mut stream, mock_handshake([1u8; 20])).await;
+                async_std::task::sleep(Duration::from_secs(5)).await;
+            });
+        }
+        // Nothing listens here once the listener is dropped
+        let dead = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        peers.push(dead.local_addr().unwrap());
+        drop(dead);
+
+        let opts = PeerStreamOpts {
+            protocol: b"BitTorrent protocol".to_vec(),
+            info_hash: vec![1u8; 20],
+            peer_id: vec![2u8; 20],
+            expected_peer_id: None,
+            supports_extensions: false,
+            supports_fast: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_message_len: MAX_MESSAGE_LEN,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            connect_retry: ConnectRetryPolicy::default(),
+        };
+        let connections = connect_peers(peers, 2, opts).collect::<Vec<_>>().await;
+        assert_eq!(connections.len(), 6);
+        assert_eq!(max_seen.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn test_peerstream_handshake_accessors() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut response = HandShake {
+                peer_id: b"-TR3000-abcdefghijkl".to_vec(),
+                ..mock_handshake([1u8; 20])
+            };
+            response.set_supports_extensions(true);
+            let _stream = accept_handshake(&listener, response).await;
+            async_std::task::sleep(Duration::from_secs(5)).await;
+        });
+        let opts = PeerStreamOpts {
+            protocol: b"BitTorrent protocol".to_vec(),
+            info_hash: vec![1u8; 20],
+            peer_id: vec![2u8; 20],
+            expected_peer_id: None,
+            supports_extensions: false,
+            supports_fast: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_message_len: MAX_MESSAGE_LEN,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            connect_retry: ConnectRetryPolicy::default(),
+        };
+        let stream = PeerStream::connect(addr, opts).await.unwrap();
+        assert_eq!(stream.peer_id(), b"-TR3000-abcdefghijkl");
+        assert_eq!(stream.reserved(), [0, 0, 0, 0, 0, 0x10, 0, 0]);
+        assert!(stream.supports_extension_protocol());
+        let extensions = PeerConnection::new(stream).extensions();
+        assert!(extensions.extension_protocol);
+        assert!(!extensions.dht && !extensions.fast);
+    }
+
+    #[test]
+    fn test_opts_default_protocol() {
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        assert_eq!(opts.protocol, b"BitTorrent protocol");
+        assert_eq!(opts.info_hash, [1u8; 20]);
+        assert_eq!(opts.peer_id, [2u8; 20]);
+        assert_eq!(opts.expected_peer_id, None);
+        assert!(!opts.supports_extensions);
+        assert_eq!(opts.connect_timeout, DEFAULT_CONNECT_TIMEOUT);
+
+        let opts = opts
+            .expected_peer_id([3u8; 20])
+            .supports_extensions(true)
+            .connect_timeout(Duration::from_secs(1));
+        assert_eq!(opts.expected_peer_id, Some(vec![3u8; 20]));
+        assert!(opts.supports_extensions);
+        assert_eq!(opts.connect_timeout, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_generate_peer_id() {
+        let first = generate_peer_id(&client_signature(CLIENT_VERSION));
+        let second = generate_peer_id(&client_signature(CLIENT_VERSION));
+        assert_eq!(&first[..8], b"-WM0001-");
+        assert_eq!(&second[..8], b"-WM0001-");
+        assert_ne!(first[8..], second[8..]);
+        assert_eq!(&client_signature(b"0102"), b"-WM0102-");
+        assert_eq!(&generate_peer_id(b"-XX1234-")[..8], b"-XX1234-");
+    }
+
+    /// The handshake mock peers answer with: no extensions and a peer id of
+    /// all 3s.
+    pub(crate) fn mock_handshake(info_hash: [u8; 20]) -> HandShake {
+        HandShake {
+            pstr: BITTORRENT_PROTOCOL.to_vec(),
+            reserved: [0u8; 8],
+            info_hash: info_hash.to_vec(),
+            peer_id: vec![3u8; 20],
+        }
+    }
+
+    /// Reads the client's handshake from `stream` and answers with `response`.
+    pub(crate) async fn respond_handshake(stream: &mut TcpStream, response: HandShake) {
+        let mut handshake = vec![0u8; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream.write_all(&response.to_bytes()).await.unwrap();
+    }
+
+    /// Accepts the next connection on `listener` and completes the
+    /// handshake with `response`, leaving the stream ready for messages.
+    pub(crate) async fn accept_handshake(
+        listener: &TcpListener,
+        response: HandShake,
+    ) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        respond_handshake(&mut stream, response).await;
+        stream
+    }
+
+    /// Accepts one connection, answers the handshake and then sends `messages`.
+    async fn spawn_peer(info_hash: [u8; 20], messages: Vec<Message>) -> SocketAddr {
+        spawn_peer_with_reserved(info_hash, [0u8; 8], messages).await
+    }
+
+    /// Like `spawn_peer`, but answers the handshake with `reserved`.
+    async fn spawn_peer_with_reserved(
+        info_hash: [u8; 20],
+        reserved: [u8; 8],
+        messages: Vec<Message>,
+    ) -> SocketAddr {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let response = HandShake { reserved, ..mock_handshake(info_hash) };
+            let mut stream = accept_handshake(&listener, response).await;
+            for message in messages {
+                stream.write_all(&frame(message)).await.unwrap();
+            }
+            async_std::task::sleep(Duration::from_secs(5)).await;
+        });
+        addr
+    }
+
+    #[async_std::test]
+    async fn test_connection_records_dht_port() {
+        let addr = spawn_peer([1u8; 20], vec![Message::Unchoke, Message::Port(6881)]).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        let mut conn = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        assert_eq!(conn.dht_port(), None);
+        assert_eq!(conn.read().await.unwrap(), Message::Unchoke);
+        assert_eq!(conn.read().await.unwrap(), Message::Port(6881));
+        assert_eq!(conn.dht_port(), Some(6881));
+    }
+
+    #[async_std::test]
+    async fn test_connection_collects_pex_peers() {
+        let first = PexMessage {
+            added: vec!["10.0.0.1:6881".parse().unwrap(), "10.0.0.2:6881".parse().unwrap()],
+            dropped: Vec::new(),
+        };
+        let second = PexMessage {
+            added: vec!["10.0.0.1:6881".parse().unwrap()],
+            dropped: vec!["10.0.0.2:6881".parse().unwrap()],
+        };
+        let messages = [first, second]
+            .iter()
+            .map(|pex| Message::Extended {
+                id: UT_PEX_ID,
+                payload: pex.to_bytes(),
+            })
+            .collect();
+        let addr = spawn_peer([1u8; 20], messages).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        let mut conn = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        conn.read().await.unwrap();
+        conn.read().await.unwrap();
+        assert_eq!(conn.take_pex_peers(), vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+        assert!(conn.take_pex_peers().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_prepare_download() {
+        let bitfield = Message::Bitfield(vec![0b1010_0000]);
+        let addr = spawn_peer([1u8; 20], vec![bitfield.clone(), Message::Unchoke]).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        let mut conn = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        conn.prepare_download().await.unwrap();
+        assert!(conn.am_interested());
+        assert!(!conn.peer_choking());
+        assert!(conn.state().can_request());
+        // Nothing read along the way is lost
+        assert_eq!(conn.read().await.unwrap(), bitfield);
+        assert_eq!(conn.read().await.unwrap(), Message::Unchoke);
+    }
+
+    #[async_std::test]
+    async fn test_prepare_download_never_unchoked() {
+        let addr = spawn_peer([1u8; 20], vec![Message::Have(3)]).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        let mut conn = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        let result = conn.prepare_download_within(Duration::from_millis(100)).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<PeerError>(),
+            Some(PeerError::UnchokeTimeout)
+        ));
+        assert!(conn.am_interested());
+        assert_eq!(conn.read().await.unwrap(), Message::Have(3));
+    }
+
+    #[async_std::test]
+    async fn test_have_sets_bit() {
+        let messages = vec![Message::Bitfield(vec![0b1000_0000]), Message::Have(3), Message::Have(8)];
+        let addr = spawn_peer([1u8; 20], messages).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        let mut conn = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        assert!(conn.bitfield().is_none());
+        conn.set_piece_count(5);
+        conn.read().await.unwrap();
+        assert_eq!(conn.bitfield().unwrap().iter().collect::<Vec<_>>(), [0]);
+        assert_eq!(conn.read().await.unwrap(), Message::Have(3));
+        assert_eq!(conn.bitfield().unwrap().iter().collect::<Vec<_>>(), [0, 3]);
+        let result = conn.read().await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<PeerError>(),
+            Some(PeerError::InvalidPieceIndex(8))
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_fast_extension() {
+        let fast = [0, 0, 0, 0, 0, 0, 0, 0x04];
+        let messages = vec![Message::HaveAll, Message::HaveNone];
+        let addr = spawn_peer_with_reserved([1u8; 20], fast, messages.clone()).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]).supports_fast(true);
+        let mut conn = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        conn.set_piece_count(10);
+        assert_eq!(conn.read().await.unwrap(), Message::HaveAll);
+        assert_eq!(conn.bitfield().unwrap().count_ones(), 10);
+        assert_eq!(conn.read().await.unwrap(), Message::HaveNone);
+        assert_eq!(conn.bitfield().unwrap().count_ones(), 0);
+
+        // Without our side advertising it, the same messages are a violation
+        let addr = spawn_peer_with_reserved([1u8; 20], fast, messages).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        let mut conn = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        assert!(matches!(
+            conn.read().await.unwrap_err().downcast_ref::<PeerError>(),
+            Some(PeerError::FastNotNegotiated)
+        ));
+        assert!(conn.send(Message::AllowedFast(1)).await.is_err());
+    }
+
+    /// Accepts one connection and forwards every frame the client sends,
+    /// length prefix included.
+    async fn spawn_recording_peer(
+        info_hash: [u8; 20],
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (frames, received) = mpsc::unbounded();
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut stream = accept_handshake(&listener, mock_handshake(info_hash)).await;
+            loop {
+                let mut length = [0u8; 4];
+                if stream.read_exact(&mut length).await.is_err() {
+                    break;
+                }
+                let mut frame = vec![0u8; BigEndian::read_u32(&length) as usize];
+                stream.read_exact(&mut frame).await.unwrap();
+                let _ = frames.unbounded_send([&length[..], &frame].concat());
+            }
+        });
+        (addr, received)
+    }
+
+    #[async_std::test]
+    async fn test_choke_and_unchoke() {
+        let (addr, mut frames) = spawn_recording_peer([1u8; 20]).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        let mut conn = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        assert!(conn.am_choking());
+        conn.unchoke().await.unwrap();
+        assert!(!conn.am_choking());
+        assert_eq!(frames.next().await.unwrap(), vec![0, 0, 0, 1, 1]);
+        conn.choke().await.unwrap();
+        assert!(conn.am_choking());
+        assert_eq!(frames.next().await.unwrap(), vec![0, 0, 0, 1, 0]);
+        // Choking an already choked peer sends nothing
+        conn.choke().await.unwrap();
+        conn.unchoke().await.unwrap();
+        assert_eq!(frames.next().await.unwrap(), vec![0, 0, 0, 1, 1]);
+    }
+
+    #[async_std::test]
+    async fn test_connect_retries_failed_attempt() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            // Hang up on the first attempt, then behave
+            drop(listener.accept().await.unwrap());
+            let _stream = accept_handshake(&listener, mock_handshake([1u8; 20])).await;
+            async_std::task::sleep(Duration::from_secs(5)).await;
+        });
+        let retry = ConnectRetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(50),
+        };
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]).connect_retry(retry);
+        let stream = PeerStream::connect_with_retry(addr, opts).await.unwrap();
+        assert_eq!(stream.peer_id(), &[3u8; 20]);
+    }
+
+    #[async_std::test]
+    async fn test_connect_gives_up_after_max_attempts() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accepted_tx, mut accepted) = mpsc::unbounded();
+        async_std::task::spawn(async move {
+            loop {
+                drop(listener.accept().await.unwrap());
+                accepted_tx.unbounded_send(()).unwrap();
+            }
+        });
+        let retry = ConnectRetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(10),
+        };
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]).connect_retry(retry);
+        assert!(PeerStream::connect_with_retry(addr, opts).await.is_err());
+        accepted.next().await.unwrap();
+        accepted.next().await.unwrap();
+        assert!(accepted.try_next().is_err());
+    }
+
+    #[async_std::test]
+    async fn test_listener_handshake() {
+        let info_hash = [1u8; 20];
+        let opts = PeerStreamOpts::new(info_hash, [2u8; 20]).supports_fast(true);
+        let listener = PeerListener::bind("127.0.0.1:0", opts).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client_opts = PeerStreamOpts::new(info_hash, [3u8; 20])
+            .supports_fast(true)
+            .expected_peer_id([2u8; 20]);
+        let (client, peer) =
+            futures::join!(PeerStream::connect(addr, client_opts), listener.accept());
+        let mut client = PeerConnection::new(client.unwrap());
+        let mut peer = peer.unwrap();
+        assert_eq!(peer.addr(), client.stream.stream.local_addr().unwrap());
+        assert_eq!(peer.stream.peer_id(), [3u8; 20]);
+        assert!(peer.fast_enabled() && client.fast_enabled());
+        peer.send(Message::HaveAll).await.unwrap();
+        assert_eq!(client.read().await.unwrap(), Message::HaveAll);
+
+        // A peer asking for another torrent is turned away unanswered
+        let other_opts = PeerStreamOpts::new([9u8; 20], [3u8; 20]);
+        let (client, peer) =
+            futures::join!(PeerStream::connect(addr, other_opts), listener.accept());
+        assert!(client.is_err());
+        assert!(matches!(
+            peer.err().unwrap().downcast_ref::<PeerError>(),
+            Some(PeerError::BadInfoHash)
+        ));
+    }
+
+    #[test]
+    fn test_peer_error_display() {
+        let cases = [
+            (PeerError::MessageTooLarge(70000), "Peer message length 70000 exceeds maximum"),
+            (PeerError::InvalidPieceIndex(12), "Peer referenced nonexistent piece 12"),
+            (
+                PeerError::BadHandshake(HandshakeError::Truncated { expected: 68, actual: 20 }),
+                "Malformed peer handshake: Handshake too short (20 of 68 bytes)",
+            ),
+            (
+                PeerError::NoAddresses("seed.invalid:6881".to_string()),
+                "Peer address seed.invalid:6881 resolved to no addresses",
+            ),
+            (
+                PeerError::MetadataPieceLength { piece: 2, length: 100 },
+                "Metadata piece 2 has wrong length 100",
+            ),
+            (PeerError::InvalidMetadataSize(0), "Invalid metadata size 0"),
+            (PeerError::MetadataRejected(1), "Peer rejected metadata piece 1"),
+            (PeerError::ConnectTimeout, "Timed out connecting to peer"),
+            (PeerError::ReadTimeout, "Timed out reading a message from peer"),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(error.to_string(), expected);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_connect_str() {
+        let addr = spawn_peer([1u8; 20], vec![Message::Unchoke]).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        let host_port = format!("127.0.0.1:{}", addr.port());
+        let mut stream = PeerStream::connect_str(&host_port, opts.clone()).await.unwrap();
+        assert_eq!(stream.addr(), addr);
+        assert_eq!(Message::from_raw(stream.read().await.unwrap()).unwrap(), Message::Unchoke);
+        assert!(PeerStream::connect_str("127.0.0.1", opts).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_keep_alive_keeps_peer_unchoked() {
+        let addr = spawn_peer([1u8; 20], vec![Message::Unchoke, Message::KeepAlive]).await;
+        let opts = PeerStreamOpts::new([1u8; 20], [2u8; 20]);
+        let mut conn = PeerConnection::new(PeerStream::connect(addr, opts).await.unwrap());
+        assert_eq!(conn.read().await.unwrap(), Message::Unchoke);
+        assert_eq!(conn.read().await.unwrap(), Message::KeepAlive);
+        assert!(!conn.peer_choking());
     }
 }
@@ -0,0 +1,131 @@
+use std::ops::Range;
+
+/// Reassembles a piece from blocks that may arrive out of order, tracking
+/// which byte ranges have been filled so far.
+#[derive(Debug)]
+pub struct PieceBuffer {
+    data: Vec<u8>,
+    // Sorted, non-overlapping and non-adjacent
+    filled: Vec<Range<usize>>,
+}
+impl PieceBuffer {
+    pub fn new(length: usize) -> Self {
+        Self {
+            data: vec![0u8; length],
+            filled: Vec::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+    /// Copies `block` in at offset `begin`. Blocks may overlap ones already
+    /// received, but must lie entirely within the piece.
+    pub fn add_block(&mut self, begin: u32, block: &[u8]) -> anyhow::Result<()> {
+        let begin = begin as usize;
+        let end = begin
+            .checked_add(block.len())
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Block at offset {} of length {} exceeds piece length {}",
+                    begin,
+                    block.len(),
+                    self.data.len()
+                )
+            })?;
+        if block.is_empty() {
+            return Ok(());
+        }
+        self.data[begin..end].copy_from_slice(block);
+        self.mark_filled(begin..end);
+        Ok(())
+    }
+    fn mark_filled(&mut self, range: Range<usize>) {
+        let mut merged = range;
+        self.filled.retain(|existing| {
+            if existing.start <= merged.end && merged.start <= existing.end {
+                merged = merged.start.min(existing.start)..merged.end.max(existing.end);
+                false
+            } else {
+                true
+            }
+        });
+        let position = self
+            .filled
+            .iter()
+            .position(|existing| existing.start > merged.start)
+            .unwrap_or(self.filled.len());
+        self.filled.insert(position, merged);
+    }
+    /// Number of bytes received so far.
+    pub fn filled_len(&self) -> usize {
+        self.filled.iter().map(|range| range.len()).sum()
+    }
+    /// Whether every byte of `begin..begin + len` has been received.
+    pub fn has_range(&self, begin: usize, len: usize) -> bool {
+        self.filled
+            .iter()
+            .any(|range| range.start <= begin && begin + len <= range.end)
+    }
+    pub fn is_complete(&self) -> bool {
+        self.filled_len() == self.data.len()
+    }
+    /// The assembled piece, once every byte has been received.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        self.is_complete().then_some(&self.data[..])
+    }
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        self.is_complete().then_some(self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reverse_order() {
+        let content = (0..100u8).collect::<Vec<_>>();
+        let mut buffer = PieceBuffer::new(content.len());
+        for (i, chunk) in content.chunks(30).enumerate().rev() {
+            assert!(buffer.bytes().is_none());
+            buffer.add_block((i * 30) as u32, chunk).unwrap();
+        }
+        assert!(buffer.is_complete());
+        assert_eq!(buffer.into_bytes().unwrap(), content);
+    }
+
+    #[test]
+    fn test_duplicate_block() {
+        let mut buffer = PieceBuffer::new(8);
+        buffer.add_block(0, &[1, 2, 3, 4]).unwrap();
+        buffer.add_block(0, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(buffer.filled_len(), 4);
+        assert!(!buffer.is_complete());
+        buffer.add_block(2, &[3, 4, 5, 6]).unwrap();
+        assert_eq!(buffer.filled_len(), 6);
+        assert!(buffer.has_range(1, 5));
+        assert!(!buffer.has_range(5, 2));
+        buffer.add_block(6, &[7, 8]).unwrap();
+        assert_eq!(buffer.bytes().unwrap(), [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_out_of_range_block() {
+        let mut buffer = PieceBuffer::new(8);
+        assert!(buffer.add_block(9, &[]).is_err());
+        assert!(buffer.add_block(6, &[1, 2, 3]).is_err());
+        assert!(buffer.add_block(u32::MAX, &[1]).is_err());
+        assert_eq!(buffer.filled_len(), 0);
+    }
+
+    #[test]
+    fn test_empty_piece_is_complete() {
+        let buffer = PieceBuffer::new(0);
+        assert!(buffer.is_complete());
+        assert_eq!(buffer.bytes(), Some(&[][..]));
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+};
+
+use futures::{channel::mpsc, FutureExt, StreamExt};
+use log::debug;
+
+use crate::peer::{
+    messages::Message,
+    peer_stream::{connect_peers, PeerConnection, PeerStreamOpts},
+};
+
+/// Every peer address learned so far, whichever source reported it, in the
+/// order they were first seen, and whether each has been dialed yet.
+#[derive(Debug, Default)]
+pub struct PeerSet {
+    order: Vec<SocketAddr>,
+    dialed: HashMap<SocketAddr, bool>,
+}
+impl PeerSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds `addr`, returning whether it wasn't known before.
+    pub fn insert(&mut self, addr: SocketAddr) -> bool {
+        if self.dialed.contains_key(&addr) {
+            return false;
+        }
+        self.dialed.insert(addr, false);
+        self.order.push(addr);
+        true
+    }
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.dialed.contains_key(addr)
+    }
+    /// Records a connection attempt to `addr`, adding it if it was unknown.
+    pub fn mark_dialed(&mut self, addr: SocketAddr) {
+        self.insert(addr);
+        self.dialed.insert(addr, true);
+    }
+    pub fn is_dialed(&self, addr: &SocketAddr) -> bool {
+        self.dialed.get(addr).copied().unwrap_or(false)
+    }
+    /// The peers never attempted, oldest first.
+    pub fn iter_undialed(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.order.iter().copied().filter(|addr| !self.dialed[addr])
+    }
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// Keeps up to `target` peer connections alive. Addresses come from a
+/// channel fed by trackers and the DHT (see
+/// [`crate::TRipClient::announce_loop`]) and from the connected peers' own
+/// ut_pex messages.
+pub struct PeerPool {
+    target: usize,
+    opts: PeerStreamOpts,
+    source: mpsc::UnboundedReceiver<SocketAddr>,
+    connections: Vec<PeerConnection>,
+    // Every address learned, so each source only contributes it once
+    peers: PeerSet,
+    // Dropped connections, dialed again after fresh candidates
+    redial: VecDeque<SocketAddr>,
+}
+impl PeerPool {
+    pub fn new(
+        target: usize,
+        opts: PeerStreamOpts,
+        source: mpsc::UnboundedReceiver<SocketAddr>,
+    ) -> Self {
+        Self {
+            target,
+            opts,
+            source,
+            connections: Vec::new(),
+            peers: PeerSet::new(),
+            redial: VecDeque::new(),
+        }
+    }
+    /// The live connections, for the piece scheduler to drive.
+    pub fn connections(&self) -> &[PeerConnection] {
+        &self.connections
+    }
+    pub fn connections_mut(&mut self) -> &mut [PeerConnection] {
+        &mut self.connections
+    }
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+    /// Every address the pool has learned of, dialed or not.
+    pub fn peers(&self) -> &PeerSet {
+        &self.peers
+    }
+    /// Queues addresses to dial, skipping ones seen before.
+    pub fn add_peers(&mut self, peers: impl IntoIterator<Item = SocketAddr>) {
+        for peer in peers {
+            self.peers.insert(peer);
+        }
+    }
+    /// Drops the connection to `addr` after it failed. The address is
+    /// redialed once the candidates learned meanwhile have had their turn.
+    pub fn remove(&mut self, addr: SocketAddr) -> Option<PeerConnection> {
+        let position = self.connections.iter().position(|conn| conn.addr() == addr)?;
+        self.redial.push_back(addr);
+        Some(self.connections.swap_remove(position))
+    }
+    /// Tells the connected peers we now have `piece_index`, skipping those
+    /// whose bitfield shows they have it already. Connections that fail are
+    /// left for their sessions to notice.
+    pub async fn broadcast_have(&mut self, piece_index: usize) {
+        for conn in self.connections.iter_mut() {
+            if conn.bitfield().is_some_and(|have| have.has(piece_index)) {
+                continue;
+            }
+            if let Err(e) = conn.send(Message::Have(piece_index as u32)).await {
+                debug!("Failed to send Have to {}: {}", conn.addr(), e);
+            }
+        }
+    }
+    /// Dials queued addresses until the pool is back at its target or runs
+    /// out of candidates, first collecting whatever the source and the
+    /// connected peers have reported since the last call. Returns how many
+    /// connections were added.
+    pub async fn fill(&mut self) -> usize {
+        // Only take what is ready; the source stays open between announces
+        while let Some(Some(peer)) = self.source.next().now_or_never() {
+            self.add_peers([peer]);
+        }
+        let pex = self
+            .connections
+            .iter_mut()
+            .flat_map(PeerConnection::take_pex_peers)
+            .collect::<Vec<_>>();
+        self.add_peers(pex);
+        let mut candidates = self
+            .peers
+            .iter_undialed()
+            .chain(self.redial.drain(..))
+            .collect::<VecDeque<_>>();
+
+        let before = self.connections.len();
+        while self.connections.len() < self.target && !candidates.is_empty() {
+            let wanted = self.target - self.connections.len();
+            let batch = candidates
+                .drain(..wanted.min(candidates.len()))
+                .collect::<Vec<_>>();
+            batch.iter().for_each(|addr| self.peers.mark_dialed(*addr));
+            let connected = connect_peers(batch, wanted, self.opts.clone())
+                .collect::<Vec<_>>()
+                .await;
+            self.connections.extend(connected);
+        }
+        // Fresh candidates stay undialed in `peers`; keep the redials
+        self.redial.extend(candidates.into_iter().filter(|addr| self.peers.is_dialed(addr)));
+        let added = self.connections.len() - before;
+        debug!("Added {} peers, pool has {} of {}", added, self.len(), self.target);
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::{
+        download::tests::{read_frame, spawn_seeder, test_content, test_opts, write_frame},
+        messages::Bitfield,
+        peer_stream::tests::{accept_handshake, mock_handshake},
+    };
+    use async_std::net::TcpListener;
+    use futures::channel::oneshot;
+
+    /// Completes one handshake and hangs up straight away.
+    async fn spawn_flaky_peer(info_hash: [u8; 20]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            accept_handshake(&listener, mock_handshake(info_hash)).await;
+        });
+        addr
+    }
+
+    /// Sends `bitfield` after the handshake and reports the Have messages
+    /// received once the connection closes.
+    async fn spawn_have_recorder(
+        info_hash: [u8; 20],
+        bitfield: Bitfield,
+    ) -> (SocketAddr, oneshot::Receiver<Vec<u32>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (haves_tx, haves_rx) = oneshot::channel();
+        async_std::task::spawn(async move {
+            let mut stream = accept_handshake(&listener, mock_handshake(info_hash)).await;
+            write_frame(&mut stream, Message::Bitfield(bitfield.as_bytes().to_vec())).await;
+            let mut haves = Vec::new();
+            while let Some(raw) = read_frame(&mut stream).await {
+                if let Ok(Message::Have(index)) = Message::from_raw(raw) {
+                    haves.push(index);
+                }
+            }
+            haves_tx.send(haves).unwrap();
+        });
+        (addr, haves_rx)
+    }
+
+    #[async_std::test]
+    async fn test_broadcast_have() {
+        let info_hash = [5u8; 20];
+        let mut has_first = Bitfield::new(2);
+        has_first.set(0);
+        let (seeder, seeder_haves) = spawn_have_recorder(info_hash, has_first).await;
+        let (leecher, leecher_haves) = spawn_have_recorder(info_hash, Bitfield::new(2)).await;
+        let (peers, source) = mpsc::unbounded();
+        let mut pool = PeerPool::new(2, test_opts(info_hash), source);
+        peers.unbounded_send(seeder).unwrap();
+        peers.unbounded_send(leecher).unwrap();
+        assert_eq!(pool.fill().await, 2);
+        for conn in pool.connections_mut() {
+            conn.set_piece_count(2);
+            assert!(matches!(conn.read().await.unwrap(), Message::Bitfield(_)));
+        }
+        pool.broadcast_have(0).await;
+        pool.broadcast_have(1).await;
+        drop(pool);
+        // The seeder already had piece 0, so it only hears about piece 1
+        assert_eq!(seeder_haves.await.unwrap(), vec![1]);
+        assert_eq!(leecher_haves.await.unwrap(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_peer_set() {
+        let addr = |n: u8| SocketAddr::from(([10, 0, 0, n], 6881));
+        let mut peers = PeerSet::new();
+        // Two sources report overlapping peers
+        let from_tracker = [addr(1), addr(2)];
+        let from_dht = [addr(2), addr(3), addr(1)];
+        let added = from_tracker
+            .into_iter()
+            .chain(from_dht)
+            .map(|peer| peers.insert(peer))
+            .collect::<Vec<_>>();
+        assert_eq!(added, vec![true, true, false, true, false]);
+        assert_eq!(peers.len(), 3);
+        assert_eq!(peers.iter_undialed().collect::<Vec<_>>(), vec![addr(1), addr(2), addr(3)]);
+
+        peers.mark_dialed(addr(2));
+        assert!(!peers.insert(addr(2)));
+        assert!(peers.is_dialed(&addr(2)));
+        assert_eq!(peers.iter_undialed().collect::<Vec<_>>(), vec![addr(1), addr(3)]);
+    }
+
+    #[async_std::test]
+    async fn test_replaces_disconnected_peer() {
+        let info_hash = [4u8; 20];
+        let (_, content) = test_content();
+        let flaky = spawn_flaky_peer(info_hash).await;
+        let steady = spawn_seeder(info_hash, content, 32768, None).await;
+        let (peers, source) = mpsc::unbounded();
+        let mut pool = PeerPool::new(1, test_opts(info_hash), source);
+        peers.unbounded_send(flaky).unwrap();
+        assert_eq!(pool.fill().await, 1);
+        assert_eq!(pool.connections()[0].addr(), flaky);
+
+        // The peer hangs up; a newly announced one takes its place
+        peers.unbounded_send(steady).unwrap();
+        peers.unbounded_send(flaky).unwrap();
+        assert!(pool.connections_mut()[0].read().await.is_err());
+        assert!(pool.remove(flaky).is_some());
+        assert!(pool.is_empty());
+        assert_eq!(pool.fill().await, 1);
+        assert_eq!(pool.connections()[0].addr(), steady);
+        // Already at target, so nothing more is dialed
+        assert_eq!(pool.fill().await, 0);
+        assert_eq!(pool.len(), 1);
+    }
+}
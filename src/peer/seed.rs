@@ -0,0 +1,253 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_std::future;
+use futures::StreamExt;
+use log::debug;
+
+use crate::peer::{
+    choke::{ChokeManager, DEFAULT_UNCHOKE_SLOTS},
+    download::BLOCK_SIZE,
+    messages::{Bitfield, Message},
+    peer_stream::{PeerConnection, PeerListener},
+    stats::StatsTracker,
+    storage::Storage,
+};
+
+/// How often upload slots are reassigned.
+pub const CHOKE_ROUND_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a peer we serve may stay silent. Peers send keep-alives every
+/// two minutes even while they have nothing to request.
+const SEED_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Upload slots shared by every peer we serve. With nothing to download, a
+/// seed ranks interested peers by how fast it uploads to them.
+#[derive(Debug)]
+pub struct UploadSlots {
+    choker: ChokeManager,
+    // Regular slots plus the optimistic one
+    capacity: usize,
+    // Bytes sent to each interested peer since the last round
+    uploaded: HashMap<SocketAddr, u64>,
+    unchoked: HashSet<SocketAddr>,
+    round_at: Instant,
+}
+impl Default for UploadSlots {
+    fn default() -> Self {
+        Self::new(DEFAULT_UNCHOKE_SLOTS)
+    }
+}
+impl UploadSlots {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            choker: ChokeManager::new(slots),
+            capacity: slots + 1,
+            uploaded: HashMap::new(),
+            unchoked: HashSet::new(),
+            round_at: Instant::now(),
+        }
+    }
+    /// Registers interest from `addr`, unchoking it straight away if a slot
+    /// is free. Otherwise it waits for a later round.
+    pub fn interested(&mut self, addr: SocketAddr) {
+        self.uploaded.entry(addr).or_insert(0);
+        if self.unchoked.len() < self.capacity {
+            self.unchoked.insert(addr);
+        }
+    }
+    /// Releases the slot of a peer that lost interest or disconnected.
+    pub fn not_interested(&mut self, addr: SocketAddr) {
+        self.uploaded.remove(&addr);
+        self.unchoked.remove(&addr);
+    }
+    pub fn record_upload(&mut self, addr: SocketAddr, len: usize) {
+        if let Some(bytes) = self.uploaded.get_mut(&addr) {
+            *bytes += len as u64;
+        }
+    }
+    /// Whether `addr` may be served, running a new round first if one is due.
+    pub fn is_unchoked(&mut self, addr: SocketAddr) -> bool {
+        self.is_unchoked_at(Instant::now(), addr)
+    }
+    fn is_unchoked_at(&mut self, now: Instant, addr: SocketAddr) -> bool {
+        if now.duration_since(self.round_at) >= CHOKE_ROUND_INTERVAL {
+            self.round(now);
+        }
+        self.unchoked.contains(&addr)
+    }
+    fn round(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.round_at).as_secs_f64();
+        let rates = self
+            .uploaded
+            .iter()
+            .map(|(addr, bytes)| (*addr, *bytes as f64 / elapsed))
+            .collect();
+        self.unchoked = self.choker.round(&rates);
+        self.uploaded.values_mut().for_each(|bytes| *bytes = 0);
+        self.round_at = now;
+    }
+}
+
+/// Serves the pieces in `have` from `storage` to every peer that connects
+/// to `listener`, counting uploaded bytes in `stats`. Runs until dropped.
+pub async fn seed_pieces(
+    listener: &PeerListener,
+    storage: &Storage,
+    have: &Bitfield,
+    stats: &Mutex<StatsTracker>,
+) {
+    let slots = Mutex::new(UploadSlots::default());
+    listener
+        .incoming()
+        .for_each_concurrent(None, |mut peer| {
+            let slots = &slots;
+            async move {
+                stats.lock().unwrap().peer_connected();
+                let result = serve_peer(&mut peer, storage, have, slots, stats).await;
+                stats.lock().unwrap().peer_disconnected();
+                slots.lock().unwrap().not_interested(peer.addr());
+                if let Err(e) = result {
+                    debug!("Stopped serving {}: {}", peer.addr(), e);
+                }
+            }
+        })
+        .await
+}
+
+/// Serves blocks of the pieces in `have` to one peer until it disconnects,
+/// answering requests only while `slots` has it unchoked.
+pub async fn serve_peer(
+    peer: &mut PeerConnection,
+    storage: &Storage,
+    have: &Bitfield,
+    slots: &Mutex<UploadSlots>,
+    stats: &Mutex<StatsTracker>,
+) -> anyhow::Result<()> {
+    let addr = peer.addr();
+    peer.set_piece_count(have.len());
+    peer.send(Message::Bitfield(have.as_bytes().to_vec())).await?;
+    loop {
+        let message = future::timeout(SEED_IDLE_TIMEOUT, peer.read())
+            .await
+            .map_err(|_| anyhow::anyhow!("Peer went idle"))??;
+        match message {
+            Message::Interested => slots.lock().unwrap().interested(addr),
+            Message::NotInterested => slots.lock().unwrap().not_interested(addr),
+            Message::Request { index, begin, length } => {
+                // Clients close connections asking for more than a block
+                if length == 0 || length > BLOCK_SIZE || index as usize >= have.len() {
+                    anyhow::bail!("Peer requested invalid block {}:{}+{}", index, begin, length);
+                }
+                let block = if peer.am_choking() || !have.has(index as usize) {
+                    None
+                } else {
+                    storage.read_block(index as usize, begin, length)?
+                };
+                match block {
+                    Some(block) => {
+                        peer.send(Message::Piece { index, begin, block }).await?;
+                        slots.lock().unwrap().record_upload(addr, length as usize);
+                        stats.lock().unwrap().record_upload(length as usize);
+                    }
+                    None if peer.fast_enabled() => {
+                        peer.send(Message::RejectRequest { index, begin, length }).await?
+                    }
+                    // Without the fast extension unanswered requests are
+                    // simply dropped
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+        // Choke changes take effect as messages arrive. Interested peers
+        // keep requesting or sending keep-alives, so none waits for long.
+        if slots.lock().unwrap().is_unchoked(addr) {
+            peer.unchoke().await?;
+        } else {
+            peer.choke().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::{
+        download::tests::{temp_dir, test_content, test_opts},
+        peer_stream::{PeerStream, PeerStreamOpts},
+    };
+    use futures::future::{self, Either};
+    use std::fs;
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    #[test]
+    fn test_upload_slots() {
+        let mut slots = UploadSlots::new(1);
+        slots.interested(peer(1));
+        slots.interested(peer(2));
+        slots.interested(peer(3));
+        let start = slots.round_at;
+        assert!(slots.is_unchoked_at(start, peer(1)));
+        assert!(slots.is_unchoked_at(start, peer(2)));
+        assert!(!slots.is_unchoked_at(start, peer(3)));
+
+        // The next round keeps the fastest peer and picks an optimistic one
+        slots.record_upload(peer(2), 100_000);
+        let later = start + CHOKE_ROUND_INTERVAL;
+        assert!(slots.is_unchoked_at(later, peer(2)));
+        let optimistic = slots.choker.optimistic().unwrap();
+        assert!(optimistic == peer(1) || optimistic == peer(3));
+        assert_eq!(slots.unchoked.len(), 2);
+
+        slots.not_interested(peer(2));
+        assert!(!slots.is_unchoked_at(later, peer(2)));
+    }
+
+    #[async_std::test]
+    async fn test_serves_requested_block() {
+        let (info, content) = test_content();
+        let info_hash = [6u8; 20];
+        let dir = temp_dir("seed");
+        let storage = Storage::create(&info, &dir).unwrap();
+        for (index, piece) in content.chunks(info.piece_length as usize).enumerate() {
+            storage.write_piece(index, piece).unwrap();
+        }
+        let have = Bitfield::full(info.pieces.len());
+        let opts = PeerStreamOpts::new(info_hash, [2u8; 20]);
+        let listener = PeerListener::bind("127.0.0.1:0", opts).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Mutex::new(StatsTracker::new());
+        let seeder = Box::pin(seed_pieces(&listener, &storage, &have, &stats));
+
+        let leecher = Box::pin(async {
+            let stream = PeerStream::connect(addr, test_opts(info_hash)).await.unwrap();
+            let mut peer = PeerConnection::new(stream);
+            peer.set_piece_count(info.pieces.len());
+            peer.prepare_download().await.unwrap();
+            assert_eq!(peer.bitfield(), Some(&have));
+            let (index, begin, length) = (1, 100, 500);
+            peer.send(Message::Request { index, begin, length }).await.unwrap();
+            loop {
+                if let Message::Piece { index, begin, block } = peer.read().await.unwrap() {
+                    break (index, begin, block);
+                }
+            }
+        });
+        let (index, begin, block) = match future::select(seeder, leecher).await {
+            Either::Left(_) => panic!("Seeder stopped"),
+            Either::Right((piece, _)) => piece,
+        };
+        assert_eq!((index, begin), (1, 100));
+        let start = info.piece_length as usize + 100;
+        assert_eq!(block, content[start..start + 500]);
+        assert_eq!(stats.lock().unwrap().snapshot().bytes_uploaded, 500);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Window the download rate is averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// A point-in-time view of download progress.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    pub pieces_completed: usize,
+    pub total_pieces: usize,
+    pub active_peers: usize,
+    /// Bytes per second, averaged over the last few seconds.
+    pub download_rate: f64,
+    /// Bytes still missing from verified pieces, once the torrent's size is
+    /// known from its metadata.
+    pub bytes_left: Option<u64>,
+}
+
+/// Accumulates [`Stats`] while a download runs. Peer sessions share one
+/// behind a mutex and readers take copies with `snapshot`.
+#[derive(Debug)]
+pub struct StatsTracker {
+    stats: Stats,
+    started: Instant,
+    total_length: Option<u64>,
+    completed_bytes: u64,
+    // Blocks received within the last RATE_WINDOW, oldest first
+    recent: VecDeque<(Instant, u64)>,
+}
+impl Default for StatsTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl StatsTracker {
+    pub fn new() -> Self {
+        Self {
+            stats: Stats::default(),
+            started: Instant::now(),
+            total_length: None,
+            completed_bytes: 0,
+            recent: VecDeque::new(),
+        }
+    }
+    pub fn set_total_pieces(&mut self, total_pieces: usize) {
+        self.stats.total_pieces = total_pieces;
+    }
+    pub fn record_upload(&mut self, len: usize) {
+        self.stats.bytes_uploaded += len as u64;
+    }
+    pub fn set_total_length(&mut self, total_length: u64) {
+        self.total_length = Some(total_length);
+    }
+    pub fn record_block(&mut self, len: usize) {
+        self.record_block_at(Instant::now(), len);
+    }
+    fn record_block_at(&mut self, at: Instant, len: usize) {
+        self.stats.bytes_downloaded += len as u64;
+        self.recent.push_back((at, len as u64));
+        while self
+            .recent
+            .front()
+            .is_some_and(|(time, _)| at.duration_since(*time) > RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+    }
+    /// Counts pieces that were already on disk when the download started,
+    /// `bytes` long in total.
+    pub fn set_pieces_completed(&mut self, pieces_completed: usize, bytes: u64) {
+        self.stats.pieces_completed = pieces_completed;
+        self.completed_bytes = bytes;
+    }
+    pub fn piece_completed(&mut self, len: usize) {
+        self.stats.pieces_completed += 1;
+        self.completed_bytes += len as u64;
+    }
+    pub fn peer_connected(&mut self) {
+        self.stats.active_peers += 1;
+    }
+    pub fn peer_disconnected(&mut self) {
+        self.stats.active_peers = self.stats.active_peers.saturating_sub(1);
+    }
+    pub fn snapshot(&self) -> Stats {
+        self.snapshot_at(Instant::now())
+    }
+    fn snapshot_at(&self, now: Instant) -> Stats {
+        let recent_bytes = self
+            .recent
+            .iter()
+            .filter(|(time, _)| now.duration_since(*time) <= RATE_WINDOW)
+            .map(|(_, len)| len)
+            .sum::<u64>();
+        // Early on, average over the time actually spent downloading
+        let window = now
+            .duration_since(self.started)
+            .clamp(Duration::from_secs(1), RATE_WINDOW);
+        Stats {
+            download_rate: recent_bytes as f64 / window.as_secs_f64(),
+            bytes_left: self
+                .total_length
+                .map(|total| total.saturating_sub(self.completed_bytes)),
+            ..self.stats.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let mut tracker = StatsTracker::new();
+        tracker.set_total_pieces(4);
+        assert_eq!(tracker.snapshot().bytes_left, None);
+        tracker.set_total_length(4 * 32768);
+        tracker.peer_connected();
+        tracker.peer_connected();
+        for _ in 0..3 {
+            tracker.record_block(16384);
+            tracker.record_block(16384);
+            tracker.piece_completed(32768);
+        }
+        tracker.record_upload(1000);
+        tracker.peer_disconnected();
+        let stats = tracker.snapshot();
+        assert_eq!(stats.bytes_downloaded, 6 * 16384);
+        assert_eq!(stats.bytes_uploaded, 1000);
+        assert_eq!(stats.pieces_completed, 3);
+        assert_eq!(stats.total_pieces, 4);
+        assert_eq!(stats.active_peers, 1);
+        assert_eq!(stats.bytes_left, Some(32768));
+    }
+
+    #[test]
+    fn test_download_rate_window() {
+        let mut tracker = StatsTracker::new();
+        let start = tracker.started;
+        tracker.record_block_at(start, 1000);
+        tracker.record_block_at(start + Duration::from_secs(4), 4000);
+        assert_eq!(tracker.snapshot_at(start + Duration::from_secs(5)).download_rate, 1000.0);
+        // The first block has aged out of the window
+        let later = start + Duration::from_secs(8);
+        assert_eq!(tracker.snapshot_at(later).download_rate, 800.0);
+        assert_eq!(tracker.snapshot_at(later).bytes_downloaded, 5000);
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::peer::torrent::{FileLayout, Info};
+
+#[derive(Debug)]
+struct StorageFile {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+/// Maps the torrent's global byte range onto the files it is made of.
+#[derive(Debug)]
+pub struct Storage {
+    files: Vec<StorageFile>,
+    piece_length: u64,
+    total_length: u64,
+}
+impl Storage {
+    /// Creates (or opens) every file under `output_dir` at its final size.
+    /// Fails before touching the disk if any file would land outside
+    /// `output_dir`.
+    pub fn create(info: &Info, output_dir: &Path) -> anyhow::Result<Self> {
+        let layout = match &info.layout {
+            FileLayout::Single { length } => vec![(output_dir.join(&info.name), *length)],
+            FileLayout::Multi { files } => files
+                .iter()
+                .map(|file| (output_dir.join(&info.name).join(&file.path), file.length))
+                .collect(),
+        };
+        // Info parsing already rejects such names; this guards any Info
+        // built some other way
+        for (path, _) in &layout {
+            let escapes = path.strip_prefix(output_dir).map_or(true, |relative| {
+                relative.as_os_str().is_empty()
+                    || !relative.components().all(|c| matches!(c, Component::Normal(_)))
+            });
+            if escapes {
+                anyhow::bail!("File {} lies outside {}", path.display(), output_dir.display());
+            }
+        }
+        let mut files = Vec::with_capacity(layout.len());
+        let mut offset = 0;
+        for (path, length) in layout {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            file.set_len(length)?;
+            files.push(StorageFile {
+                path,
+                offset,
+                length,
+            });
+            offset += length;
+        }
+        Ok(Self {
+            files,
+            piece_length: info.piece_length,
+            total_length: offset,
+        })
+    }
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+    /// Length of piece `index`; only the final piece may be short.
+    pub fn piece_len(&self, index: usize) -> u64 {
+        let start = index as u64 * self.piece_length;
+        self.total_length
+            .saturating_sub(start)
+            .min(self.piece_length)
+    }
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        if data.len() as u64 != self.piece_len(index) {
+            anyhow::bail!("Piece {} has wrong length {}", index, data.len());
+        }
+        let start = index as u64 * self.piece_length;
+        let end = start + data.len() as u64;
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.offset >= end {
+                continue;
+            }
+            let write_start = start.max(file.offset);
+            let write_end = end.min(file_end);
+            let chunk = &data[(write_start - start) as usize..(write_end - start) as usize];
+            let mut handle = OpenOptions::new().write(true).open(&file.path)?;
+            handle.seek(SeekFrom::Start(write_start - file.offset))?;
+            handle.write_all(chunk)?;
+        }
+        Ok(())
+    }
+    /// Reads piece `index` back from disk, or `None` if a file has been
+    /// truncated since it was created and no longer covers the whole piece.
+    pub fn read_piece(&self, index: usize) -> anyhow::Result<Option<Vec<u8>>> {
+        let start = index as u64 * self.piece_length;
+        self.read_range(start, start + self.piece_len(index))
+    }
+    /// Reads `length` bytes at `begin` within piece `index`, or `None` if
+    /// that range lies outside the piece or is no longer on disk.
+    pub fn read_block(
+        &self,
+        index: usize,
+        begin: u32,
+        length: u32,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let end = begin as u64 + length as u64;
+        if index as u64 * self.piece_length >= self.total_length || end > self.piece_len(index) {
+            return Ok(None);
+        }
+        let start = index as u64 * self.piece_length + begin as u64;
+        self.read_range(start, start + length as u64)
+    }
+    /// Reads the torrent's bytes `start..end` from the files covering them,
+    /// or `None` if one of those files is missing or too short.
+    fn read_range(&self, start: u64, end: u64) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut data = Vec::with_capacity((end - start) as usize);
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.offset >= end {
+                continue;
+            }
+            let read_start = start.max(file.offset) - file.offset;
+            let read_len = end.min(file_end) - file.offset - read_start;
+            let mut handle = match fs::File::open(&file.path) {
+                Ok(handle) => handle,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if handle.metadata()?.len() < read_start + read_len {
+                return Ok(None);
+            }
+            handle.seek(SeekFrom::Start(read_start))?;
+            let mut chunk = vec![0u8; read_len as usize];
+            handle.read_exact(&mut chunk)?;
+            data.extend_from_slice(&chunk);
+        }
+        Ok(Some(data))
+    }
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|file| file.path.as_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::{download::tests::temp_dir, torrent::TorrentFile};
+
+    #[test]
+    fn test_write_piece_across_files() {
+        let dir = temp_dir("storage");
+        let info = Info {
+            name: "root".to_string(),
+            piece_length: 4,
+            pieces: vec![[0u8; 20]; 3],
+            layout: FileLayout::Multi {
+                files: vec![
+                    TorrentFile {
+                        path: PathBuf::from("a"),
+                        length: 3,
+                    },
+                    TorrentFile {
+                        path: PathBuf::from("b"),
+                        length: 7,
+                    },
+                ],
+            },
+        };
+        let storage = Storage::create(&info, &dir).unwrap();
+        assert_eq!(storage.total_length(), 10);
+        assert_eq!(storage.piece_len(0), 4);
+        assert_eq!(storage.piece_len(2), 2);
+        storage.write_piece(2, &[9, 10]).unwrap();
+        storage.write_piece(0, &[1, 2, 3, 4]).unwrap();
+        storage.write_piece(1, &[5, 6, 7, 8]).unwrap();
+        assert!(storage.write_piece(1, &[5, 6]).is_err());
+
+        assert_eq!(fs::read(dir.join("root").join("a")).unwrap(), vec![1, 2, 3]);
+        assert_eq!(
+            fs::read(dir.join("root").join("b")).unwrap(),
+            vec![4, 5, 6, 7, 8, 9, 10]
+        );
+        assert_eq!(storage.read_piece(0).unwrap(), Some(vec![1, 2, 3, 4]));
+        assert_eq!(storage.read_piece(2).unwrap(), Some(vec![9, 10]));
+        assert_eq!(storage.read_block(1, 1, 2).unwrap(), Some(vec![6, 7]));
+        // Spans the boundary between the two files
+        assert_eq!(storage.read_block(0, 2, 2).unwrap(), Some(vec![3, 4]));
+        assert_eq!(storage.read_block(2, 1, 2).unwrap(), None);
+        assert_eq!(storage.read_block(3, 0, 1).unwrap(), None);
+
+        // Truncating a file loses the pieces it no longer covers
+        OpenOptions::new()
+            .write(true)
+            .open(dir.join("root").join("b"))
+            .unwrap()
+            .set_len(5)
+            .unwrap();
+        assert_eq!(storage.read_piece(1).unwrap(), Some(vec![5, 6, 7, 8]));
+        assert_eq!(storage.read_piece(2).unwrap(), None);
+        // Blocks only need the bytes they cover to survive
+        assert_eq!(storage.read_block(2, 0, 1).unwrap(), None);
+        assert_eq!(storage.read_block(1, 0, 2).unwrap(), Some(vec![5, 6]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_paths_outside_output_dir() {
+        let dir = temp_dir("storage_escape");
+        let single = |name: &str| Info {
+            name: name.to_string(),
+            piece_length: 4,
+            pieces: vec![[0u8; 20]],
+            layout: FileLayout::Single { length: 4 },
+        };
+        let outside = std::env::temp_dir().join(format!("t_rip_escaped_{}", std::process::id()));
+        for info in [
+            single("../escaped"),
+            single(outside.to_str().unwrap()),
+            single(""),
+            Info {
+                layout: FileLayout::Multi {
+                    files: vec![TorrentFile {
+                        path: PathBuf::from("../../escaped"),
+                        length: 4,
+                    }],
+                },
+                ..single("root")
+            },
+        ] {
+            assert!(Storage::create(&info, &dir).is_err(), "accepted {:?}", info.name);
+        }
+        assert!(!dir.exists());
+        assert!(!outside.exists());
+        assert!(!std::env::temp_dir().join("escaped").exists());
+    }
+}
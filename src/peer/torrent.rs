@@ -0,0 +1,290 @@
+use std::{fs, path::Path, path::PathBuf};
+
+use sha1::{Digest, Sha1};
+
+use crate::peer::bencode::{raw_dict_value, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    pub path: PathBuf,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileLayout {
+    Single { length: u64 },
+    Multi { files: Vec<TorrentFile> },
+}
+
+/// The parsed info dictionary, shared by .torrent files and metadata fetched
+/// over ut_metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: Vec<[u8; 20]>,
+    pub layout: FileLayout,
+}
+impl Info {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Info::from_value(&Value::decode(bytes)?)
+    }
+    /// Size of the whole torrent: the single file's length, or the sum of
+    /// every file's.
+    pub fn total_length(&self) -> u64 {
+        match &self.layout {
+            FileLayout::Single { length } => *length,
+            FileLayout::Multi { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+    fn from_value(info: &Value) -> anyhow::Result<Self> {
+        let name = info
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow::anyhow!("Info dictionary missing name"))?
+            .to_string();
+        // The name becomes the file or directory created in the output dir
+        if !is_safe_component(&name) {
+            anyhow::bail!("Invalid torrent name {:?}", name);
+        }
+        let piece_length = info
+            .get("piece length")
+            .and_then(Value::as_int)
+            .and_then(|length| u64::try_from(length).ok())
+            .filter(|length| *length > 0)
+            .ok_or_else(|| anyhow::anyhow!("Info dictionary missing piece length"))?;
+        let piece_bytes = info
+            .get("pieces")
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| anyhow::anyhow!("Info dictionary missing pieces"))?;
+        if !piece_bytes.len().is_multiple_of(20) {
+            anyhow::bail!("Pieces length {} is not a multiple of 20", piece_bytes.len());
+        }
+        let pieces = piece_bytes
+            .chunks_exact(20)
+            .map(|chunk| {
+                let mut hash = [0u8; 20];
+                hash.copy_from_slice(chunk);
+                hash
+            })
+            .collect();
+        let layout = match (info.get("length"), info.get("files")) {
+            (Some(length), None) => FileLayout::Single {
+                length: non_negative(length)?,
+            },
+            (None, Some(files)) => {
+                let files = files
+                    .as_list()
+                    .ok_or_else(|| anyhow::anyhow!("Info files is not a list"))?
+                    .iter()
+                    .map(TorrentFile::from_value)
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                FileLayout::Multi { files }
+            }
+            _ => anyhow::bail!("Info dictionary must have exactly one of length or files"),
+        };
+        Ok(Self {
+            name,
+            piece_length,
+            pieces,
+            layout,
+        })
+    }
+}
+
+impl TorrentFile {
+    fn from_value(file: &Value) -> anyhow::Result<Self> {
+        let length = non_negative(
+            file.get("length")
+                .ok_or_else(|| anyhow::anyhow!("File missing length"))?,
+        )?;
+        let mut path = PathBuf::new();
+        let components = file
+            .get("path")
+            .and_then(Value::as_list)
+            .ok_or_else(|| anyhow::anyhow!("File missing path"))?;
+        for component in components {
+            let component = component
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("File path component is not a string"))?;
+            if !is_safe_component(component) {
+                anyhow::bail!("Invalid file path component {:?}", component);
+            }
+            path.push(component);
+        }
+        if path.as_os_str().is_empty() {
+            anyhow::bail!("File path is empty");
+        }
+        Ok(Self { path, length })
+    }
+}
+
+/// Whether `component` names a single entry, so joining it onto a
+/// directory can't escape that directory.
+fn is_safe_component(component: &str) -> bool {
+    !(component.is_empty()
+        || component == "."
+        || component == ".."
+        || component.contains(['/', '\\']))
+}
+
+fn non_negative(value: &Value) -> anyhow::Result<u64> {
+    value
+        .as_int()
+        .and_then(|length| u64::try_from(length).ok())
+        .ok_or_else(|| anyhow::anyhow!("Expected a non-negative integer"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Torrent {
+    pub announce: Option<String>,
+    pub announce_list: Vec<Vec<String>>,
+    pub info: Info,
+    pub info_hash: [u8; 20],
+}
+impl Torrent {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Torrent::from_bytes(&fs::read(path)?)
+    }
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let value = Value::decode(bytes)?;
+        let (Some(info_bytes), Some(info)) = (raw_dict_value(bytes, "info")?, value.get("info")) else {
+            anyhow::bail!("Torrent missing info dictionary");
+        };
+        let info = Info::from_value(info)?;
+        let announce = value
+            .get("announce")
+            .and_then(Value::as_str)
+            .map(String::from);
+        let announce_list = value
+            .get("announce-list")
+            .and_then(Value::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(Value::as_list)
+            .map(|tier| {
+                tier.iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect()
+            })
+            .collect();
+        Ok(Self {
+            announce,
+            announce_list,
+            info,
+            info_hash: Sha1::digest(info_bytes).into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single_file_torrent() -> Vec<u8> {
+        let mut bytes = b"d8:announce37:udp://tracker.example.com:80/announce13:announce-listll37:udp://tracker.example.com:80/announceel25:http://other.example.com/ee4:infod6:lengthi40000e4:name8:file.bin12:piece lengthi32768e6:pieces40:".to_vec();
+        bytes.extend_from_slice(&[1u8; 20]);
+        bytes.extend_from_slice(&[2u8; 20]);
+        bytes.extend_from_slice(b"ee");
+        bytes
+    }
+
+    #[test]
+    fn test_single_file_torrent() {
+        let bytes = single_file_torrent();
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            torrent.announce.as_deref(),
+            Some("udp://tracker.example.com:80/announce")
+        );
+        assert_eq!(torrent.announce_list.len(), 2);
+        assert_eq!(torrent.announce_list[1], vec!["http://other.example.com/"]);
+        assert_eq!(torrent.info.name, "file.bin");
+        assert_eq!(torrent.info.piece_length, 32768);
+        assert_eq!(torrent.info.pieces, vec![[1u8; 20], [2u8; 20]]);
+        assert_eq!(torrent.info.layout, FileLayout::Single { length: 40000 });
+
+        let info_bytes = raw_dict_value(&bytes, "info").unwrap().unwrap();
+        let expected: [u8; 20] = Sha1::digest(info_bytes).into();
+        assert_eq!(torrent.info_hash, expected);
+    }
+
+    #[test]
+    fn test_multi_file_info() {
+        let mut bytes = b"d5:filesld6:lengthi10e4:pathl3:dir5:a.txteed6:lengthi20e4:pathl5:b.txteee4:name4:root12:piece lengthi16384e6:pieces20:".to_vec();
+        bytes.extend_from_slice(&[3u8; 20]);
+        bytes.push(b'e');
+        let info = Info::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            info.layout,
+            FileLayout::Multi {
+                files: vec![
+                    TorrentFile {
+                        path: PathBuf::from("dir").join("a.txt"),
+                        length: 10,
+                    },
+                    TorrentFile {
+                        path: PathBuf::from("b.txt"),
+                        length: 20,
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_total_length() {
+        let mut bytes = b"d5:filesld6:lengthi10e4:pathl3:dir5:a.txteed6:lengthi20e4:pathl5:b.txteed6:lengthi4000000000e4:pathl5:c.isoeee4:name4:root12:piece lengthi16384e6:pieces20:".to_vec();
+        bytes.extend_from_slice(&[3u8; 20]);
+        bytes.push(b'e');
+        let info = Info::from_bytes(&bytes).unwrap();
+        assert_eq!(info.total_length(), 4_000_000_030);
+        let single = Info {
+            layout: FileLayout::Single { length: 77 },
+            ..info
+        };
+        assert_eq!(single.total_length(), 77);
+    }
+
+    #[test]
+    fn test_rejects_path_traversal() {
+        let mut bytes = b"d5:filesld6:lengthi10e4:pathl2:..6:escapeeee4:name4:root12:piece lengthi16384e6:pieces20:".to_vec();
+        bytes.extend_from_slice(&[3u8; 20]);
+        bytes.push(b'e');
+        assert!(Info::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_rejects_unsafe_name() {
+        let info = |name: &str| {
+            let mut bytes = format!(
+                "d6:lengthi10e4:name{}:{}12:piece lengthi16384e6:pieces20:",
+                name.len(),
+                name
+            )
+            .into_bytes();
+            bytes.extend_from_slice(&[3u8; 20]);
+            bytes.push(b'e');
+            Info::from_bytes(&bytes)
+        };
+        assert_eq!(info("file.bin").unwrap().name, "file.bin");
+        for name in ["", ".", "..", "../../x", "/etc", "a\\b"] {
+            assert!(info(name).is_err(), "accepted name {:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_from_file() {
+        let path = std::env::temp_dir().join(format!("t_rip_test_{}.torrent", std::process::id()));
+        fs::write(&path, single_file_torrent()).unwrap();
+        let torrent = Torrent::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(torrent.info.name, "file.bin");
+    }
+
+    #[test]
+    fn test_missing_info() {
+        assert!(Torrent::from_bytes(b"d8:announce3:urle").is_err());
+    }
+}
@@ -1,95 +1,287 @@
-use std::{net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs}, time::Duration};
+use std::{
+    cmp::min,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
 
-use anyhow::Context;
-use async_std::{net::UdpSocket, future};
+use async_std::{
+    future,
+    net::{ToSocketAddrs, UdpSocket},
+};
 use byteorder::{BigEndian, ByteOrder};
-use rand::Rng;
 use url::Url;
 
+#[derive(thiserror::Error, Debug)]
+pub enum TrackerError {
+    #[error("Tracker timed out after {attempts} attempts")]
+    Timeout { attempts: u32 },
+    #[error("Failed to resolve tracker {0}")]
+    Dns(String),
+    #[error("Tracker protocol violation: {0}")]
+    Protocol(String),
+    #[error("Malformed tracker response: {0}")]
+    BadResponse(String),
+    #[error("Tracker socket error")]
+    Io(#[from] std::io::Error),
+}
+
+/// Retransmission schedule for UDP tracker requests (BEP 15). Attempt `n`
+/// waits `base_timeout * 2^n` for a response, up to `max_retries` retries,
+/// and the whole exchange is abandoned once `deadline` has elapsed.
+#[derive(Debug, Clone, Copy)]
+pub struct RetransmitPolicy {
+    pub base_timeout: Duration,
+    pub max_retries: u32,
+    pub deadline: Duration,
+}
+impl Default for RetransmitPolicy {
+    fn default() -> Self {
+        Self {
+            base_timeout: Duration::from_secs(15),
+            max_retries: 8,
+            deadline: Duration::from_secs(60),
+        }
+    }
+}
+impl RetransmitPolicy {
+    fn timeout_for(&self, attempt: u32) -> Duration {
+        self.base_timeout.saturating_mul(2u32.saturating_pow(attempt))
+    }
+}
+
+/// How long a connection id handed out by a tracker stays valid (BEP 15).
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct TrackerConnection {
     pub addr: Url,
+    /// `addr` resolved once up front and reused for every request.
+    pub socket_addr: SocketAddr,
     pub connection_id: i64,
+    pub connection_expires: Instant,
+    pub retransmit: RetransmitPolicy,
+    /// Local address for tracker sockets. `None` binds an ephemeral port on
+    /// all interfaces of the tracker's address family.
+    pub bind_addr: Option<SocketAddr>,
 }
 
 impl TrackerConnection {
-    pub async fn new(addr: Url) -> anyhow::Result<Self> {
-        let connection_id = TrackerConnection::connect(addr.clone()).await?;
+    pub async fn new(addr: Url) -> Result<Self, TrackerError> {
+        TrackerConnection::with_retransmit(addr, RetransmitPolicy::default()).await
+    }
+    pub async fn with_retransmit(addr: Url, retransmit: RetransmitPolicy) -> Result<Self, TrackerError> {
+        TrackerConnection::open(addr, retransmit, None).await
+    }
+    /// Like `with_retransmit`, but sends every request from `bind_addr`.
+    /// Announces still advertise the descriptor's port, which is where we
+    /// accept peers. Only one connection at a time can use a fixed port.
+    pub async fn with_bind_addr(
+        addr: Url,
+        retransmit: RetransmitPolicy,
+        bind_addr: SocketAddr,
+    ) -> Result<Self, TrackerError> {
+        TrackerConnection::open(addr, retransmit, Some(bind_addr)).await
+    }
+    async fn open(
+        addr: Url,
+        retransmit: RetransmitPolicy,
+        bind_addr: Option<SocketAddr>,
+    ) -> Result<Self, TrackerError> {
+        let socket_addr = resolve(&addr).await?;
+        let local = bind_addr.unwrap_or_else(|| bind_addr_for(socket_addr));
+        let connection_id = TrackerConnection::connect(socket_addr, local, &retransmit).await?;
         Ok(Self {
             addr,
+            socket_addr,
             connection_id,
+            connection_expires: Instant::now() + CONNECTION_ID_LIFETIME,
+            retransmit,
+            bind_addr,
         })
     }
-    pub async fn connect(addr: Url) -> anyhow::Result<i64> {
-        let host_port = format!("{}:{}", addr.host_str().unwrap(), addr.port().unwrap_or(80));
-        let s_addr = host_port.to_socket_addrs()?.last().unwrap();
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .context("Failed to establish UDP Socket")?;
-        let connection_id = TrackerConnection::handshake(&socket, s_addr).await?;
+    fn local_addr(&self) -> SocketAddr {
+        self.bind_addr.unwrap_or_else(|| bind_addr_for(self.socket_addr))
+    }
+    /// Re-handshakes with the tracker if the cached connection id has expired.
+    pub async fn refresh_connection_id(&mut self) -> Result<i64, TrackerError> {
+        if Instant::now() >= self.connection_expires {
+            self.connection_id =
+                TrackerConnection::connect(self.socket_addr, self.local_addr(), &self.retransmit).await?;
+            self.connection_expires = Instant::now() + CONNECTION_ID_LIFETIME;
+        }
+        Ok(self.connection_id)
+    }
+    pub async fn connect(
+        addr: SocketAddr,
+        bind_addr: SocketAddr,
+        retransmit: &RetransmitPolicy,
+    ) -> Result<i64, TrackerError> {
+        let socket = UdpSocket::bind(bind_addr).await?;
+        let connection_id = TrackerConnection::handshake(&socket, addr, retransmit).await?;
         Ok(connection_id)
     }
-    async fn handshake(socket: &UdpSocket, addr: SocketAddr) -> anyhow::Result<i64> {
-        let request = ConnectRequest::new();
-        let bytes_sent = socket.send_to(&request.to_bytes(), &addr).await?;
-        if bytes_sent != CONNECT_REQUEST_SIZE {
-            anyhow::bail!("Unable to send connect request");
+    async fn handshake(
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        retransmit: &RetransmitPolicy,
+    ) -> Result<i64, TrackerError> {
+        let request = ConnectRequest::new().to_bytes();
+        let response_len = CONNECT_RESPONSE_SIZE;
+        let bytes_recv =
+            send_with_retransmit(socket, addr, &request, 0, response_len, retransmit).await?;
+        if bytes_recv.len() != CONNECT_RESPONSE_SIZE {
+            return Err(TrackerError::BadResponse(format!(
+                "connect response of {} bytes",
+                bytes_recv.len()
+            )));
         }
-        let mut bytes_recv = [0u8; CONNECT_RESPONSE_SIZE];
-        let duration = Duration::from_secs(3);
-        let conn_result = future::timeout(duration, async {
+        Ok(ConnectResponse::from_bytes(&bytes_recv).connection_id)
+    }
+    pub async fn announce(
+        &mut self,
+        descriptor: AnnounceRequestDescriptor,
+    ) -> Result<AnnounceResult, TrackerError> {
+        let connection_id = self.refresh_connection_id().await?;
+        let s_addr = self.socket_addr;
+        let socket = UdpSocket::bind(self.local_addr()).await?;
+        let buffer_len = announce_buffer_len(descriptor.num_want, s_addr.is_ipv6());
+        let request = AnnounceRequest::new(connection_id, descriptor).to_bytes();
+        let bytes_recv =
+            send_with_retransmit(&socket, s_addr, &request, 1, buffer_len, &self.retransmit).await?;
+        // Filling the spare byte means the datagram was cut short, so the
+        // tracker sent more peers than we asked for. Keep the ones we wanted.
+        let n = bytes_recv.len().min(buffer_len - 1);
+        let response = AnnounceResponse::from_bytes(&bytes_recv, n, s_addr.is_ipv6())?;
+        Ok(AnnounceResult {
+            peers: response.peers,
+            interval: response.interval,
+            leechers: response.leechers,
+            seeders: response.seeders,
+        })
+    }
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        if info_hashes.len() > MAX_SCRAPE_HASHES {
+            return Err(TrackerError::Protocol(format!(
+                "cannot scrape more than {} info hashes at once",
+                MAX_SCRAPE_HASHES
+            )));
+        }
+        let connection_id = self.refresh_connection_id().await?;
+        let s_addr = self.socket_addr;
+        let request = ScrapeRequest::new(connection_id, info_hashes).to_bytes();
+        let socket = UdpSocket::bind(self.local_addr()).await?;
+        let buffer_len = SCRAPE_HEADER_BYTES + SCRAPE_STATS_BYTES * info_hashes.len();
+        let bytes_recv =
+            send_with_retransmit(&socket, s_addr, &request, 2, buffer_len, &self.retransmit).await?;
+        let response = ScrapeResponse::from_bytes(&bytes_recv)?;
+        if response.stats.len() != info_hashes.len() {
+            return Err(TrackerError::BadResponse(
+                "scrape response does not cover every info hash".to_string(),
+            ));
+        }
+        Ok(response.stats)
+    }
+}
+
+/// Resolves a tracker url, preferring an IPv4 address when the host has
+/// both. Requests are sent from a socket bound to the same family as the
+/// chosen address (see `bind_addr_for`). The lookup runs off the executor
+/// so a stalled resolver can be abandoned with a timeout.
+async fn resolve(addr: &Url) -> Result<SocketAddr, TrackerError> {
+    let host = addr
+        .host_str()
+        .ok_or_else(|| TrackerError::Dns(addr.to_string()))?;
+    let host_port = format!("{}:{}", host, addr.port().unwrap_or(80));
+    let resolved = host_port
+        .to_socket_addrs()
+        .await
+        .map_err(|_| TrackerError::Dns(host_port.clone()))?;
+    preferred_addr(resolved).ok_or(TrackerError::Dns(host_port))
+}
+
+fn preferred_addr(addrs: impl IntoIterator<Item = SocketAddr>) -> Option<SocketAddr> {
+    let addrs = addrs.into_iter().collect::<Vec<_>>();
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or(addrs.first())
+        .copied()
+}
+
+/// Unspecified local address in the same family as `remote`.
+fn bind_addr_for(remote: SocketAddr) -> SocketAddr {
+    match remote {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Sends `request` to `addr` and waits for a reply carrying the request's
+/// transaction id, retransmitting on the schedule given by `retransmit`.
+/// Packets from other hosts and stale replies to earlier requests are
+/// skipped. Returns the number of bytes received.
+async fn transact(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    request: &[u8],
+    response: &mut [u8],
+    retransmit: &RetransmitPolicy,
+) -> Result<usize, TrackerError> {
+    let started = Instant::now();
+    let mut attempts = 0;
+    while attempts <= retransmit.max_retries {
+        let remaining = retransmit.deadline.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            break;
+        }
+        let timeout = min(retransmit.timeout_for(attempts), remaining);
+        attempts += 1;
+        let bytes_sent = socket.send_to(request, &addr).await?;
+        if bytes_sent != request.len() {
+            return Err(TrackerError::Protocol("tracker request was truncated".to_string()));
+        }
+        let received = future::timeout(timeout, async {
             loop {
-                let (n, tracker) = socket.recv_from(&mut bytes_recv).await?;
-                if tracker != addr {
-                    continue;
-                } else if n != CONNECT_RESPONSE_SIZE {
-                    anyhow::bail!("Unable to read connect response");
+                let (n, tracker) = socket.recv_from(response).await?;
+                // Every request carries its transaction id at 12..16 and
+                // every response echoes it back at 4..8
+                if tracker == addr && n >= 8 && response[4..8] == request[12..16] {
+                    return Ok::<_, TrackerError>(n);
                 }
-                break;
             }
-            Ok(())
-        }).await?;
-        if conn_result.is_err() {
-            return Err(conn_result.unwrap_err().into());
-        }
-        let response = ConnectResponse::from_bytes(&bytes_recv);
-        if response.transaction_id != request.transaction_id {
-            anyhow::bail!("Mismatched transaction ids");
-        }
-        Ok(response.connection_id)
-    }
-    pub async fn announce(&self, descriptor: AnnounceRequestDescriptor) -> anyhow::Result<Vec<SocketAddr>> {
-        let host_port = format!("{}:{}", self.addr.host_str().unwrap(), self.addr.port().unwrap_or(80));
-        let s_addr = host_port.to_socket_addrs()?.last().unwrap();
-        let request = AnnounceRequest::new(descriptor);
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .context("Failed to establish UDP Socket")?;
-        let bytes_sent = socket.send_to(&request.to_bytes(), &s_addr).await?;
-        if bytes_sent != ANNOUNCE_REQUEST_BYTES {
-            anyhow::bail!("Unable to send connect request");
-        }
-        let mut bytes_recv = [0u8; 4000];
-        let duration = Duration::from_secs(3);
-        let conn_result: anyhow::Result<usize> = future::timeout(duration, async {
-            Ok(loop {
-                let (n, tracker) = socket.recv_from(&mut bytes_recv).await?;
-                if tracker != s_addr {
-                    continue;
-                }
-                break n;
-            })
-        }).await?;
-        if conn_result.is_err() {
-            return Err(conn_result.unwrap_err().into());
-        }
-        let response = AnnounceResponse::from_bytes(&bytes_recv, conn_result.unwrap());
-        if response.transaction_id != request.transaction_id {
-            anyhow::bail!("Mismatched transaction ids");
+        })
+        .await;
+        if let Ok(result) = received {
+            return result;
         }
-        Ok(response.peers)
+    }
+    Err(TrackerError::Timeout { attempts })
+}
 
+/// Sends `request` to `addr` with [`transact`] and checks the reply carries
+/// `expected_action`, returning up to `response_len` bytes of it. Connect,
+/// announce and scrape all go through here so they retry and validate
+/// replies the same way.
+async fn send_with_retransmit(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    request: &[u8],
+    expected_action: u32,
+    response_len: usize,
+    retransmit: &RetransmitPolicy,
+) -> Result<Vec<u8>, TrackerError> {
+    let mut response = vec![0u8; response_len];
+    let n = transact(socket, addr, request, &mut response, retransmit).await?;
+    response.truncate(n);
+    // transact only returns replies long enough to hold a transaction id
+    let action = BigEndian::read_u32(&response[0..4]);
+    if action != expected_action {
+        return Err(TrackerError::Protocol(format!(
+            "unexpected response action {}, expected {}",
+            action, expected_action
+        )));
     }
+    Ok(response)
 }
 
 #[derive(Debug)]
@@ -112,7 +304,7 @@ impl ConnectRequest {
         }
     }
     fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![0u8; 16];
+        let mut bytes = vec![0u8; CONNECT_REQUEST_SIZE];
         BigEndian::write_i64(&mut bytes[0..8], self.protocol_id);
         BigEndian::write_u32(&mut bytes[8..12], self.action);
         BigEndian::write_u32(&mut bytes[12..16], self.transaction_id);
@@ -122,29 +314,26 @@ impl ConnectRequest {
 
 #[derive(Debug)]
 struct ConnectResponse {
-    action: u32,
-    transaction_id: u32,
     connection_id: i64,
 }
 impl ConnectResponse {
+    /// Parses a connect response whose action and transaction id were
+    /// already checked by `send_with_retransmit`.
     fn from_bytes(bytes: &[u8]) -> Self {
-        let action = BigEndian::read_u32(&bytes[0..4]);
-        let transaction_id = BigEndian::read_u32(&bytes[4..8]);
-        let connection_id = BigEndian::read_i64(&bytes[8..16]);
         Self {
-            action,
-            transaction_id,
-            connection_id,
+            connection_id: BigEndian::read_i64(&bytes[8..16]),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// The event field of an announce, with its BEP 15 wire value.
+#[derive(Debug, Copy, Clone, Default)]
 pub enum AnnounceEvent {
+    #[default]
     None = 0,
-    Completed,
-    Started,
-    Stopped,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
 }
 
 #[derive(Debug)]
@@ -158,28 +347,47 @@ struct AnnounceRequest {
     left: u64,
     uploaded: u64,
     event: AnnounceEvent,
+    // Always 0 so the tracker uses the packet's source address. The field
+    // only has room for an IPv4 address, and BEP 15 drops it entirely for
+    // announces to IPv6 trackers, where the source address is all there is
     ip_address: u32,
     key: u32,
     num_want: i32,
     port: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct AnnounceRequestDescriptor {
-    pub connection_id: i64,
     pub peer_id: [u8; 20],
     pub info_hash: [u8; 20],
     pub downloaded: u64,
     pub left: u64,
     pub uploaded: u64,
     pub event: AnnounceEvent,
+    pub num_want: i32,
+    pub port: u16,
+}
+impl Default for AnnounceRequestDescriptor {
+    fn default() -> Self {
+        Self {
+            peer_id: [0u8; 20],
+            info_hash: [0u8; 20],
+            downloaded: 0,
+            left: 0,
+            uploaded: 0,
+            event: AnnounceEvent::None,
+            // -1 lets the tracker pick how many peers to return
+            num_want: -1,
+            port: 6881,
+        }
+    }
 }
 
 const ANNOUNCE_REQUEST_BYTES: usize = 98;
 impl AnnounceRequest {
-    fn new(descriptor: AnnounceRequestDescriptor) -> Self {
+    fn new(connection_id: i64, descriptor: AnnounceRequestDescriptor) -> Self {
         Self {
-            connection_id: descriptor.connection_id,
+            connection_id,
             action: 1,
             transaction_id: rand::random(),
             info_hash: descriptor.info_hash,
@@ -190,8 +398,8 @@ impl AnnounceRequest {
             event: descriptor.event,
             ip_address: 0,
             key: rand::random(),
-            num_want: -1,
-            port: 6881,
+            num_want: descriptor.num_want,
+            port: descriptor.port,
         }
     }
     fn to_bytes(&self) -> Vec<u8> {
@@ -213,40 +421,702 @@ impl AnnounceRequest {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceResult {
+    pub peers: Vec<SocketAddr>,
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+}
+
+const ANNOUNCE_RESPONSE_HEADER_BYTES: usize = 20;
+const IPV4_PEER_BYTES: usize = 6;
+const IPV6_PEER_BYTES: usize = 18;
+/// Largest payload a UDP datagram can carry.
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// Buffer size for an announce response with up to `num_want` peers, plus a
+/// spare byte that only gets filled if the tracker sent more. When the
+/// tracker picks the count, any datagram fits.
+fn announce_buffer_len(num_want: i32, ipv6: bool) -> usize {
+    let entry_size = if ipv6 { IPV6_PEER_BYTES } else { IPV4_PEER_BYTES };
+    match usize::try_from(num_want) {
+        Ok(num_want) => {
+            let len = ANNOUNCE_RESPONSE_HEADER_BYTES + num_want.saturating_mul(entry_size);
+            len.min(MAX_UDP_PAYLOAD) + 1
+        }
+        Err(_) => MAX_UDP_PAYLOAD + 1,
+    }
+}
+
+/// Decodes a compact peer list: 6-byte IPv4 or 18-byte IPv6 entries, each
+/// an address followed by a big-endian port, as sent by trackers and in
+/// ut_pex messages. Returns `None` if `bytes` isn't a whole number of
+/// entries.
+pub(crate) fn decode_compact_peers(bytes: &[u8], ipv6: bool) -> Option<Vec<SocketAddr>> {
+    let entry_size = if ipv6 { IPV6_PEER_BYTES } else { IPV4_PEER_BYTES };
+    if !bytes.len().is_multiple_of(entry_size) {
+        return None;
+    }
+    let peers = bytes
+        .chunks(entry_size)
+        .map(|entry| {
+            let (ip, port) = entry.split_at(entry_size - 2);
+            let ip = if ipv6 {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(ip);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            } else {
+                IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+            };
+            SocketAddr::new(ip, BigEndian::read_u16(port))
+        })
+        .collect();
+    Some(peers)
+}
+
 #[derive(Debug)]
 struct AnnounceResponse {
-    action: u32,
-    transaction_id: u32,
     interval: u32,
     leechers: u32,
     seeders: u32,
     peers: Vec<SocketAddr>,
 }
 impl AnnounceResponse {
-    fn from_bytes(bytes: &[u8], length: usize) -> Self {
-        let action = BigEndian::read_u32(&bytes[0..4]);
-        let transaction_id = BigEndian::read_u32(&bytes[4..8]);
+    /// Parses an announce response. Trackers reply with 18-byte IPv6 peer
+    /// entries when the request arrived over IPv6 and 6-byte IPv4 entries
+    /// otherwise, so `ipv6` should reflect the family of the tracker address.
+    fn from_bytes(bytes: &[u8], length: usize, ipv6: bool) -> Result<Self, TrackerError> {
+        if length > bytes.len() {
+            return Err(TrackerError::BadResponse(format!(
+                "announce response length {} exceeds buffer",
+                length
+            )));
+        } else if length < ANNOUNCE_RESPONSE_HEADER_BYTES {
+            return Err(TrackerError::BadResponse(format!(
+                "announce response too short ({} bytes)",
+                length
+            )));
+        }
         let interval = BigEndian::read_u32(&bytes[8..12]);
         let leechers = BigEndian::read_u32(&bytes[12..16]);
         let seeders = BigEndian::read_u32(&bytes[16..20]);
-        let peer_list = &bytes[20..length];
-        if peer_list.len() % 6 != 0 {
-            panic!("Invalid peer list size");
-        }
-        let mut peers = Vec::new();
-        for address in peer_list.chunks(6) {
-            let ip = Ipv4Addr::new(address[0], address[1], address[2], address[3]);
-            let port = BigEndian::read_u16(&address[4..6]);
-            let peer = SocketAddr::new(IpAddr::V4(ip), port);
-            peers.push(peer);
-        }
-        Self {
-            action,
-            transaction_id,
+        let peer_list = &bytes[ANNOUNCE_RESPONSE_HEADER_BYTES..length];
+        let peers = decode_compact_peers(peer_list, ipv6).ok_or_else(|| {
+            TrackerError::BadResponse(format!("invalid peer list size {}", peer_list.len()))
+        })?;
+        Ok(Self {
             interval,
             leechers,
             seeders,
             peers,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ScrapeRequest {
+    connection_id: i64,
+    action: u32,
+    transaction_id: u32,
+    info_hashes: Vec<[u8; 20]>,
+}
+
+const MAX_SCRAPE_HASHES: usize = 74;
+const SCRAPE_HEADER_BYTES: usize = 8;
+const SCRAPE_STATS_BYTES: usize = 12;
+impl ScrapeRequest {
+    fn new(connection_id: i64, info_hashes: &[[u8; 20]]) -> Self {
+        Self {
+            connection_id,
+            action: 2,
+            transaction_id: rand::random(),
+            info_hashes: info_hashes.to_vec(),
+        }
+    }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; 16 + 20 * self.info_hashes.len()];
+        BigEndian::write_i64(&mut bytes[0..8], self.connection_id);
+        BigEndian::write_u32(&mut bytes[8..12], self.action);
+        BigEndian::write_u32(&mut bytes[12..16], self.transaction_id);
+        for (hash, chunk) in self.info_hashes.iter().zip(bytes[16..].chunks_mut(20)) {
+            chunk.copy_from_slice(hash);
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+#[derive(Debug)]
+struct ScrapeResponse {
+    stats: Vec<ScrapeStats>,
+}
+impl ScrapeResponse {
+    /// Parses a scrape response whose action and transaction id were
+    /// already checked by `send_with_retransmit`.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, TrackerError> {
+        if bytes.len() < SCRAPE_HEADER_BYTES {
+            return Err(TrackerError::BadResponse("scrape response too short".to_string()));
+        }
+        let stats = bytes[SCRAPE_HEADER_BYTES..]
+            .chunks_exact(SCRAPE_STATS_BYTES)
+            .map(|chunk| ScrapeStats {
+                seeders: BigEndian::read_u32(&chunk[0..4]),
+                completed: BigEndian::read_u32(&chunk[4..8]),
+                leechers: BigEndian::read_u32(&chunk[8..12]),
+            })
+            .collect();
+        Ok(Self { stats })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use futures::channel::mpsc::{self, UnboundedReceiver};
+
+    fn test_policy() -> RetransmitPolicy {
+        RetransmitPolicy {
+            base_timeout: Duration::from_millis(100),
+            max_retries: 8,
+            deadline: Duration::from_secs(5),
         }
     }
+
+    /// Answers connect requests with an incrementing connection id, announces
+    /// with a single peer and scrape requests with fixed stats for every hash.
+    pub(crate) async fn spawn_mock_tracker() -> SocketAddr {
+        spawn_recording_mock_tracker(60).await.0
+    }
+
+    /// Like `spawn_mock_tracker`, but asks for a re-announce every `interval`
+    /// seconds and forwards every announce request it receives.
+    pub(crate) async fn spawn_recording_mock_tracker(
+        interval: u32,
+    ) -> (SocketAddr, UnboundedReceiver<Vec<u8>>) {
+        spawn_delayed_mock_tracker(interval, Duration::ZERO).await
+    }
+
+    /// Like `spawn_recording_mock_tracker`, but waits `delay` before
+    /// answering each announce.
+    pub(crate) async fn spawn_delayed_mock_tracker(
+        interval: u32,
+        delay: Duration,
+    ) -> (SocketAddr, UnboundedReceiver<Vec<u8>>) {
+        let (announces, received) = mpsc::unbounded();
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut next_id: i64 = 1;
+            let mut buf = [0u8; 1500];
+            loop {
+                let (n, client) = tracker.recv_from(&mut buf).await.unwrap();
+                let action = BigEndian::read_u32(&buf[8..12]);
+                let mut response = vec![0u8; 8];
+                BigEndian::write_u32(&mut response[0..4], action);
+                response[4..8].copy_from_slice(&buf[12..16]);
+                match action {
+                    0 => {
+                        response.extend_from_slice(&next_id.to_be_bytes());
+                        next_id += 1;
+                    }
+                    1 => {
+                        let _ = announces.unbounded_send(buf[..n].to_vec());
+                        async_std::task::sleep(delay).await;
+                        // interval, leechers, seeders, then a single peer
+                        response.extend_from_slice(&interval.to_be_bytes());
+                        response.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 3]);
+                        response.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1]);
+                    }
+                    2 => {
+                        for _ in buf[16..n].chunks(20) {
+                            response.extend_from_slice(&[0, 0, 0, 10, 0, 0, 0, 20, 0, 0, 0, 30]);
+                        }
+                    }
+                    _ => continue,
+                }
+                tracker.send_to(&response, client).await.unwrap();
+            }
+        });
+        (tracker_addr, received)
+    }
+
+    async fn connect_mock_tracker() -> TrackerConnection {
+        let tracker_addr = spawn_mock_tracker().await;
+        let url = Url::parse(&format!("udp://{}", tracker_addr)).unwrap();
+        TrackerConnection::with_retransmit(url, test_policy())
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_handshake_retransmits_after_dropped_packet() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        let mock = async_std::task::spawn(async move {
+            let mut buf = [0u8; CONNECT_REQUEST_SIZE];
+            // Drop the first request on the floor
+            tracker.recv_from(&mut buf).await.unwrap();
+            let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut response = [0u8; CONNECT_RESPONSE_SIZE];
+            BigEndian::write_u32(&mut response[0..4], 0);
+            response[4..8].copy_from_slice(&buf[12..16]);
+            BigEndian::write_i64(&mut response[8..16], 42);
+            tracker.send_to(&response, client).await.unwrap();
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let connection_id = TrackerConnection::handshake(&socket, tracker_addr, &test_policy())
+            .await
+            .unwrap();
+        mock.await;
+        assert_eq!(connection_id, 42);
+    }
+
+    #[async_std::test]
+    async fn test_handshake_times_out_with_attempt_count() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let policy = RetransmitPolicy {
+            base_timeout: Duration::from_millis(10),
+            max_retries: 2,
+            deadline: Duration::from_secs(5),
+        };
+        let result = TrackerConnection::handshake(&socket, tracker_addr, &policy).await;
+        assert!(matches!(result, Err(TrackerError::Timeout { attempts: 3 })));
+    }
+
+    #[async_std::test]
+    async fn test_send_with_retransmit() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut buf = [0u8; 16];
+            // Drop the first request, answer the retry, then answer the
+            // next request with the wrong action
+            tracker.recv_from(&mut buf).await.unwrap();
+            for action in [7u32, 3] {
+                let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+                let mut response = vec![0u8; 12];
+                BigEndian::write_u32(&mut response[0..4], action);
+                response[4..8].copy_from_slice(&buf[12..16]);
+                tracker.send_to(&response, client).await.unwrap();
+            }
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut request = [0u8; 16];
+        request[12..16].copy_from_slice(&[1, 2, 3, 4]);
+        let response =
+            send_with_retransmit(&socket, tracker_addr, &request, 7, 64, &test_policy())
+                .await
+                .unwrap();
+        assert_eq!(response, [0, 0, 0, 7, 1, 2, 3, 4, 0, 0, 0, 0]);
+        let result =
+            send_with_retransmit(&socket, tracker_addr, &request, 7, 64, &test_policy()).await;
+        assert!(matches!(result, Err(TrackerError::Protocol(_))));
+    }
+
+    #[async_std::test]
+    async fn test_scrape_retransmits() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut buf = [0u8; 1500];
+            let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut response = vec![0u8; CONNECT_RESPONSE_SIZE];
+            response[4..8].copy_from_slice(&buf[12..16]);
+            tracker.send_to(&response, client).await.unwrap();
+            // Drop the first scrape request on the floor
+            tracker.recv_from(&mut buf).await.unwrap();
+            let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut response = vec![0u8; 8];
+            BigEndian::write_u32(&mut response[0..4], 2);
+            response[4..8].copy_from_slice(&buf[12..16]);
+            response.extend_from_slice(&[0, 0, 0, 5, 0, 0, 0, 6, 0, 0, 0, 7]);
+            tracker.send_to(&response, client).await.unwrap();
+        });
+        let url = Url::parse(&format!("udp://{}", tracker_addr)).unwrap();
+        let mut conn = TrackerConnection::with_retransmit(url, test_policy())
+            .await
+            .unwrap();
+        let stats = conn.scrape(&[[1u8; 20]]).await.unwrap();
+        let expected = ScrapeStats {
+            seeders: 5,
+            completed: 6,
+            leechers: 7,
+        };
+        assert_eq!(stats, vec![expected]);
+    }
+
+    #[async_std::test]
+    async fn test_resolves_tracker_once() {
+        let tracker_addr = spawn_mock_tracker().await;
+        let url = Url::parse(&format!("udp://localhost:{}", tracker_addr.port())).unwrap();
+        let mut conn = TrackerConnection::with_retransmit(url, test_policy())
+            .await
+            .unwrap();
+        assert_eq!(conn.socket_addr, tracker_addr);
+        // Announcing reuses the stored address rather than the hostname
+        conn.addr = Url::parse("udp://tracker.invalid:1337").unwrap();
+        assert!(conn.announce(AnnounceRequestDescriptor::default()).await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_expired_connection_id_is_refreshed() {
+        let mut conn = connect_mock_tracker().await;
+        assert_eq!(conn.connection_id, 1);
+
+        assert_eq!(conn.refresh_connection_id().await.unwrap(), 1);
+
+        conn.connection_expires = Instant::now();
+        assert_eq!(conn.refresh_connection_id().await.unwrap(), 2);
+        assert!(conn.connection_expires > Instant::now());
+    }
+
+    #[test]
+    fn test_scrape_request_to_bytes() {
+        let request = ScrapeRequest::new(7, &[[1u8; 20], [2u8; 20]]);
+        let bytes = request.to_bytes();
+        assert_eq!(bytes.len(), 56);
+        assert_eq!(BigEndian::read_i64(&bytes[0..8]), 7);
+        assert_eq!(BigEndian::read_u32(&bytes[8..12]), 2);
+        assert_eq!(&bytes[16..36], &[1u8; 20]);
+        assert_eq!(&bytes[36..56], &[2u8; 20]);
+    }
+
+    #[async_std::test]
+    async fn test_scrape() {
+        let mut conn = connect_mock_tracker().await;
+        let stats = conn.scrape(&[[1u8; 20], [2u8; 20]]).await.unwrap();
+        let expected = ScrapeStats {
+            seeders: 10,
+            completed: 20,
+            leechers: 30,
+        };
+        assert_eq!(stats, vec![expected, expected]);
+    }
+
+    #[async_std::test]
+    async fn test_scrape_too_many_hashes() {
+        let mut conn = connect_mock_tracker().await;
+        let hashes = vec![[0u8; 20]; MAX_SCRAPE_HASHES + 1];
+        assert!(conn.scrape(&hashes).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_announce_result() {
+        let mut conn = connect_mock_tracker().await;
+        let result = conn
+            .announce(AnnounceRequestDescriptor {
+                peer_id: [1u8; 20],
+                info_hash: [2u8; 20],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.interval, 60);
+        assert_eq!(result.leechers, 2);
+        assert_eq!(result.seeders, 3);
+        assert_eq!(result.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_announce_request_num_want_and_port() {
+        let request = AnnounceRequest::new(
+            7,
+            AnnounceRequestDescriptor {
+                num_want: 50,
+                port: 51413,
+                event: AnnounceEvent::Started,
+                ..Default::default()
+            },
+        );
+        let bytes = request.to_bytes();
+        assert_eq!(BigEndian::read_u32(&bytes[80..84]), 2);
+        assert_eq!(BigEndian::read_i32(&bytes[92..96]), 50);
+        assert_eq!(BigEndian::read_u16(&bytes[96..98]), 51413);
+    }
+
+    #[test]
+    fn test_announce_request_event() {
+        let events = [
+            (AnnounceEvent::None, 0),
+            (AnnounceEvent::Completed, 1),
+            (AnnounceEvent::Started, 2),
+            (AnnounceEvent::Stopped, 3),
+        ];
+        for (event, expected) in events {
+            let descriptor = AnnounceRequestDescriptor {
+                event,
+                ..Default::default()
+            };
+            let bytes = AnnounceRequest::new(7, descriptor).to_bytes();
+            assert_eq!(BigEndian::read_u32(&bytes[80..84]), expected);
+        }
+    }
+
+    #[test]
+    fn test_announce_request_defaults() {
+        let request = AnnounceRequest::new(7, AnnounceRequestDescriptor::default());
+        let bytes = request.to_bytes();
+        assert_eq!(BigEndian::read_i32(&bytes[92..96]), -1);
+        assert_eq!(BigEndian::read_u16(&bytes[96..98]), 6881);
+    }
+
+    fn announce_response_bytes(peer_list: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0, 0, 0, 1, 0, 0, 0, 9, 0, 0, 0, 60, 0, 0, 0, 2, 0, 0, 0, 3];
+        bytes.extend_from_slice(peer_list);
+        bytes
+    }
+
+    #[test]
+    fn test_announce_response_ipv4_peers() {
+        let bytes = announce_response_bytes(&[10, 0, 0, 1, 0x1A, 0xE1, 192, 168, 1, 2, 0, 80]);
+        let response = AnnounceResponse::from_bytes(&bytes, bytes.len(), false).unwrap();
+        assert_eq!(
+            response.peers,
+            vec![
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "192.168.1.2:80".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_announce_response_ipv6_peers() {
+        let mut peer = vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        peer.extend_from_slice(&[0x1A, 0xE1]);
+        let bytes = announce_response_bytes(&peer);
+        let response = AnnounceResponse::from_bytes(&bytes, bytes.len(), true).unwrap();
+        assert_eq!(
+            response.peers,
+            vec!["[2001:db8::1]:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_announce_response_misaligned_peers() {
+        let bytes = announce_response_bytes(&[10, 0, 0, 1, 0x1A]);
+        assert!(AnnounceResponse::from_bytes(&bytes, bytes.len(), false).is_err());
+        let bytes = announce_response_bytes(&[10, 0, 0, 1, 0x1A, 0xE1]);
+        assert!(AnnounceResponse::from_bytes(&bytes, bytes.len(), true).is_err());
+    }
+
+    #[test]
+    fn test_announce_response_truncated_and_oversized() {
+        let bytes = announce_response_bytes(&[10, 0, 0, 1, 0x1A, 0xE1]);
+        for length in 0..ANNOUNCE_RESPONSE_HEADER_BYTES {
+            assert!(AnnounceResponse::from_bytes(&bytes[..length], length, false).is_err());
+        }
+        for length in ANNOUNCE_RESPONSE_HEADER_BYTES + 1..bytes.len() {
+            assert!(AnnounceResponse::from_bytes(&bytes, length, false).is_err());
+        }
+        assert!(AnnounceResponse::from_bytes(&bytes, bytes.len() + 6, false).is_err());
+        assert!(AnnounceResponse::from_bytes(&bytes, bytes.len(), false).is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_unresolvable_tracker_is_dns_error() {
+        for url in ["udp://tracker.invalid:1337/announce", "udp:nohost"] {
+            let url = Url::parse(url).unwrap();
+            let result = TrackerConnection::with_retransmit(url, test_policy()).await;
+            assert!(matches!(result, Err(TrackerError::Dns(_))));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_mismatched_transaction_is_skipped() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut buf = [0u8; 1500];
+            let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+            let connect_tid = buf[12..16].to_vec();
+            let mut response = vec![0u8; CONNECT_RESPONSE_SIZE];
+            response[4..8].copy_from_slice(&connect_tid);
+            BigEndian::write_i64(&mut response[8..16], 42);
+            tracker.send_to(&response, client).await.unwrap();
+
+            let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+            // A late duplicate of the connect reply arrives first
+            tracker.send_to(&response, client).await.unwrap();
+            let mut response = vec![0u8; 8];
+            BigEndian::write_u32(&mut response[0..4], 1);
+            response[4..8].copy_from_slice(&buf[12..16]);
+            response.extend_from_slice(&[0, 0, 0, 60, 0, 0, 0, 2, 0, 0, 0, 3]);
+            response.extend_from_slice(&[127, 0, 0, 1, 0x1A, 0xE1]);
+            tracker.send_to(&response, client).await.unwrap();
+        });
+        let url = Url::parse(&format!("udp://{}", tracker_addr)).unwrap();
+        let mut conn = TrackerConnection::with_retransmit(url, test_policy())
+            .await
+            .unwrap();
+        let result = conn.announce(AnnounceRequestDescriptor::default()).await.unwrap();
+        assert_eq!(result.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[async_std::test]
+    async fn test_announce_with_many_peers() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        // Far more peers than the 4000 bytes announces used to be read into
+        let peers = (0..1000u16)
+            .map(|i| SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 6881)))
+            .collect::<Vec<_>>();
+        let sent = peers.clone();
+        async_std::task::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+                let action = BigEndian::read_u32(&buf[8..12]);
+                let mut response = vec![0u8; 8];
+                BigEndian::write_u32(&mut response[0..4], action);
+                response[4..8].copy_from_slice(&buf[12..16]);
+                if action == 0 {
+                    response.extend_from_slice(&1i64.to_be_bytes());
+                } else {
+                    response.extend_from_slice(&[0, 0, 0, 60, 0, 0, 0, 2, 0, 0, 0, 3]);
+                    for peer in &sent {
+                        let SocketAddr::V4(peer) = peer else { unreachable!() };
+                        response.extend_from_slice(&peer.ip().octets());
+                        response.extend_from_slice(&peer.port().to_be_bytes());
+                    }
+                }
+                tracker.send_to(&response, client).await.unwrap();
+            }
+        });
+        let url = Url::parse(&format!("udp://{}", tracker_addr)).unwrap();
+        let mut conn = TrackerConnection::with_retransmit(url, test_policy())
+            .await
+            .unwrap();
+        let result = conn.announce(AnnounceRequestDescriptor::default()).await.unwrap();
+        assert_eq!(result.peers, peers);
+        // A tracker ignoring num_want is cut off at what we asked for
+        let descriptor = AnnounceRequestDescriptor {
+            num_want: 100,
+            ..Default::default()
+        };
+        let result = conn.announce(descriptor).await.unwrap();
+        assert_eq!(result.peers, peers[..100]);
+    }
+
+    #[test]
+    fn test_announce_buffer_len() {
+        assert_eq!(announce_buffer_len(50, false), 20 + 50 * 6 + 1);
+        assert_eq!(announce_buffer_len(50, true), 20 + 50 * 18 + 1);
+        assert_eq!(announce_buffer_len(-1, false), MAX_UDP_PAYLOAD + 1);
+        assert_eq!(announce_buffer_len(i32::MAX, true), MAX_UDP_PAYLOAD + 1);
+    }
+
+    #[test]
+    fn test_malformed_responses_are_bad_response_errors() {
+        let bytes = announce_response_bytes(&[10, 0, 0, 1, 0x1A]);
+        assert!(matches!(
+            AnnounceResponse::from_bytes(&bytes, bytes.len(), false),
+            Err(TrackerError::BadResponse(_))
+        ));
+        assert!(matches!(
+            ScrapeResponse::from_bytes(&[0, 0, 0, 2]),
+            Err(TrackerError::BadResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_prefers_ipv4_address() {
+        let v4: SocketAddr = "203.0.113.7:6969".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::7]:6969".parse().unwrap();
+        let chosen = preferred_addr([v6, v4]).unwrap();
+        assert_eq!(chosen, v4);
+        assert!(bind_addr_for(chosen).is_ipv4());
+
+        let chosen = preferred_addr([v6]).unwrap();
+        assert_eq!(chosen, v6);
+        assert!(bind_addr_for(chosen).is_ipv6());
+
+        assert_eq!(preferred_addr([]), None);
+    }
+
+    #[async_std::test]
+    async fn test_ipv6_announce() {
+        let tracker = UdpSocket::bind("[::1]:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+                assert!(client.is_ipv6());
+                let action = BigEndian::read_u32(&buf[8..12]);
+                let mut response = vec![0u8; 8];
+                BigEndian::write_u32(&mut response[0..4], action);
+                response[4..8].copy_from_slice(&buf[12..16]);
+                if action == 0 {
+                    response.extend_from_slice(&7i64.to_be_bytes());
+                } else {
+                    // IPv6 trackers answer with 18-byte peer entries
+                    response.extend_from_slice(&[0, 0, 0, 60, 0, 0, 0, 0, 0, 0, 0, 1]);
+                    response.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+                    response.extend_from_slice(&[0x1A, 0xE1]);
+                }
+                tracker.send_to(&response, client).await.unwrap();
+            }
+        });
+        let url = Url::parse(&format!("udp://{}", tracker_addr)).unwrap();
+        let mut conn = TrackerConnection::with_retransmit(url, test_policy())
+            .await
+            .unwrap();
+        assert!(conn.socket_addr.is_ipv6());
+        assert_eq!(conn.local_addr(), "[::]:0".parse().unwrap());
+        let result = conn.announce(AnnounceRequestDescriptor::default()).await.unwrap();
+        assert_eq!(result.peers, vec!["[2001:db8::1]:6881".parse().unwrap()]);
+    }
+
+    #[async_std::test]
+    async fn test_bind_addr() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        async_std::task::spawn(async move {
+            let mut buf = [0u8; 1500];
+            let mut sources = Vec::new();
+            loop {
+                let (_, client) = tracker.recv_from(&mut buf).await.unwrap();
+                sources.push(client);
+                let action = BigEndian::read_u32(&buf[8..12]);
+                let mut response = vec![0u8; 8];
+                BigEndian::write_u32(&mut response[0..4], action);
+                response[4..8].copy_from_slice(&buf[12..16]);
+                if action == 0 {
+                    response.extend_from_slice(&7i64.to_be_bytes());
+                } else {
+                    response.extend_from_slice(&[0, 0, 0, 60, 0, 0, 0, 0, 0, 0, 0, 0]);
+                }
+                tracker.send_to(&response, client).await.unwrap();
+                if action == 1 {
+                    sender.send((sources, BigEndian::read_u16(&buf[96..98]))).unwrap();
+                    break;
+                }
+            }
+        });
+        let port = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let bind_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let url = Url::parse(&format!("udp://{}", tracker_addr)).unwrap();
+        let mut conn = TrackerConnection::with_bind_addr(url, test_policy(), bind_addr)
+            .await
+            .unwrap();
+        let descriptor = AnnounceRequestDescriptor {
+            port: 51413,
+            ..Default::default()
+        };
+        conn.announce(descriptor).await.unwrap();
+        let (sources, announced_port) = receiver.await.unwrap();
+        assert_eq!(sources, vec![bind_addr, bind_addr]);
+        // The listen port is announced, not the tracker socket's
+        assert_eq!(announced_port, 51413);
+    }
 }
@@ -0,0 +1,45 @@
+use sha1::{Digest, Sha1};
+
+/// Checks downloaded piece data against its hash from the info dictionary.
+pub fn verify_piece(data: &[u8], expected: &[u8; 20]) -> bool {
+    Sha1::digest(data).as_slice() == expected
+}
+
+/// Checks a bencoded info dictionary against the torrent's info hash.
+pub fn verify_info_hash(info_dict_bytes: &[u8], expected: &[u8; 20]) -> bool {
+    Sha1::digest(info_dict_bytes).as_slice() == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(hex_str: &str) -> [u8; 20] {
+        let mut bytes = [0u8; 20];
+        hex::decode_to_slice(hex_str, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_verify_piece_known_vectors() {
+        assert!(verify_piece(b"abc", &hash("a9993e364706816aba3e25717850c26c9cd0d89d")));
+        assert!(verify_piece(b"", &hash("da39a3ee5e6b4b0d3255bfef95601890afd80709")));
+    }
+
+    #[test]
+    fn test_verify_piece_corrupted_block() {
+        let mut data = vec![0xAB; 32768];
+        let expected = Sha1::digest(&data).into();
+        assert!(verify_piece(&data, &expected));
+        data[16384] ^= 0x01;
+        assert!(!verify_piece(&data, &expected));
+    }
+
+    #[test]
+    fn test_verify_info_hash() {
+        let info = b"d4:name4:teste";
+        let expected = Sha1::digest(info).into();
+        assert!(verify_info_hash(info, &expected));
+        assert!(!verify_info_hash(b"d4:name4:tesue", &expected));
+    }
+}